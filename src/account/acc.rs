@@ -37,6 +37,19 @@ pub struct Account {
     pub username: String,
     #[serde(skip)]
     password: String,
+    // OpenID Connect standard claims, handed out by scope through userinfo
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    #[serde(default)]
+    pub address: Option<String>,
+    #[serde(default)]
+    pub phone_number: Option<String>,
+    #[serde(default)]
+    pub phone_number_verified: bool,
 }
 
 impl Account {
@@ -46,6 +59,12 @@ impl Account {
             id: Uuid::new_v4(),
             username,
             password,
+            name: None,
+            email: None,
+            email_verified: false,
+            address: None,
+            phone_number: None,
+            phone_number_verified: false,
         }
     }
 
@@ -59,12 +78,17 @@ impl AccountStorage {
         Self(AccountMap::new(HashMap::new()))
     }
 
-    #[allow(dead_code)]
     pub async fn get(&self, id: &Uuid) -> Option<Account> {
         let accounts = self.0.lock().await;
         Some(accounts.get(id)?.clone())
     }
 
+    #[allow(dead_code)] // used in unit tests
+    pub async fn update(&self, account: Account) {
+        let mut accounts = self.0.lock().await;
+        accounts.entry(account.id).and_modify(|a| *a = account);
+    }
+
     pub async fn login(&self, username: &str, password: &str) -> Option<Account> {
         let accounts = self.0.lock().await;
         let account = accounts.values().find(|a| a.username == username)?;
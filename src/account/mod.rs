@@ -6,12 +6,22 @@ use rocket::response::Redirect;
 use rocket::serde::uuid::Uuid;
 use rocket_dyn_templates::{context, Template};
 
-mod acc;
+pub mod acc;
 mod forms;
 
 #[derive(Debug)]
 pub struct LoggedIn {
     pub user_id: Uuid,
+    // when the user last typed their password, if we know
+    pub auth_time: Option<i64>,
+}
+
+// Private, so encrypted and authenticated with the secret key. Otherwise anybody could claim
+// to be any user, or to have just logged in.
+fn add_session_cookies(jar: &CookieJar<'_>, user_id: Uuid) {
+    let auth_time = chrono::offset::Utc::now().timestamp();
+    jar.add_private(Cookie::new("user_id", user_id.to_string()));
+    jar.add_private(Cookie::new("auth_time", auth_time.to_string()));
 }
 
 #[rocket::async_trait]
@@ -20,19 +30,20 @@ impl<'r> FromRequest<'r> for LoggedIn {
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
         let jar = request.cookies();
-        let user_id_cookie = jar.get("user_id");
-        match user_id_cookie {
-            Some(user_id) => Outcome::Success(Self {
-                user_id: Uuid::parse_str(user_id.value()).unwrap(),
-            }),
-            None => Outcome::Failure((Status::Unauthorized, acc::Error::Account)),
+        let user_id_cookie = jar.get_private("user_id");
+        let auth_time = jar
+            .get_private("auth_time")
+            .and_then(|auth_time| auth_time.value().parse().ok());
+        match user_id_cookie.and_then(|user_id| Uuid::parse_str(user_id.value()).ok()) {
+            Some(user_id) => Outcome::Success(Self { user_id, auth_time }),
+            None => Outcome::Error((Status::Unauthorized, acc::Error::Account)),
         }
     }
 }
 
 #[get("/login")]
 async fn login_form(jar: &CookieJar<'_>) -> Result<NamedFile, Redirect> {
-    let user_cookie = jar.get("user_id");
+    let user_cookie = jar.get_private("user_id");
     if user_cookie.is_some() {
         Err(Redirect::to("/account/settings"))
     } else {
@@ -52,8 +63,7 @@ async fn login(
 
     match user {
         Some(user) => {
-            let cookie = Cookie::build("user_id", user.id.to_string());
-            jar.add(cookie.finish());
+            add_session_cookies(jar, user.id);
             Redirect::to("/account/settings")
         }
         None => Redirect::to("/account/login"),
@@ -62,7 +72,7 @@ async fn login(
 
 #[get("/register")]
 async fn register_form(jar: &CookieJar<'_>) -> Result<NamedFile, Redirect> {
-    let user_cookie = jar.get("user_id");
+    let user_cookie = jar.get_private("user_id");
     if user_cookie.is_some() {
         Err(Redirect::to("/account/settings"))
     } else {
@@ -80,7 +90,7 @@ async fn register(
         .register(login_form.username, login_form.password)
         .await
         .map_err(|e| -> Status { e.into() })?;
-    jar.add(Cookie::build("user_id", account.id.to_string()).finish());
+    add_session_cookies(jar, account.id);
     Ok(Redirect::to("/account/settings"))
 }
 
@@ -100,7 +110,8 @@ async fn settings(
             },
         )),
         None => {
-            jar.remove(Cookie::named("user_id"));
+            jar.remove_private("user_id");
+            jar.remove_private("auth_time");
             Err(Redirect::to("/account/login"))
         }
     }
@@ -108,7 +119,8 @@ async fn settings(
 
 #[post("/logout")]
 async fn logout(cookies: &CookieJar<'_>) -> Redirect {
-    cookies.remove(Cookie::named("user_id"));
+    cookies.remove_private("user_id");
+    cookies.remove_private("auth_time");
     Redirect::to("/account/login")
 }
 
@@ -1,6 +1,12 @@
-use crate::oauth::jwk;
+use crate::oauth::jws::Algorithm;
+use crate::oauth::key_set::SigningKeys;
+use crate::oauth::resources::Resource;
 use lazy_static::lazy_static;
+use rocket::serde::de::DeserializeOwned;
 use std::env::var;
+use std::fmt::Debug;
+use std::str::FromStr;
+use uuid::Uuid;
 
 fn get_password_cost() -> u32 {
     let cost = var("PASSWORD_COST").unwrap_or("10".to_string());
@@ -14,7 +20,70 @@ fn get_password_cost() -> u32 {
     }
 }
 
+fn get_parsed<T: FromStr + Debug>(name: &str, default: T) -> T
+where
+    T::Err: Debug,
+{
+    match var(name).map(|v| v.parse::<T>()) {
+        Ok(Ok(value)) => value,
+        Ok(Err(e)) => {
+            eprintln!("Invalid {} {:?}, using default {:?}", name, e, default);
+            default
+        }
+        Err(_) => default,
+    }
+}
+
+fn get_json<T: DeserializeOwned + Default>(name: &str) -> T {
+    match var(name).map(|v| serde_json::from_str::<T>(&v)) {
+        Ok(Ok(value)) => value,
+        Ok(Err(e)) => {
+            eprintln!("Invalid {} {:?}, using default", name, e);
+            T::default()
+        }
+        Err(_) => T::default(),
+    }
+}
+
 lazy_static! {
     pub static ref PASSWORD_COST: u32 = get_password_cost();
-    pub static ref KEY: jwk::Jwk = jwk::Jwk::new().unwrap();
+    // seconds an authorization code stays redeemable
+    pub static ref AUTHORIZATION_CODE_TTL: i64 = get_parsed("AUTHORIZATION_CODE_TTL", 60);
+    // Seconds each kind of token lives for clients that didn't register their own lifetime,
+    // and the longest one a client may register.
+    pub static ref ACCESS_TOKEN_TTL: i64 = get_parsed("ACCESS_TOKEN_TTL", 3600);
+    pub static ref MAX_ACCESS_TOKEN_TTL: i64 = get_parsed("MAX_ACCESS_TOKEN_TTL", 60 * 60 * 24);
+    pub static ref REFRESH_TOKEN_TTL: i64 = get_parsed("REFRESH_TOKEN_TTL", 60 * 60 * 24 * 30);
+    pub static ref MAX_REFRESH_TOKEN_TTL: i64 = get_parsed("MAX_REFRESH_TOKEN_TTL", 60 * 60 * 24 * 90);
+    pub static ref ID_TOKEN_TTL: i64 = get_parsed("ID_TOKEN_TTL", 3600);
+    pub static ref MAX_ID_TOKEN_TTL: i64 = get_parsed("MAX_ID_TOKEN_TTL", 60 * 60 * 24);
+    // seconds between sweeps of expired codes, refresh tokens and denylist entries
+    pub static ref SWEEP_INTERVAL: u64 = get_parsed("SWEEP_INTERVAL", 60);
+    // `iss` of everything we sign, and what verifiers insist on
+    pub static ref ISSUER: String = get_parsed("ISSUER", String::from("http://localhost:8000"));
+    // `aud` our own resources (/decks, /oauth/clients) expect in access tokens
+    pub static ref AUDIENCE: String = get_parsed("AUDIENCE", ISSUER.clone());
+    // other resource servers clients may ask tokens for, as a JSON list like
+    // [{"identifier": "https://api.example.com", "scopes": ["profile", "email"]}]
+    pub static ref RESOURCE_SERVERS: Vec<Resource> = get_json("RESOURCE_SERVERS");
+    // seconds of clock skew tolerated when checking `exp` and `nbf`
+    pub static ref JWT_LEEWAY: i64 = get_parsed("JWT_LEEWAY", 60);
+    // seconds a DPoP proof stays good for after its `iat`, on top of JWT_LEEWAY
+    pub static ref DPOP_PROOF_TTL: i64 = get_parsed("DPOP_PROOF_TTL", 60);
+    // seconds between new DPoP nonces, the one before the current stays good as well
+    pub static ref DPOP_NONCE_TTL: i64 = get_parsed("DPOP_NONCE_TTL", 300);
+    // RS256, ES256, ES384 or EdDSA for newly generated signing keys. Keys already stored keep
+    // theirs until they rotate out.
+    pub static ref SIGNING_ALG: Algorithm = get_parsed("SIGNING_ALG", Algorithm::Rs256);
+    // PKCS#8 PEM signing key, generated and written there if it doesn't exist yet
+    pub static ref SIGNING_KEY_FILE: Option<String> = var("SIGNING_KEY_FILE").ok();
+    // or MySQL holding the signing key shared by every instance
+    pub static ref SIGNING_KEY_DATABASE_URL: Option<String> = var("SIGNING_KEY_DATABASE_URL").ok();
+    // seconds between scheduled signing key rotations, 0 leaves it to the admin endpoint. At
+    // least the longest token lifetime plus JWT_LEEWAY, or the server refuses to start.
+    pub static ref KEY_ROTATION_INTERVAL: i64 = get_parsed("KEY_ROTATION_INTERVAL", 0);
+    // the one client allowed to rotate and revoke signing keys
+    pub static ref ADMIN_CLIENT_ID: Option<Uuid> = var("ADMIN_CLIENT_ID").ok().and_then(|id| id.parse().ok());
 }
+
+pub static KEYS: SigningKeys = SigningKeys::new();
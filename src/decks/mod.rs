@@ -1,11 +1,13 @@
 use rocket::serde::json::{json, Value};
 
-use crate::oauth::client_jwt;
+use crate::oauth::client_jwt::BearerError;
+use crate::oauth::dpop::DpopJwt;
+use crate::oauth::error::Error;
 
 #[get("/")]
-async fn decks(auth: client_jwt::ClientJwt) -> Value {
-    let cid = auth.get_claim("client_id").or(Some("none".to_string()));
-    json!({ "client_id": cid })
+async fn decks(auth: Result<DpopJwt, Error>) -> Result<Value, BearerError> {
+    let auth = auth.map_err(BearerError)?;
+    Ok(json!({ "client_id": auth.claims().client_id }))
 }
 
 pub async fn stage() -> rocket::fairing::AdHoc {
@@ -13,3 +15,223 @@ pub async fn stage() -> rocket::fairing::AdHoc {
         rocket.mount("/decks", routes![decks,])
     })
 }
+
+#[cfg(test)]
+mod test {
+    use crate::config::ISSUER;
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::asynchronous::Client;
+    use rocket::serde::json::{json, Value};
+    use rocket_dyn_templates::Template;
+
+    async fn test_client() -> Client {
+        let rocket = rocket::build()
+            .attach(Template::fairing())
+            .attach(crate::oauth::stage().await)
+            .attach(crate::account::stage().await)
+            .attach(super::stage().await);
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn test_decks_without_token() {
+        let client = test_client().await;
+        let response = client.get("/decks").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(
+            response.headers().get_one("WWW-Authenticate"),
+            Some("Bearer")
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_decks_with_invalid_token() {
+        let client = test_client().await;
+        let response = client
+            .get("/decks")
+            .header(Header::new("Authorization", "Bearer nope"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(
+            response.headers().get_one("WWW-Authenticate"),
+            Some(
+                "Bearer error=\"invalid_token\", error_description=\"The access token is invalid\""
+            )
+        );
+    }
+
+    async fn token_for(client: &Client, resource: &str) -> String {
+        let response = client
+            .post("/oauth/clients")
+            .header(ContentType::JSON)
+            .body(json!({ "name": "test", "description": "test" }).to_string())
+            .dispatch()
+            .await;
+        let body: Value = response.into_json().await.unwrap();
+        let response = client
+            .post("/oauth/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=client_credentials&client_id={}&client_secret={}&resource={}",
+                body["id"].as_str().unwrap(),
+                body["secret"].as_str().unwrap(),
+                resource
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let body: Value = response.into_json().await.unwrap();
+        body["access_token"].as_str().unwrap().to_string()
+    }
+
+    #[rocket::async_test]
+    async fn test_decks_audience() {
+        let client = test_client().await;
+        let base = ISSUER.trim_end_matches('/');
+
+        let token = token_for(&client, &format!("{}/decks", base)).await;
+        let response = client
+            .get("/decks")
+            .header(Header::new("Authorization", format!("Bearer {}", token)))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let token = token_for(&client, &format!("{}/oauth", base)).await;
+        let response = client
+            .get("/decks")
+            .header(Header::new("Authorization", format!("Bearer {}", token)))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(
+            response.headers().get_one("WWW-Authenticate"),
+            Some(
+                "Bearer error=\"invalid_token\", \
+                error_description=\"The access token is not meant for this resource\""
+            )
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_decks_dpop() {
+        use crate::oauth::dpop::encode_proof;
+        use crate::oauth::jwk::Jwk;
+        use crate::oauth::jws::Algorithm;
+        use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        use base64::Engine;
+        use sha2::{Digest, Sha256};
+
+        let client = test_client().await;
+        let base = ISSUER.trim_end_matches('/');
+        let jwk = Jwk::generate(Algorithm::Es256).unwrap();
+        let proof = |method: &str, path: &str, nonce: &str, token: Option<&str>| {
+            let mut claims = json!({
+                "jti": uuid::Uuid::new_v4().to_string(),
+                "htm": method,
+                "htu": format!("{}{}", base, path),
+                "iat": chrono::offset::Utc::now().timestamp(),
+                "nonce": nonce,
+            });
+            if let Some(token) = token {
+                claims["ath"] = json!(URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes())));
+            }
+            encode_proof(&jwk, &claims).unwrap()
+        };
+
+        let response = client
+            .post("/oauth/clients")
+            .header(ContentType::JSON)
+            .body(json!({ "name": "test", "description": "test" }).to_string())
+            .dispatch()
+            .await;
+        let body: Value = response.into_json().await.unwrap();
+        let token_request = |nonce: &str| {
+            client
+                .post("/oauth/token")
+                .header(ContentType::Form)
+                .header(Header::new(
+                    "DPoP",
+                    proof("POST", "/oauth/token", nonce, None),
+                ))
+                .body(format!(
+                    "grant_type=client_credentials&client_id={}&client_secret={}&resource={}/decks",
+                    body["id"].as_str().unwrap(),
+                    body["secret"].as_str().unwrap(),
+                    base
+                ))
+                .dispatch()
+        };
+        let response = token_request("").await;
+        let nonce = response
+            .headers()
+            .get_one("DPoP-Nonce")
+            .unwrap()
+            .to_string();
+        let response = token_request(&nonce).await;
+        assert_eq!(response.status(), Status::Ok);
+        let token: Value = response.into_json().await.unwrap();
+        let token = token["access_token"].as_str().unwrap().to_string();
+
+        let decks = |scheme: &str, proof: Option<String>| {
+            let mut request = client.get("/decks").header(Header::new(
+                "Authorization",
+                format!("{} {}", scheme, token),
+            ));
+            if let Some(proof) = proof {
+                request = request.header(Header::new("DPoP", proof));
+            }
+            request.dispatch()
+        };
+
+        let valid = proof("GET", "/decks", &nonce, Some(&token));
+        let response = decks("DPoP", Some(valid.clone())).await;
+        assert_eq!(response.status(), Status::Ok);
+
+        // a stolen token is no good as a bearer token, or without a fresh proof
+        let response = decks("Bearer", None).await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        for proof in [
+            None,
+            Some(valid),
+            Some(proof("GET", "/decks", &nonce, None)),
+            Some(proof("POST", "/decks", &nonce, Some(&token))),
+            Some(proof("GET", "/decks", &nonce, Some("another token"))),
+        ] {
+            let response = decks("DPoP", proof).await;
+            assert_eq!(response.status(), Status::Unauthorized);
+            assert_eq!(
+                response.headers().get_one("WWW-Authenticate"),
+                Some(
+                    "DPoP error=\"invalid_dpop_proof\", \
+                    error_description=\"The DPoP proof is invalid\""
+                )
+            );
+        }
+        // and one signed by another key
+        let other = Jwk::generate(Algorithm::Es256).unwrap();
+        let claims = json!({
+            "jti": uuid::Uuid::new_v4().to_string(),
+            "htm": "GET",
+            "htu": format!("{}/decks", base),
+            "iat": chrono::offset::Utc::now().timestamp(),
+            "nonce": nonce,
+            "ath": URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes())),
+        });
+        let response = decks("DPoP", Some(encode_proof(&other, &claims).unwrap())).await;
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let response = decks("DPoP", Some(proof("GET", "/decks", "stale", Some(&token)))).await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(
+            response.headers().get_one("DPoP-Nonce"),
+            Some(nonce.as_str())
+        );
+        assert!(response
+            .headers()
+            .get_one("WWW-Authenticate")
+            .unwrap()
+            .starts_with("DPoP error=\"use_dpop_nonce\""));
+    }
+}
@@ -0,0 +1,382 @@
+use crate::config::{ISSUER, JWT_LEEWAY};
+use crate::oauth::client::{Client, TokenEndpointAuthMethod};
+use crate::oauth::error::Error;
+use crate::oauth::jwk::{JwkSet, PublicJwk};
+use crate::oauth::jws::Compact;
+use crate::oauth::revocation::TokenId;
+use crate::oauth::scopes::Scope;
+use rocket::serde::{Deserialize, Serialize};
+use uuid::{Builder, Uuid};
+
+// RFC 7523 section 2.2, the only client_assertion_type there is
+pub const CLIENT_ASSERTION_TYPE: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+// RFC 7523 section 3: who may vouch for a client by signing an assertion, and what we take
+// from them. Anyone can register a client, so an assertion never gets to speak for one of our
+// accounts; its subject is mapped to one of the client's own, see `mapped_subject`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct TrustedIssuer {
+    pub issuer: String,
+    // the issuer's keys, either its whole JWKS document or a single key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwks: Option<JwkSet>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwk: Option<PublicJwk>,
+    // the `aud` assertions have to carry, our issuer or token endpoint if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audience: Option<String>,
+    // subjects accepted, exactly or by a prefix ending in `*`
+    pub subjects: Vec<String>,
+    // the most a token issued for this issuer's assertions can grant
+    #[serde(default)]
+    pub scopes: Vec<Scope>,
+}
+
+// RFC 7523 section 3, what we need out of an assertion
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct AssertionClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: Audience,
+    pub exp: i64,
+    #[serde(default)]
+    pub nbf: Option<i64>,
+    #[serde(default)]
+    pub jti: Option<String>,
+}
+
+// RFC 7519 section 4.1.3 allows either
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", untagged)]
+pub enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, audience: &str) -> bool {
+        match self {
+            Audience::One(aud) => aud == audience,
+            Audience::Many(auds) => auds.iter().any(|aud| aud == audience),
+        }
+    }
+}
+
+// our issuer, or the token endpoint as RFC 7523 section 3 suggests
+fn names_us(aud: &Audience) -> bool {
+    let token_endpoint = format!("{}/oauth/token", ISSUER.trim_end_matches('/'));
+    aud.contains(&ISSUER) || aud.contains(&token_endpoint)
+}
+
+fn is_current(claims: &AssertionClaims) -> bool {
+    let now = chrono::offset::Utc::now().timestamp();
+    now <= claims.exp + *JWT_LEEWAY && !matches!(claims.nbf, Some(nbf) if now + *JWT_LEEWAY < nbf)
+}
+
+// whichever of `keys` signed it, preferring the one the header names
+fn verify_with_keys<'a>(
+    assertion: &Compact,
+    keys: impl Iterator<Item = &'a PublicJwk>,
+) -> Option<AssertionClaims> {
+    let kid = assertion.header.kid.as_deref();
+    keys.filter(|key| kid.is_none() || key.kid.as_deref() == kid)
+        .find_map(|key| assertion.verify_public(key).ok())
+}
+
+// OpenID Connect Core section 9: private_key_jwt and client_secret_jwt. What comes back is
+// what the caller needs to make sure the assertion is only used once.
+pub fn verify_client_assertion(assertion: &str, client: &Client) -> Result<TokenId, Error> {
+    let assertion = Compact::parse(assertion).map_err(|_| Error::InvalidClient)?;
+    let claims = match client.token_endpoint_auth_method {
+        TokenEndpointAuthMethod::PrivateKeyJwt => {
+            let keys = client.jwks.iter().flat_map(|jwks| jwks.keys.iter());
+            verify_with_keys(&assertion, keys)
+        }
+        TokenEndpointAuthMethod::ClientSecretJwt => client
+            .jwt_secret
+            .as_ref()
+            .and_then(|secret| assertion.verify_hmac(secret.as_bytes()).ok()),
+        // the other methods don't come with assertions
+        _ => None,
+    }
+    .ok_or(Error::InvalidClient)?;
+    let client_id = client.id.to_string();
+    if claims.iss != client_id
+        || claims.sub != client_id
+        || !names_us(&claims.aud)
+        || !is_current(&claims)
+    {
+        return Err(Error::InvalidClient);
+    }
+    let jti = claims.jti.ok_or(Error::InvalidClient)?;
+    Ok(TokenId {
+        jti: format!("{} {}", client_id, jti),
+        expires_at: claims.exp + *JWT_LEEWAY,
+    })
+}
+
+// The `sub` of tokens issued for an assertion: a name-based UUID (RFC 4122 section 4.3) of the
+// issuer and subject under the client. The same subject always maps to the same one, and being
+// version 5 it can't be any of our accounts, which are all random.
+pub fn mapped_subject(client: &Client, claims: &AssertionClaims) -> Uuid {
+    let mut name = client.id.as_bytes().to_vec();
+    name.extend_from_slice(format!("{} {}", claims.iss, claims.sub).as_bytes());
+    let hash = openssl::sha::sha1(&name);
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&hash[..16]);
+    Builder::from_sha1_bytes(bytes).into_uuid()
+}
+
+impl TrustedIssuer {
+    fn keys(&self) -> impl Iterator<Item = &PublicJwk> {
+        self.jwks
+            .iter()
+            .flat_map(|jwks| jwks.keys.iter())
+            .chain(self.jwk.iter())
+    }
+
+    // registered with keys we can use and subjects to match
+    pub fn is_valid(&self) -> bool {
+        !self.issuer.is_empty()
+            && self.jwks.is_some() != self.jwk.is_some()
+            && self.keys().next().is_some()
+            && self.keys().all(|key| key.public_key().is_ok())
+            && !self.subjects.is_empty()
+            && self.subjects.iter().all(|sub| !sub.is_empty())
+    }
+
+    fn accepts_subject(&self, sub: &str) -> bool {
+        self.subjects
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => sub.starts_with(prefix),
+                None => sub == pattern,
+            })
+    }
+
+    fn accepts_audience(&self, aud: &Audience) -> bool {
+        match &self.audience {
+            Some(audience) => aud.contains(audience),
+            None => names_us(aud),
+        }
+    }
+
+    // RFC 7523 section 3, all but the one-time use of `jti`
+    pub fn verify(&self, assertion: &Compact) -> Result<AssertionClaims, Error> {
+        let claims = verify_with_keys(assertion, self.keys()).ok_or(Error::InvalidGrant)?;
+        if claims.iss != self.issuer
+            || !self.accepts_subject(&claims.sub)
+            || !self.accepts_audience(&claims.aud)
+            || !is_current(&claims)
+        {
+            return Err(Error::InvalidGrant);
+        }
+        Ok(claims)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::oauth::claims::TokenHeader;
+    use crate::oauth::jwk::Jwk;
+    use crate::oauth::jws::{encode, Algorithm};
+    use rocket::serde::json::{json, Value};
+    use uuid::Uuid;
+
+    fn trusted(jwk: &Jwk) -> TrustedIssuer {
+        let mut key = PublicJwk::from_key(&jwk.key).unwrap();
+        key.kid = Some(jwk.kid.clone());
+        TrustedIssuer {
+            issuer: "https://ci.example".to_string(),
+            jwks: Some(JwkSet { keys: vec![key] }),
+            jwk: None,
+            audience: None,
+            subjects: vec!["repo:grant/decks:*".to_string()],
+            scopes: vec![Scope::Profile],
+        }
+    }
+
+    fn assertion(jwk: &Jwk, claims: Value) -> String {
+        let header = TokenHeader {
+            alg: jwk.alg,
+            kid: Some(jwk.kid.clone()),
+            typ: Some("JWT".to_string()),
+        };
+        encode(&header, &claims, &jwk.key).unwrap()
+    }
+
+    fn claims() -> Value {
+        json!({
+            "iss": "https://ci.example",
+            "sub": "repo:grant/decks:ref:main",
+            "aud": [ISSUER.clone()],
+            "exp": chrono::offset::Utc::now().timestamp() + 300,
+            "jti": "1",
+        })
+    }
+
+    fn verify(issuer: &TrustedIssuer, token: &str) -> Result<AssertionClaims, Error> {
+        issuer.verify(&Compact::parse(token).unwrap())
+    }
+
+    #[test]
+    fn test_verify() {
+        for alg in Algorithm::ALL {
+            let jwk = Jwk::generate(alg).unwrap();
+            let claims = verify(&trusted(&jwk), &assertion(&jwk, claims())).unwrap();
+            assert_eq!(claims.sub, "repo:grant/decks:ref:main");
+            assert_eq!(claims.jti.as_deref(), Some("1"));
+        }
+    }
+
+    #[test]
+    fn test_verify_inline_jwk() {
+        let jwk = Jwk::generate(Algorithm::Es256).unwrap();
+        let mut issuer = trusted(&jwk);
+        issuer.jwk = issuer.jwks.take().map(|jwks| jwks.keys[0].clone());
+        assert!(issuer.is_valid());
+        assert!(verify(&issuer, &assertion(&jwk, claims())).is_ok());
+    }
+
+    #[test]
+    fn test_verify_rejects() {
+        let jwk = Jwk::generate(Algorithm::Es256).unwrap();
+        let issuer = trusted(&jwk);
+        let other = Jwk::generate(Algorithm::Es256).unwrap();
+        assert!(verify(&issuer, &assertion(&other, claims())).is_err());
+
+        for (claim, value) in [
+            ("iss", json!("https://elsewhere.example")),
+            ("sub", json!("repo:mallory/decks:ref:main")),
+            ("aud", json!("https://elsewhere.example")),
+            ("exp", json!(chrono::offset::Utc::now().timestamp() - 3600)),
+            ("nbf", json!(chrono::offset::Utc::now().timestamp() + 3600)),
+        ] {
+            let mut claims = claims();
+            claims[claim] = value;
+            assert!(matches!(
+                verify(&issuer, &assertion(&jwk, claims)),
+                Err(Error::InvalidGrant)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_audience() {
+        let jwk = Jwk::generate(Algorithm::Rs256).unwrap();
+        let mut issuer = trusted(&jwk);
+        let mut claims = claims();
+        claims["aud"] = json!(format!("{}/oauth/token", ISSUER.trim_end_matches('/')));
+        assert!(verify(&issuer, &assertion(&jwk, claims.clone())).is_ok());
+
+        issuer.audience = Some("https://as.example".to_string());
+        assert!(verify(&issuer, &assertion(&jwk, claims.clone())).is_err());
+        claims["aud"] = json!("https://as.example");
+        assert!(verify(&issuer, &assertion(&jwk, claims)).is_ok());
+    }
+
+    #[test]
+    fn test_mapped_subject() {
+        let jwk = Jwk::generate(Algorithm::Es256).unwrap();
+        let issuer = trusted(&jwk);
+        let client = Client::new_no_secret("name".to_string(), "test".to_string());
+        let verified = |claims| verify(&issuer, &assertion(&jwk, claims)).unwrap();
+
+        let subject = mapped_subject(&client, &verified(claims()));
+        assert_eq!(subject.get_version_num(), 5);
+        let mut again = claims();
+        again["jti"] = json!("2");
+        assert_eq!(mapped_subject(&client, &verified(again)), subject);
+
+        let mut other = claims();
+        other["sub"] = json!("repo:grant/decks:ref:dev");
+        assert_ne!(mapped_subject(&client, &verified(other)), subject);
+        let other_client = Client::new_no_secret("name".to_string(), "test".to_string());
+        assert_ne!(mapped_subject(&other_client, &verified(claims())), subject);
+    }
+
+    #[test]
+    fn test_is_valid() {
+        let jwk = Jwk::generate(Algorithm::Es256).unwrap();
+        let issuer = trusted(&jwk);
+        assert!(issuer.is_valid());
+        // one source of keys, not both
+        let both = TrustedIssuer {
+            jwk: Some(issuer.jwks.as_ref().unwrap().keys[0].clone()),
+            ..issuer.clone()
+        };
+        assert!(!both.is_valid());
+        let no_subjects = TrustedIssuer {
+            subjects: vec![],
+            ..issuer.clone()
+        };
+        assert!(!no_subjects.is_valid());
+        let mut bad_key = issuer.clone();
+        bad_key.jwks.as_mut().unwrap().keys[0].x = None;
+        assert!(!bad_key.is_valid());
+    }
+
+    fn client_claims(client: &Client) -> Value {
+        json!({
+            "iss": client.id,
+            "sub": client.id,
+            "aud": format!("{}/oauth/token", ISSUER.trim_end_matches('/')),
+            "exp": chrono::offset::Utc::now().timestamp() + 300,
+            "jti": "1",
+        })
+    }
+
+    #[test]
+    fn test_verify_client_assertion() {
+        use crate::oauth::jws::encode_hmac;
+
+        let jwk = Jwk::generate(Algorithm::Es256).unwrap();
+        let mut client = Client::new_no_secret("name".to_string(), "test".to_string());
+        client.token_endpoint_auth_method = TokenEndpointAuthMethod::PrivateKeyJwt;
+        client.jwks = trusted(&jwk).jwks;
+        let token_id =
+            verify_client_assertion(&assertion(&jwk, client_claims(&client)), &client).unwrap();
+        assert_eq!(token_id.jti, format!("{} 1", client.id));
+
+        let header = TokenHeader {
+            alg: Algorithm::Hs256,
+            kid: None,
+            typ: None,
+        };
+        let hmac = encode_hmac(&header, &client_claims(&client), b"secret").unwrap();
+        // an HMAC is no good for a private_key_jwt client
+        assert!(verify_client_assertion(&hmac, &client).is_err());
+        client.token_endpoint_auth_method = TokenEndpointAuthMethod::ClientSecretJwt;
+        client.jwt_secret = Some("secret".to_string());
+        assert!(verify_client_assertion(&hmac, &client).is_ok());
+        assert!(
+            verify_client_assertion(&assertion(&jwk, client_claims(&client)), &client).is_err()
+        );
+    }
+
+    #[test]
+    fn test_verify_client_assertion_claims() {
+        let jwk = Jwk::generate(Algorithm::Rs256).unwrap();
+        let mut client = Client::new_no_secret("name".to_string(), "test".to_string());
+        client.token_endpoint_auth_method = TokenEndpointAuthMethod::PrivateKeyJwt;
+        client.jwks = trusted(&jwk).jwks;
+
+        for (claim, value) in [
+            ("iss", json!(Uuid::new_v4())),
+            ("sub", json!(Uuid::new_v4())),
+            ("aud", json!("https://elsewhere.example")),
+            ("exp", json!(chrono::offset::Utc::now().timestamp() - 3600)),
+            ("jti", Value::Null),
+        ] {
+            let mut claims = client_claims(&client);
+            claims[claim] = value;
+            assert!(matches!(
+                verify_client_assertion(&assertion(&jwk, claims), &client),
+                Err(Error::InvalidClient)
+            ));
+        }
+    }
+}
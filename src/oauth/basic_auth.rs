@@ -0,0 +1,105 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rocket::http::{RawStr, Status};
+use rocket::request::{self, FromRequest, Outcome, Request};
+use uuid::Uuid;
+
+use crate::oauth::error::Error;
+
+// RFC 6749 section 2.3.1, client_secret_basic: the client id and secret are each form-urlencoded,
+// joined with a colon and base64-ed into the Authorization header
+#[derive(Debug, PartialEq)]
+pub struct BasicAuth(pub Option<(Uuid, String)>);
+
+impl BasicAuth {
+    // any other scheme isn't ours to judge, the request just didn't use Basic
+    pub fn parse(header: Option<&str>) -> Result<Self, Error> {
+        let credentials = match header.and_then(|h| h.split_once(' ')) {
+            Some((scheme, credentials)) if scheme.eq_ignore_ascii_case("Basic") => credentials,
+            _ => return Ok(Self(None)),
+        };
+        let credentials = STANDARD
+            .decode(credentials.trim())
+            .map_err(|_| Error::InvalidClient)?;
+        let credentials = String::from_utf8(credentials).map_err(|_| Error::InvalidClient)?;
+        let (client_id, client_secret) = credentials.split_once(':').ok_or(Error::InvalidClient)?;
+
+        let client_id = form_decode(client_id)?
+            .parse()
+            .map_err(|_| Error::InvalidClient)?;
+        Ok(Self(Some((client_id, form_decode(client_secret)?))))
+    }
+}
+
+fn form_decode(s: &str) -> Result<String, Error> {
+    RawStr::new(s)
+        .url_decode()
+        .map(|s| s.into_owned())
+        .map_err(|_| Error::InvalidClient)
+}
+
+// never fails for a missing header, plenty of clients authenticate in the body instead
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BasicAuth {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match BasicAuth::parse(request.headers().get_one("Authorization")) {
+            Ok(basic) => Outcome::Success(basic),
+            Err(e) => Outcome::Error((Status::Unauthorized, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header(credentials: &str) -> String {
+        format!("Basic {}", STANDARD.encode(credentials))
+    }
+
+    #[test]
+    fn test_parse() {
+        let id = Uuid::new_v4();
+        assert_eq!(
+            BasicAuth::parse(Some(&header(&format!("{}:secret", id)))).unwrap(),
+            BasicAuth(Some((id, "secret".to_string())))
+        );
+        // the scheme is case-insensitive
+        let lowercase = header(&format!("{}:secret", id)).replacen("Basic", "basic", 1);
+        assert!(BasicAuth::parse(Some(&lowercase)).unwrap().0.is_some());
+    }
+
+    #[test]
+    fn test_parse_form_encoded() {
+        let id = Uuid::new_v4();
+        let basic = BasicAuth::parse(Some(&header(&format!("{}:a%3Ab%2Bc+d%25", id)))).unwrap();
+        assert_eq!(basic, BasicAuth(Some((id, "a:b+c d%".to_string()))));
+    }
+
+    #[test]
+    fn test_parse_other_schemes() {
+        assert_eq!(BasicAuth::parse(None).unwrap(), BasicAuth(None));
+        assert_eq!(
+            BasicAuth::parse(Some("Bearer token")).unwrap(),
+            BasicAuth(None)
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        let id = Uuid::new_v4();
+        for header in [
+            "Basic not-base64!".to_string(),
+            header("no-colon"),
+            header("not-a-uuid:secret"),
+            header(&format!("{}:bad%FF", id)),
+        ] {
+            assert!(matches!(
+                BasicAuth::parse(Some(&header)),
+                Err(Error::InvalidClient)
+            ));
+        }
+    }
+}
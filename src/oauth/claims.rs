@@ -0,0 +1,135 @@
+use crate::oauth::jws::Algorithm;
+use rocket::serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const ACCESS_TOKEN_TYPE: &str = "at+jwt";
+// everything else we sign: ID tokens, userinfo responses
+pub const JWT_TYPE: &str = "JWT";
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct TokenHeader {
+    pub alg: Algorithm,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub typ: Option<String>,
+}
+
+// RFC 9068 section 2.2
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct AccessTokenClaims {
+    pub iss: String,
+    // the account for user grants, the client itself for client credentials
+    pub sub: String,
+    pub aud: String,
+    pub exp: i64,
+    pub iat: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<i64>,
+    pub jti: String,
+    pub client_id: Uuid,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub scope: String,
+    // set on tokens from a token exchange
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>,
+    // set on tokens bound to the client's TLS certificate or DPoP key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cnf: Option<Confirmation>,
+}
+
+// RFC 8693 section 4.1: who acts for the subject, with whoever they in turn acted for nested
+// inside
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct Actor {
+    pub sub: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<Box<Actor>>,
+}
+
+// RFC 7800 section 3.1, what ties an access token to something only its holder has
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct Confirmation {
+    // RFC 8705 section 3.1, the thumbprint of the client's TLS certificate
+    #[serde(rename = "x5t#S256", default, skip_serializing_if = "Option::is_none")]
+    pub x5t_s256: Option<String>,
+    // RFC 9449 section 6.1, the thumbprint of the key the client signs DPoP proofs with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jkt: Option<String>,
+}
+
+impl Confirmation {
+    // nothing to confirm leaves the token a plain bearer token
+    pub fn new(x5t_s256: Option<String>, jkt: Option<String>) -> Option<Self> {
+        match (&x5t_s256, &jkt) {
+            (None, None) => None,
+            _ => Some(Self { x5t_s256, jkt }),
+        }
+    }
+}
+
+// OpenID Connect Core section 2
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    // the client the user logged in to
+    pub aud: String,
+    pub exp: i64,
+    pub iat: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_time: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_access_token_claims_serialization() {
+        let client_id = Uuid::new_v4();
+        let claims = AccessTokenClaims {
+            iss: "http://localhost:8000".to_string(),
+            sub: client_id.to_string(),
+            aud: "http://localhost:8000".to_string(),
+            exp: 1_700_003_600,
+            iat: 1_700_000_000,
+            nbf: None,
+            jti: "jti".to_string(),
+            client_id,
+            scope: String::new(),
+            act: None,
+            cnf: None,
+        };
+        let value = serde_json::to_value(&claims).unwrap();
+        assert_eq!(value["exp"], 1_700_003_600);
+        assert_eq!(value["client_id"], client_id.to_string());
+        assert!(value.get("nbf").is_none());
+        assert!(value.get("scope").is_none());
+        assert!(value.get("act").is_none());
+        assert_eq!(
+            serde_json::from_value::<AccessTokenClaims>(value).unwrap(),
+            claims
+        );
+    }
+
+    #[test]
+    fn test_token_header() {
+        let header = TokenHeader {
+            alg: Algorithm::Rs256,
+            kid: Some("kid".to_string()),
+            typ: Some(ACCESS_TOKEN_TYPE.to_string()),
+        };
+        assert_eq!(
+            serde_json::to_value(&header).unwrap(),
+            serde_json::json!({ "alg": "RS256", "kid": "kid", "typ": "at+jwt" })
+        );
+    }
+}
@@ -1,17 +1,144 @@
-use crate::config::PASSWORD_COST;
+use crate::config::{
+    ACCESS_TOKEN_TTL, ID_TOKEN_TTL, KEYS, MAX_ACCESS_TOKEN_TTL, MAX_ID_TOKEN_TTL,
+    MAX_REFRESH_TOKEN_TTL, PASSWORD_COST, REFRESH_TOKEN_TTL,
+};
+use crate::oauth::assertion::TrustedIssuer;
 use crate::oauth::error::Error;
+use crate::oauth::jwe::{ContentEncryption, Encryption, KeyManagement};
+use crate::oauth::jwk::JwkSet;
+use crate::oauth::jws::Algorithm;
+use crate::oauth::scopes::Scope;
 use hex::ToHex;
 use rand::Rng;
+use rocket::http::uri::Absolute;
 use rocket::serde::uuid::Uuid;
 use rocket::serde::{Deserialize, Serialize};
 use rocket::tokio::sync::Mutex;
 use rocket::State;
 use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+// failed authentications in a row before a client has to wait out the lockout, in seconds
+const MAX_FAILED_LOGINS: u32 = 5;
+const FAILED_LOGIN_LOCKOUT: i64 = 60;
 
 type ClientsMap = Mutex<HashMap<Uuid, Client>>;
 pub type Clients<'r> = &'r State<ClientStorage>;
 pub struct ClientStorage(ClientsMap);
 
+// how a client authenticates at the token, introspection and revocation endpoints, OpenID
+// Connect Core section 9
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum TokenEndpointAuthMethod {
+    #[default]
+    ClientSecretPost,
+    // the same secret, in an Authorization: Basic header
+    ClientSecretBasic,
+    // a client_assertion JWT HMAC-ed with the client secret
+    ClientSecretJwt,
+    // a client_assertion JWT signed with a key from the client's `jwks`
+    PrivateKeyJwt,
+    // RFC 8705 section 2.1, a CA-issued TLS client certificate with the registered subject
+    TlsClientAuth,
+    // RFC 8705 section 2.2, a TLS client certificate for a key from the client's `jwks`
+    SelfSignedTlsClientAuth,
+}
+
+impl TokenEndpointAuthMethod {
+    pub const ALL: [TokenEndpointAuthMethod; 6] = [
+        TokenEndpointAuthMethod::ClientSecretPost,
+        TokenEndpointAuthMethod::ClientSecretBasic,
+        TokenEndpointAuthMethod::ClientSecretJwt,
+        TokenEndpointAuthMethod::PrivateKeyJwt,
+        TokenEndpointAuthMethod::TlsClientAuth,
+        TokenEndpointAuthMethod::SelfSignedTlsClientAuth,
+    ];
+}
+
+impl fmt::Display for TokenEndpointAuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenEndpointAuthMethod::ClientSecretPost => write!(f, "client_secret_post"),
+            TokenEndpointAuthMethod::ClientSecretBasic => write!(f, "client_secret_basic"),
+            TokenEndpointAuthMethod::ClientSecretJwt => write!(f, "client_secret_jwt"),
+            TokenEndpointAuthMethod::PrivateKeyJwt => write!(f, "private_key_jwt"),
+            TokenEndpointAuthMethod::TlsClientAuth => write!(f, "tls_client_auth"),
+            TokenEndpointAuthMethod::SelfSignedTlsClientAuth => {
+                write!(f, "self_signed_tls_client_auth")
+            }
+        }
+    }
+}
+
+impl FromStr for TokenEndpointAuthMethod {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TokenEndpointAuthMethod::ALL
+            .into_iter()
+            .find(|method| method.to_string() == s)
+            .ok_or(Error::InvalidClientMetadata)
+    }
+}
+
+// what the token endpoint hands this client as access token
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum AccessTokenFormat {
+    #[default]
+    Jwt,
+    // opaque, only resolvable through us
+    Reference,
+}
+
+impl FromStr for AccessTokenFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jwt" => Ok(AccessTokenFormat::Jwt),
+            "reference" => Ok(AccessTokenFormat::Reference),
+            _ => Err(Error::InvalidClientMetadata),
+        }
+    }
+}
+
+// RFC 8693: another client that may swap this one's access tokens for its own. Only the client
+// whose tokens get exchanged, or the admin, gets to say so.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct TokenExchanger {
+    pub client_id: Uuid,
+    // the most an exchanged token can carry, everything the subject token had if empty
+    #[serde(default)]
+    pub scopes: Vec<Scope>,
+    // what the subject token has to be meant for, any audience if empty
+    #[serde(default)]
+    pub audiences: Vec<String>,
+}
+
+// what a client asks for at registration, RFC 7591 section 2
+#[derive(Debug, Default)]
+pub struct ClientMetadata {
+    pub name: String,
+    pub description: String,
+    pub redirect_uris: Vec<String>,
+    pub userinfo_signed_response_alg: Option<String>,
+    pub jwks: Option<JwkSet>,
+    pub id_token_encrypted_response_alg: Option<String>,
+    pub id_token_encrypted_response_enc: Option<String>,
+    pub access_token_format: Option<String>,
+    pub access_token_ttl: Option<i64>,
+    pub refresh_token_ttl: Option<i64>,
+    pub id_token_ttl: Option<i64>,
+    pub token_exchangers: Vec<TokenExchanger>,
+    pub trusted_issuers: Vec<TrustedIssuer>,
+    pub token_endpoint_auth_method: Option<String>,
+    pub tls_client_auth_subject_dn: Option<String>,
+}
+
 impl ClientStorage {
     pub fn new() -> Self {
         Self(ClientsMap::new(HashMap::new()))
@@ -33,21 +160,124 @@ impl ClientStorage {
         clients.insert(client.id, client);
     }
 
-    pub async fn register(
-        &self,
-        name: String,
-        description: String,
-    ) -> Result<(Client, String), Error> {
-        if name == *"Grant Azure" {
+    pub async fn register(&self, metadata: ClientMetadata) -> Result<(Client, String), Error> {
+        if metadata.name == *"Grant Azure" {
             return Err(Error::InvalidClientName);
         }
+        if !metadata
+            .redirect_uris
+            .iter()
+            .all(|uri| valid_redirect_uri(uri))
+        {
+            return Err(Error::InvalidRedirectUri);
+        }
+        // we can only sign with whatever the active key is for
+        let signing_alg = KEYS.active().alg.to_string();
+        if matches!(&metadata.userinfo_signed_response_alg, Some(alg) if *alg != signing_alg) {
+            return Err(Error::InvalidClientMetadata);
+        }
+        let id_token_encryption = match (
+            &metadata.id_token_encrypted_response_alg,
+            &metadata.id_token_encrypted_response_enc,
+        ) {
+            (None, None) => None,
+            // OpenID Connect Registration section 2, enc means nothing without alg
+            (None, Some(_)) => return Err(Error::InvalidClientMetadata),
+            (Some(alg), enc) => {
+                let alg: KeyManagement = alg.parse().map_err(|_| Error::InvalidClientMetadata)?;
+                let enc = match enc {
+                    Some(enc) => enc.parse().map_err(|_| Error::InvalidClientMetadata)?,
+                    None => ContentEncryption::A128CbcHs256,
+                };
+                // there has to be a key we can actually encrypt to
+                let keys = metadata.jwks.as_ref().map_or(&[][..], |jwks| &jwks.keys);
+                Encryption::select(keys, alg, enc).ok_or(Error::InvalidClientMetadata)?;
+                Some((alg, enc))
+            }
+        };
+        for (ttl, max) in [
+            (metadata.access_token_ttl, *MAX_ACCESS_TOKEN_TTL),
+            (metadata.refresh_token_ttl, *MAX_REFRESH_TOKEN_TTL),
+            (metadata.id_token_ttl, *MAX_ID_TOKEN_TTL),
+        ] {
+            if matches!(ttl, Some(ttl) if ttl <= 0 || ttl > max) {
+                return Err(Error::InvalidClientMetadata);
+            }
+        }
+        if !metadata.trusted_issuers.iter().all(TrustedIssuer::is_valid) {
+            return Err(Error::InvalidClientMetadata);
+        }
+        let token_endpoint_auth_method = match &metadata.token_endpoint_auth_method {
+            Some(method) => method.parse()?,
+            None => TokenEndpointAuthMethod::default(),
+        };
+        // there has to be a key to check its assertions with
+        if token_endpoint_auth_method == TokenEndpointAuthMethod::PrivateKeyJwt
+            && !metadata.jwks.as_ref().is_some_and(|jwks| {
+                jwks.keys.iter().any(|key| {
+                    key.public_key().is_ok() && Algorithm::ALL.iter().any(|alg| key.verifies(*alg))
+                })
+            })
+        {
+            return Err(Error::InvalidClientMetadata);
+        }
+        // and something to hold the certificate up against
+        let certificate_checkable = match token_endpoint_auth_method {
+            TokenEndpointAuthMethod::TlsClientAuth => metadata.tls_client_auth_subject_dn.is_some(),
+            TokenEndpointAuthMethod::SelfSignedTlsClientAuth => metadata
+                .jwks
+                .as_ref()
+                .is_some_and(|jwks| jwks.keys.iter().any(|key| key.public_key().is_ok())),
+            _ => true,
+        };
+        if !certificate_checkable {
+            return Err(Error::InvalidClientMetadata);
+        }
+        let access_token_format = match &metadata.access_token_format {
+            Some(format) => format.parse()?,
+            None => AccessTokenFormat::default(),
+        };
 
         let mut clients = self.0.lock().await;
-        let (client, secret) = Client::new(name, description);
+        if !known_exchangers(&clients, &metadata.token_exchangers) {
+            return Err(Error::InvalidClientMetadata);
+        }
+        let (mut client, secret) = Client::new(metadata.name, metadata.description);
+        client.redirect_uris = metadata.redirect_uris;
+        client.userinfo_signed_response_alg = metadata.userinfo_signed_response_alg;
+        client.jwks = metadata.jwks;
+        client.id_token_encrypted_response_alg = id_token_encryption.map(|(alg, _)| alg);
+        client.id_token_encrypted_response_enc = id_token_encryption.map(|(_, enc)| enc);
+        client.access_token_format = access_token_format;
+        client.access_token_ttl = metadata.access_token_ttl;
+        client.refresh_token_ttl = metadata.refresh_token_ttl;
+        client.id_token_ttl = metadata.id_token_ttl;
+        client.token_exchangers = metadata.token_exchangers;
+        client.trusted_issuers = metadata.trusted_issuers;
+        client.token_endpoint_auth_method = token_endpoint_auth_method;
+        client.tls_client_auth_subject_dn = metadata.tls_client_auth_subject_dn;
+        if token_endpoint_auth_method == TokenEndpointAuthMethod::ClientSecretJwt {
+            client.jwt_secret = Some(secret.clone());
+        }
         clients.insert(client.id, client.clone());
         Ok((client, secret))
     }
 
+    // InvalidClient when there's no such client to change
+    pub async fn set_token_exchangers(
+        &self,
+        id: &Uuid,
+        exchangers: Vec<TokenExchanger>,
+    ) -> Result<Client, Error> {
+        let mut clients = self.0.lock().await;
+        if !known_exchangers(&clients, &exchangers) {
+            return Err(Error::InvalidClientMetadata);
+        }
+        let client = clients.get_mut(id).ok_or(Error::InvalidClient)?;
+        client.token_exchangers = exchangers;
+        Ok(client.clone())
+    }
+
     pub async fn delete(&self, id: Uuid) {
         self.0.lock().await.remove(&id);
     }
@@ -59,10 +289,48 @@ pub struct Client {
     pub id: Uuid,
     pub name: String,
     pub description: String,
+    #[serde(default)]
+    pub redirect_uris: Vec<String>,
+    // answer userinfo requests with a signed JWT instead of plain JSON
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub userinfo_signed_response_alg: Option<String>,
+    // public keys, for now only the ones tokens get encrypted to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwks: Option<JwkSet>,
+    // ID tokens come back as a signed JWT nested in a JWE encrypted to `jwks`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id_token_encrypted_response_alg: Option<KeyManagement>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id_token_encrypted_response_enc: Option<ContentEncryption>,
+    #[serde(default)]
+    pub access_token_format: AccessTokenFormat,
+    // seconds tokens issued to this client live, None for the server default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_token_ttl: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token_ttl: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id_token_ttl: Option<i64>,
+    // clients that may swap this one's access tokens for their own in a token exchange
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub token_exchangers: Vec<TokenExchanger>,
+    // issuers whose signed assertions stand in for this client's secret
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trusted_issuers: Vec<TrustedIssuer>,
+    #[serde(default)]
+    pub token_endpoint_auth_method: TokenEndpointAuthMethod,
+    // RFC 4514, what tls_client_auth compares the certificate's subject to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_client_auth_subject_dn: Option<String>,
     #[serde(skip)]
-    recent_login_count: u32,
+    failed_login_count: u32,
+    #[serde(skip)]
+    last_failed_login: i64,
     #[serde(skip)]
     pub secret: String,
+    // client_secret_jwt needs the secret itself as HMAC key, so only those clients keep it
+    #[serde(skip)]
+    pub jwt_secret: Option<String>,
 }
 
 impl Client {
@@ -75,7 +343,22 @@ impl Client {
             secret: bcrypt::hash(secret.as_bytes(), *PASSWORD_COST).unwrap(),
             name,
             description,
-            recent_login_count: 0,
+            redirect_uris: Vec::new(),
+            userinfo_signed_response_alg: None,
+            jwks: None,
+            id_token_encrypted_response_alg: None,
+            id_token_encrypted_response_enc: None,
+            access_token_format: AccessTokenFormat::Jwt,
+            access_token_ttl: None,
+            refresh_token_ttl: None,
+            id_token_ttl: None,
+            token_exchangers: Vec::new(),
+            trusted_issuers: Vec::new(),
+            token_endpoint_auth_method: TokenEndpointAuthMethod::ClientSecretPost,
+            tls_client_auth_subject_dn: None,
+            failed_login_count: 0,
+            last_failed_login: 0,
+            jwt_secret: None,
         };
         (client, secret)
     }
@@ -87,13 +370,77 @@ impl Client {
             secret: Self::generate_secret(),
             name,
             description,
-            recent_login_count: 0,
+            redirect_uris: Vec::new(),
+            userinfo_signed_response_alg: None,
+            jwks: None,
+            id_token_encrypted_response_alg: None,
+            id_token_encrypted_response_enc: None,
+            access_token_format: AccessTokenFormat::Jwt,
+            access_token_ttl: None,
+            refresh_token_ttl: None,
+            id_token_ttl: None,
+            token_exchangers: Vec::new(),
+            trusted_issuers: Vec::new(),
+            token_endpoint_auth_method: TokenEndpointAuthMethod::ClientSecretPost,
+            tls_client_auth_subject_dn: None,
+            failed_login_count: 0,
+            last_failed_login: 0,
+            jwt_secret: None,
         }
     }
 
+    // The lifetimes the client registered, or the server defaults. The maximums apply again
+    // here in case they were lowered after the client registered.
+    pub fn access_token_lifetime(&self) -> i64 {
+        lifetime(
+            self.access_token_ttl,
+            *ACCESS_TOKEN_TTL,
+            *MAX_ACCESS_TOKEN_TTL,
+        )
+    }
+
+    pub fn refresh_token_lifetime(&self) -> i64 {
+        lifetime(
+            self.refresh_token_ttl,
+            *REFRESH_TOKEN_TTL,
+            *MAX_REFRESH_TOKEN_TTL,
+        )
+    }
+
+    pub fn id_token_lifetime(&self) -> i64 {
+        lifetime(self.id_token_ttl, *ID_TOKEN_TTL, *MAX_ID_TOKEN_TTL)
+    }
+
+    // what `client_id` may do with this client's access tokens in a token exchange, if anything
+    pub fn exchanger(&self, client_id: &Uuid) -> Option<&TokenExchanger> {
+        self.token_exchangers
+            .iter()
+            .find(|exchanger| exchanger.client_id == *client_id)
+    }
+
+    // None unless the client registered for encrypted ID tokens
+    pub fn id_token_encryption(&self) -> Option<Encryption> {
+        let keys = &self.jwks.as_ref()?.keys;
+        Encryption::select(
+            keys,
+            self.id_token_encrypted_response_alg?,
+            self.id_token_encrypted_response_enc
+                .unwrap_or(ContentEncryption::A128CbcHs256),
+        )
+    }
+
     // return true if not rate-limited (I hate naming)
     fn assert_rate_limit(&self) -> bool {
-        self.recent_login_count < 5
+        self.failed_login_count < MAX_FAILED_LOGINS
+            || self.last_failed_login + FAILED_LOGIN_LOCKOUT
+                <= chrono::offset::Utc::now().timestamp()
+    }
+
+    pub fn check_rate_limit(&self) -> Result<(), Error> {
+        match self.assert_rate_limit() {
+            true => Ok(()),
+            false => Err(Error::RateLimited),
+        }
     }
 
     pub fn validate_secret(&self, secret: &str) -> Result<(), Error> {
@@ -112,8 +459,16 @@ impl Client {
         rand::thread_rng().gen::<[u8; 32]>().encode_hex::<String>()
     }
 
-    pub fn increment_login_count(&mut self) {
-        self.recent_login_count += 1;
+    // Only failures count, and a success starts over, so clients that authenticate for every
+    // refresh or introspection never run into the limit
+    pub fn record_login(&mut self, succeeded: bool) {
+        match succeeded {
+            true => self.failed_login_count = 0,
+            false => {
+                self.failed_login_count += 1;
+                self.last_failed_login = chrono::offset::Utc::now().timestamp();
+            }
+        }
     }
 
     pub fn reroll_secret(&mut self, secret: Option<String>) -> String {
@@ -123,8 +478,56 @@ impl Client {
         };
 
         self.secret = bcrypt::hash(new_secret.as_bytes(), *PASSWORD_COST).unwrap();
+        if self.token_endpoint_auth_method == TokenEndpointAuthMethod::ClientSecretJwt {
+            self.jwt_secret = Some(new_secret.clone());
+        }
         new_secret
     }
+
+    // Exact match against the registered URIs, except that native apps redirecting to a
+    // loopback IP may pick any port at runtime (RFC 8252 section 7.3).
+    pub fn has_redirect_uri(&self, redirect_uri: &str) -> bool {
+        self.redirect_uris.iter().any(|registered| {
+            registered == redirect_uri || loopback_matches(registered, redirect_uri)
+        })
+    }
+}
+
+fn known_exchangers(clients: &HashMap<Uuid, Client>, exchangers: &[TokenExchanger]) -> bool {
+    exchangers
+        .iter()
+        .all(|exchanger| clients.contains_key(&exchanger.client_id))
+}
+
+fn lifetime(registered: Option<i64>, default: i64, max: i64) -> i64 {
+    registered.unwrap_or(default).min(max)
+}
+
+// absolute and without a fragment, RFC 6749 section 3.1.2
+pub fn valid_redirect_uri(redirect_uri: &str) -> bool {
+    Absolute::parse(redirect_uri).is_ok()
+}
+
+fn loopback_matches(registered: &str, redirect_uri: &str) -> bool {
+    let (registered, redirect_uri) =
+        match (Absolute::parse(registered), Absolute::parse(redirect_uri)) {
+            (Ok(registered), Ok(redirect_uri)) => (registered, redirect_uri),
+            _ => return false,
+        };
+    let (registered_authority, authority) = match (registered.authority(), redirect_uri.authority())
+    {
+        (Some(registered), Some(authority)) => (registered, authority),
+        _ => return false,
+    };
+    let is_loopback = matches!(registered_authority.host(), "127.0.0.1" | "[::1]");
+    is_loopback
+        && registered.scheme() == "http"
+        && redirect_uri.scheme() == "http"
+        && registered_authority.user_info().is_none()
+        && authority.user_info().is_none()
+        && registered_authority.host() == authority.host()
+        && registered.path().as_str() == redirect_uri.path().as_str()
+        && registered.query().map(|q| q.as_str()) == redirect_uri.query().map(|q| q.as_str())
 }
 
 impl PartialEq for Client {
@@ -137,6 +540,7 @@ pub async fn init_state() -> ClientStorage {
     let client_storage = ClientStorage::new();
     let mut client = Client::new_no_secret(String::from("Grant"), String::from("Grant Azure"));
     client.id = Uuid::parse_str("f452faa7-cbe0-437b-97ff-c53049b0f710").unwrap();
+    client.redirect_uris = vec![String::from("http://127.0.0.1/callback")];
     client.reroll_secret(Some(String::from(
         "5a02dd7d0e66aa5c9224bd0dc09d25ef2fa880a8d66f13a0312113938a2f4701",
     )));
@@ -160,8 +564,13 @@ mod test {
         let mut client =
             Client::new_no_secret(String::from("Grant"), String::from("Grant's client"));
         assert!(client.assert_rate_limit());
-        client.recent_login_count = 5;
+        for _ in 0..MAX_FAILED_LOGINS {
+            client.record_login(false);
+        }
         assert!(!client.assert_rate_limit());
+        // the lockout wears off
+        client.last_failed_login -= FAILED_LOGIN_LOCKOUT;
+        assert!(client.assert_rate_limit());
     }
 
     #[test]
@@ -169,19 +578,23 @@ mod test {
         let (mut client, secret) =
             Client::new(String::from("Grant"), String::from("Grant's client"));
         assert!(client.validate_secret(&secret).is_ok());
-        client.recent_login_count = 5;
+        client.failed_login_count = MAX_FAILED_LOGINS;
+        client.last_failed_login = chrono::offset::Utc::now().timestamp();
         assert!(client.validate_secret(&secret).is_err());
-        client.recent_login_count = 0;
+        client.failed_login_count = 0;
         assert!(client.validate_secret("").is_err());
     }
 
     #[test]
-    fn test_client_increment_login_count() {
+    fn test_client_record_login() {
         let mut client =
             Client::new_no_secret(String::from("Grant"), String::from("Grant's client"));
-        assert_eq!(client.recent_login_count, 0);
-        client.increment_login_count();
-        assert_eq!(client.recent_login_count, 1);
+        assert_eq!(client.failed_login_count, 0);
+        client.record_login(false);
+        client.record_login(false);
+        assert_eq!(client.failed_login_count, 2);
+        client.record_login(true);
+        assert_eq!(client.failed_login_count, 0);
     }
 
     #[test]
@@ -196,11 +609,341 @@ mod test {
         assert_eq!(client.name, "Grant");
         assert_eq!(client.description, "Grant's client");
         assert_ne!(client.secret, secret);
-        assert_eq!(client.recent_login_count, 0);
+        assert_eq!(client.failed_login_count, 0);
         assert_eq!(secret.len(), 64);
         assert!(client.match_secret(&secret));
     }
 
+    #[test]
+    fn test_client_has_redirect_uri() {
+        let mut client =
+            Client::new_no_secret(String::from("Grant"), String::from("Grant's client"));
+        client.redirect_uris = vec![
+            String::from("https://example.com/callback"),
+            String::from("http://127.0.0.1/callback"),
+            String::from("http://[::1]:8080/callback?app=1"),
+        ];
+
+        assert!(client.has_redirect_uri("https://example.com/callback"));
+        assert!(!client.has_redirect_uri("https://example.com/callback/"));
+        assert!(!client.has_redirect_uri("https://example.com:8443/callback"));
+        assert!(!client.has_redirect_uri("https://evil.com/callback"));
+
+        assert!(client.has_redirect_uri("http://127.0.0.1:51004/callback"));
+        assert!(client.has_redirect_uri("http://[::1]:3000/callback?app=1"));
+        assert!(!client.has_redirect_uri("http://127.0.0.1:51004/other"));
+        assert!(!client.has_redirect_uri("http://[::1]:3000/callback"));
+        assert!(!client.has_redirect_uri("http://localhost:51004/callback"));
+    }
+
+    #[test]
+    fn test_valid_redirect_uri() {
+        assert!(valid_redirect_uri("https://example.com/callback"));
+        assert!(valid_redirect_uri("com.example.app:/callback"));
+        assert!(!valid_redirect_uri("/callback"));
+        assert!(!valid_redirect_uri("https://example.com/callback#fragment"));
+    }
+
+    #[rocket::async_test]
+    async fn test_client_storage_register() {
+        let client_storage = ClientStorage::new();
+        let metadata = || ClientMetadata {
+            name: String::from("Grant"),
+            description: String::from("Grant's client"),
+            redirect_uris: vec![String::from("https://example.com/callback")],
+            ..Default::default()
+        };
+        let (client, _) = client_storage.register(metadata()).await.unwrap();
+        assert_eq!(client.redirect_uris, vec!["https://example.com/callback"]);
+
+        let result = client_storage
+            .register(ClientMetadata {
+                redirect_uris: vec![String::from("/callback")],
+                ..metadata()
+            })
+            .await;
+        assert!(matches!(result, Err(Error::InvalidRedirectUri)));
+
+        let (client, _) = client_storage
+            .register(ClientMetadata {
+                userinfo_signed_response_alg: Some(String::from("RS256")),
+                ..metadata()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            client.userinfo_signed_response_alg.as_deref(),
+            Some("RS256")
+        );
+
+        let result = client_storage
+            .register(ClientMetadata {
+                userinfo_signed_response_alg: Some(String::from("none")),
+                ..metadata()
+            })
+            .await;
+        assert!(matches!(result, Err(Error::InvalidClientMetadata)));
+
+        let (client, _) = client_storage
+            .register(ClientMetadata {
+                access_token_format: Some(String::from("reference")),
+                ..metadata()
+            })
+            .await
+            .unwrap();
+        assert_eq!(client.access_token_format, AccessTokenFormat::Reference);
+
+        let (client, _) = client_storage
+            .register(ClientMetadata {
+                access_token_ttl: Some(300),
+                refresh_token_ttl: Some(3600),
+                ..metadata()
+            })
+            .await
+            .unwrap();
+        assert_eq!(client.access_token_lifetime(), 300);
+        assert_eq!(client.refresh_token_lifetime(), 3600);
+        assert_eq!(client.id_token_lifetime(), *ID_TOKEN_TTL);
+
+        for metadata in [
+            ClientMetadata {
+                access_token_ttl: Some(*MAX_ACCESS_TOKEN_TTL + 1),
+                ..metadata()
+            },
+            ClientMetadata {
+                id_token_ttl: Some(0),
+                ..metadata()
+            },
+        ] {
+            let result = client_storage.register(metadata).await;
+            assert!(matches!(result, Err(Error::InvalidClientMetadata)));
+        }
+
+        let result = client_storage
+            .register(ClientMetadata {
+                access_token_format: Some(String::from("opaque")),
+                ..metadata()
+            })
+            .await;
+        assert!(matches!(result, Err(Error::InvalidClientMetadata)));
+    }
+
+    #[rocket::async_test]
+    async fn test_client_storage_register_encryption() {
+        use crate::oauth::jwk::{Jwk, PublicJwk};
+        use crate::oauth::jws::Algorithm;
+
+        let client_storage = ClientStorage::new();
+        let key = PublicJwk::from_key(&Jwk::generate(Algorithm::Es256).unwrap().key).unwrap();
+        let metadata = || ClientMetadata {
+            name: String::from("Grant"),
+            description: String::from("Grant's client"),
+            jwks: Some(JwkSet {
+                keys: vec![key.clone()],
+            }),
+            id_token_encrypted_response_alg: Some(String::from("ECDH-ES")),
+            ..Default::default()
+        };
+        let (client, _) = client_storage.register(metadata()).await.unwrap();
+        let encryption = client.id_token_encryption().unwrap();
+        assert_eq!(encryption.alg, KeyManagement::EcdhEs);
+        assert_eq!(encryption.enc, ContentEncryption::A128CbcHs256);
+        assert_eq!(encryption.key, key);
+
+        for metadata in [
+            // no key of the right type
+            ClientMetadata {
+                id_token_encrypted_response_alg: Some(String::from("RSA-OAEP-256")),
+                ..metadata()
+            },
+            ClientMetadata {
+                jwks: None,
+                ..metadata()
+            },
+            ClientMetadata {
+                id_token_encrypted_response_enc: Some(String::from("A128GCM")),
+                ..metadata()
+            },
+            ClientMetadata {
+                id_token_encrypted_response_alg: None,
+                id_token_encrypted_response_enc: Some(String::from("A256GCM")),
+                ..metadata()
+            },
+        ] {
+            let result = client_storage.register(metadata).await;
+            assert!(matches!(result, Err(Error::InvalidClientMetadata)));
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_client_storage_register_token_exchange() {
+        let client_storage = ClientStorage::new();
+        let metadata = || ClientMetadata {
+            name: String::from("Grant"),
+            description: String::from("Grant's client"),
+            ..Default::default()
+        };
+        let exchanger = |client_id| TokenExchanger {
+            client_id,
+            scopes: vec![Scope::Profile],
+            audiences: Vec::new(),
+        };
+        let (backend, _) = client_storage.register(metadata()).await.unwrap();
+        let (frontend, _) = client_storage
+            .register(ClientMetadata {
+                token_exchangers: vec![exchanger(backend.id)],
+                ..metadata()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            frontend.exchanger(&backend.id),
+            Some(&exchanger(backend.id))
+        );
+        assert!(frontend.exchanger(&frontend.id).is_none());
+        assert!(backend.exchanger(&frontend.id).is_none());
+
+        let result = client_storage
+            .register(ClientMetadata {
+                token_exchangers: vec![exchanger(Uuid::new_v4())],
+                ..metadata()
+            })
+            .await;
+        assert!(matches!(result, Err(Error::InvalidClientMetadata)));
+
+        // changed later by the client whose tokens they are
+        let backend = client_storage
+            .set_token_exchangers(&backend.id, vec![exchanger(frontend.id)])
+            .await
+            .unwrap();
+        assert!(backend.exchanger(&frontend.id).is_some());
+        let frontend = client_storage
+            .set_token_exchangers(&frontend.id, Vec::new())
+            .await
+            .unwrap();
+        assert!(frontend.exchanger(&backend.id).is_none());
+        assert!(matches!(
+            client_storage
+                .set_token_exchangers(&backend.id, vec![exchanger(Uuid::new_v4())])
+                .await,
+            Err(Error::InvalidClientMetadata)
+        ));
+        assert!(matches!(
+            client_storage
+                .set_token_exchangers(&Uuid::new_v4(), Vec::new())
+                .await,
+            Err(Error::InvalidClient)
+        ));
+    }
+
+    #[rocket::async_test]
+    async fn test_client_storage_register_auth_method() {
+        use crate::oauth::jwk::{Jwk, PublicJwk};
+
+        let client_storage = ClientStorage::new();
+        let metadata = || ClientMetadata {
+            name: String::from("Grant"),
+            description: String::from("Grant's client"),
+            ..Default::default()
+        };
+        let (client, _) = client_storage.register(metadata()).await.unwrap();
+        assert_eq!(
+            client.token_endpoint_auth_method,
+            TokenEndpointAuthMethod::ClientSecretPost
+        );
+        assert!(client.jwt_secret.is_none());
+
+        let (mut client, secret) = client_storage
+            .register(ClientMetadata {
+                token_endpoint_auth_method: Some(String::from("client_secret_jwt")),
+                ..metadata()
+            })
+            .await
+            .unwrap();
+        assert_eq!(client.jwt_secret.as_deref(), Some(secret.as_str()));
+        let secret = client.reroll_secret(None);
+        assert_eq!(client.jwt_secret, Some(secret));
+
+        let key = PublicJwk::from_key(&Jwk::generate(Algorithm::EdDsa).unwrap().key).unwrap();
+        let (client, _) = client_storage
+            .register(ClientMetadata {
+                token_endpoint_auth_method: Some(String::from("private_key_jwt")),
+                jwks: Some(JwkSet { keys: vec![key] }),
+                ..metadata()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            client.token_endpoint_auth_method,
+            TokenEndpointAuthMethod::PrivateKeyJwt
+        );
+
+        for metadata in [
+            // nothing to check its assertions with
+            ClientMetadata {
+                token_endpoint_auth_method: Some(String::from("private_key_jwt")),
+                ..metadata()
+            },
+            ClientMetadata {
+                token_endpoint_auth_method: Some(String::from("none")),
+                ..metadata()
+            },
+        ] {
+            let result = client_storage.register(metadata).await;
+            assert!(matches!(result, Err(Error::InvalidClientMetadata)));
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_client_storage_register_tls_client_auth() {
+        use crate::oauth::jwk::{Jwk, PublicJwk};
+
+        let client_storage = ClientStorage::new();
+        let metadata = |method: &str| ClientMetadata {
+            name: String::from("Grant"),
+            description: String::from("Grant's client"),
+            token_endpoint_auth_method: Some(method.to_string()),
+            ..Default::default()
+        };
+        let (client, _) = client_storage
+            .register(ClientMetadata {
+                tls_client_auth_subject_dn: Some(String::from("CN=grant,O=Example")),
+                ..metadata("tls_client_auth")
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            client.token_endpoint_auth_method,
+            TokenEndpointAuthMethod::TlsClientAuth
+        );
+        assert_eq!(
+            client.tls_client_auth_subject_dn.as_deref(),
+            Some("CN=grant,O=Example")
+        );
+
+        let key = PublicJwk::from_key(&Jwk::generate(Algorithm::Es256).unwrap().key).unwrap();
+        let (client, _) = client_storage
+            .register(ClientMetadata {
+                jwks: Some(JwkSet { keys: vec![key] }),
+                ..metadata("self_signed_tls_client_auth")
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            client.token_endpoint_auth_method,
+            TokenEndpointAuthMethod::SelfSignedTlsClientAuth
+        );
+
+        // nothing to compare the certificate to
+        for metadata in [
+            metadata("tls_client_auth"),
+            metadata("self_signed_tls_client_auth"),
+        ] {
+            let result = client_storage.register(metadata).await;
+            assert!(matches!(result, Err(Error::InvalidClientMetadata)));
+        }
+    }
+
     #[rocket::async_test]
     async fn test_client_storage_new() {
         let client_storage = ClientStorage::new();
@@ -1,50 +1,128 @@
-use jwt::{token as jwt_token, Header, PKeyWithDigest, Token, VerifyWithKey};
-use openssl::bn::BigNum;
-use openssl::hash::MessageDigest;
-use openssl::pkey::PKey;
-use openssl::rsa::Rsa;
 use rocket::http::Status;
 use rocket::request::{self, FromRequest, Outcome, Request};
-use std::collections::BTreeMap;
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::json;
 use std::str;
 use uuid::Uuid;
 
-use crate::config::KEY;
+use crate::config::{ADMIN_CLIENT_ID, ISSUER, JWT_LEEWAY, KEYS};
+use crate::oauth::claims::{AccessTokenClaims, ACCESS_TOKEN_TYPE};
+use crate::oauth::dpop::{self, DPOP_TOKEN_TYPE};
 use crate::oauth::error::Error;
+use crate::oauth::jws::Compact;
+use crate::oauth::mtls::ClientCertificate;
+use crate::oauth::reference::ReferenceTokenStorage;
+use crate::oauth::resources::ResourceRegistry;
+use crate::oauth::revocation::{RevocationStorage, TokenId};
 
-pub struct ClientJwt(Token<Header, BTreeMap<String, String>, jwt_token::Verified>);
+pub struct ClientJwt(AccessTokenClaims);
 
 impl ClientJwt {
-    fn parse(token: &str) -> Result<Self, Error> {
-        let n = BigNum::from_dec_str(&KEY.n)?;
-        let e = BigNum::from_dec_str(&KEY.e)?;
-        let rsa = Rsa::from_public_components(n, e)?;
-        let cert = PKey::from_rsa(rsa)?;
-
-        let key = PKeyWithDigest {
-            key: cert,
-            digest: MessageDigest::sha256(),
-        };
-        let token: Token<Header, BTreeMap<String, String>, _> = token.verify_with_key(&key)?;
-        Ok(Self(token))
+    pub fn parse(token: &str) -> Result<Self, Error> {
+        let token = Compact::parse(token)?;
+        let jwk = KEYS.verifying_key(token.header.kid.as_deref())?;
+        let claims = token.verify(&jwk)?;
+        // RFC 9068 section 4: keeps ID tokens and the like from passing as access tokens
+        if token.header.typ.as_deref() != Some(ACCESS_TOKEN_TYPE) {
+            return Err(Error::InvalidToken);
+        }
+        Ok(Self(claims))
+    }
+
+    // one of our reference tokens, or else a JWT we signed
+    pub async fn resolve(
+        token: &str,
+        reference_tokens: &ReferenceTokenStorage,
+    ) -> Result<Self, Error> {
+        match reference_tokens.get(token).await {
+            Some(claims) => Ok(Self(claims)),
+            None => Self::parse(token),
+        }
+    }
+
+    pub fn claims(&self) -> &AccessTokenClaims {
+        &self.0
     }
 
-    fn claims(&self) -> &BTreeMap<String, String> {
-        self.0.claims()
+    // everything besides the signature that a resource has to check before trusting the token
+    pub fn validate(&self, audiences: &[&str]) -> Result<(), Error> {
+        let now = chrono::offset::Utc::now().timestamp();
+        if now > self.0.exp + *JWT_LEEWAY {
+            return Err(Error::TokenExpired);
+        }
+        if let Some(nbf) = self.0.nbf {
+            if now + *JWT_LEEWAY < nbf {
+                return Err(Error::TokenNotYetValid);
+            }
+        }
+        if self.0.iss != *ISSUER {
+            return Err(Error::InvalidIssuer);
+        }
+        match audiences.contains(&self.0.aud.as_str()) {
+            true => Ok(()),
+            false => Err(Error::InvalidAudience),
+        }
     }
 
     pub fn authorize_for(&self, id: &Uuid) -> Result<(), Error> {
-        match self.claims().get("client_id") {
-            Some(client_id) => match *client_id == id.to_string() {
-                true => Ok(()),
-                false => Err(Error::InvalidResourceAccess),
-            },
+        match self.0.client_id == *id {
+            true => Ok(()),
+            false => Err(Error::InvalidResourceAccess),
+        }
+    }
+
+    pub fn authorize_admin(&self) -> Result<(), Error> {
+        match *ADMIN_CLIENT_ID {
+            Some(admin) => self.authorize_for(&admin),
             None => Err(Error::InvalidResourceAccess),
         }
     }
 
-    pub fn get_claim(&self, key: &str) -> Option<String> {
-        self.0.claims().get(key).map(|s| s.to_string())
+    // denied for as long as validate() would still take it
+    pub fn token_id(&self) -> TokenId {
+        TokenId {
+            jti: self.0.jti.clone(),
+            expires_at: self.0.exp + *JWT_LEEWAY,
+        }
+    }
+
+    pub async fn is_revoked(&self, revocations: &RevocationStorage) -> bool {
+        revocations.is_revoked(&self.0.jti, &self.0.client_id).await
+    }
+
+    // everything a guard checks about a token from the Authorization header, whichever
+    // scheme it came with
+    pub async fn authenticate(request: &Request<'_>, token: &str) -> Result<Self, (Status, Error)> {
+        let reference_tokens = request
+            .rocket()
+            .state::<ReferenceTokenStorage>()
+            .ok_or((Status::InternalServerError, Error::InvalidToken))?;
+        let jwt = ClientJwt::resolve(token, reference_tokens)
+            .await
+            .map_err(|_| (Status::Unauthorized, Error::InvalidToken))?;
+        // only tokens minted for a resource covering this path get through
+        let resources = request
+            .rocket()
+            .state::<ResourceRegistry>()
+            .ok_or((Status::InternalServerError, Error::InvalidToken))?;
+        jwt.validate(&resources.audiences(request.uri().path().as_str()))
+            .map_err(|e| (Status::Unauthorized, e))?;
+
+        let revocations = request
+            .rocket()
+            .state::<RevocationStorage>()
+            .ok_or((Status::InternalServerError, Error::InvalidToken))?;
+        if jwt.is_revoked(revocations).await {
+            return Err((Status::Unauthorized, Error::InvalidToken));
+        }
+        // a certificate-bound token only works over a connection with that certificate
+        if let Some(cnf) = jwt.0.cnf.as_ref().filter(|cnf| cnf.x5t_s256.is_some()) {
+            let certificate = request.guard::<ClientCertificate>().await.succeeded();
+            if !certificate.is_some_and(|certificate| certificate.confirms(cnf)) {
+                return Err((Status::Unauthorized, Error::InvalidToken));
+            }
+        }
+        Ok(jwt)
     }
 }
 
@@ -59,22 +137,205 @@ impl<'r> FromRequest<'r> for ClientJwt {
         let auth_header = request.headers().get_one("Authorization");
         let auth_header = match auth_header {
             Some(header) => header,
-            None => return Outcome::Failure((Status::Unauthorized, Error::InvalidAuthHeader)),
+            None => return Outcome::Error((Status::Unauthorized, Error::InvalidAuthHeader)),
         };
 
         let auth_header = auth_header.split(' ').collect::<Vec<&str>>();
         if auth_header.len() != 2 {
-            return Outcome::Failure((Status::Unauthorized, Error::InvalidAuthHeader));
+            return Outcome::Error((Status::Unauthorized, Error::InvalidAuthHeader));
         }
 
         let (auth_type, token) = (auth_header[0], auth_header[1]);
         if auth_type != "Bearer" {
-            return Outcome::Failure((Status::Unauthorized, Error::InvalidAuthType));
+            return Outcome::Error((Status::Unauthorized, Error::InvalidAuthType));
+        }
+
+        let jwt = match ClientJwt::authenticate(request, token).await {
+            Ok(jwt) => jwt,
+            Err(e) => return Outcome::Error(e),
+        };
+        // RFC 9449 section 7.2, a DPoP-bound token is worthless without a proof to go with it
+        if jwt.0.cnf.as_ref().is_some_and(|cnf| cnf.jkt.is_some()) {
+            return Outcome::Error((Status::Unauthorized, Error::InvalidToken));
+        }
+        Outcome::Success(jwt)
+    }
+}
+
+// Failed ClientJwt guards as an RFC 6750 section 3 challenge, for routes that take
+// `Result<ClientJwt, Error>` and want to tell the caller exactly what was wrong. Tokens sent
+// with DPoP get the RFC 9449 section 7.1 one instead.
+pub struct BearerError(pub Error);
+
+impl BearerError {
+    fn challenge(&self, scheme: &str) -> String {
+        let description = match self.0 {
+            Error::InvalidAuthHeader | Error::InvalidAuthType => return "Bearer".to_string(),
+            Error::InvalidDpopProof => "The DPoP proof is invalid",
+            Error::UseDpopNonce => "The DPoP proof needs the nonce in DPoP-Nonce",
+            Error::TokenExpired => "The access token expired",
+            Error::TokenNotYetValid => "The access token is not valid yet",
+            Error::InvalidIssuer => "The access token was issued by someone else",
+            Error::InvalidAudience => "The access token is not meant for this resource",
+            Error::InvalidResourceAccess => "The access token does not grant this",
+            _ => "The access token is invalid",
+        };
+        let error = match self.0 {
+            Error::InvalidResourceAccess => "insufficient_scope",
+            Error::InvalidDpopProof | Error::UseDpopNonce => self.0.code(),
+            _ => "invalid_token",
+        };
+        format!(
+            "{} error=\"{}\", error_description=\"{}\"",
+            scheme, error, description
+        )
+    }
+}
+
+impl<'r> Responder<'r, 'static> for BearerError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let dpop = matches!(self.0, Error::InvalidDpopProof | Error::UseDpopNonce)
+            || request
+                .headers()
+                .get_one("Authorization")
+                .and_then(|header| header.split_once(' '))
+                .is_some_and(|(scheme, _)| scheme == DPOP_TOKEN_TYPE);
+        let challenge = match dpop {
+            true => self.challenge(DPOP_TOKEN_TYPE),
+            false => self.challenge("Bearer"),
+        };
+        let status = match self.0 {
+            Error::InvalidResourceAccess => Status::Forbidden,
+            _ => Status::Unauthorized,
+        };
+        let body = json!({
+            "status": status.code,
+            "reason": status.reason_lossy().to_lowercase(),
+        });
+        let mut response = Response::build_from(body.respond_to(request)?);
+        response
+            .status(status)
+            .raw_header("WWW-Authenticate", challenge);
+        if let Some(nonce) = dpop::nonce_header(request) {
+            response.raw_header("DPoP-Nonce", nonce);
         }
+        response.ok()
+    }
+}
 
-        match ClientJwt::parse(token) {
-            Ok(jwt) => Outcome::Success(jwt),
-            Err(_) => Outcome::Failure((Status::Unauthorized, Error::InvalidClient)),
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::AUDIENCE;
+    use crate::oauth::server::generate::sign;
+
+    fn claims(offset: i64) -> AccessTokenClaims {
+        let now = chrono::offset::Utc::now().timestamp() + offset;
+        let client_id = Uuid::new_v4();
+        AccessTokenClaims {
+            iss: ISSUER.clone(),
+            sub: client_id.to_string(),
+            aud: AUDIENCE.clone(),
+            exp: now + 3600,
+            iat: now,
+            nbf: Some(now),
+            jti: Uuid::new_v4().to_string(),
+            client_id,
+            scope: String::new(),
+            act: None,
+            cnf: None,
         }
     }
+
+    fn parse(claims: AccessTokenClaims) -> ClientJwt {
+        ClientJwt::parse(&sign(claims, ACCESS_TOKEN_TYPE).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_picks_key_by_kid() {
+        use crate::oauth::claims::TokenHeader;
+        use crate::oauth::jws::encode;
+
+        let jwk = KEYS.active();
+        let header = |kid: Option<&str>| TokenHeader {
+            alg: jwk.alg,
+            kid: kid.map(str::to_string),
+            typ: Some(ACCESS_TOKEN_TYPE.to_string()),
+        };
+        let token = |kid| encode(&header(kid), &claims(0), &jwk.key).unwrap();
+
+        assert!(ClientJwt::parse(&token(Some(&jwk.kid))).is_ok());
+        assert!(ClientJwt::parse(&token(None)).is_ok());
+        assert!(matches!(
+            ClientJwt::parse(&token(Some("unknown"))),
+            Err(Error::InvalidToken)
+        ));
+    }
+
+    #[test]
+    fn test_parse_requires_access_token_type() {
+        let token = sign(claims(0), "JWT").unwrap();
+        assert!(matches!(ClientJwt::parse(&token), Err(Error::InvalidToken)));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(parse(claims(0)).validate(&[&AUDIENCE]).is_ok());
+        // inside the allowed clock skew either way
+        assert!(parse(claims(-3600 - *JWT_LEEWAY + 5))
+            .validate(&[&AUDIENCE])
+            .is_ok());
+        assert!(parse(claims(*JWT_LEEWAY - 5))
+            .validate(&[&AUDIENCE])
+            .is_ok());
+    }
+
+    #[rocket::async_test]
+    async fn test_revoked_past_exp() {
+        // expired, but still inside the clock skew validate() allows
+        let jwt = parse(claims(-3600 - 5));
+        assert!(jwt.validate(&[&AUDIENCE]).is_ok());
+
+        let revocations = RevocationStorage::new();
+        revocations.revoke(&jwt.token_id()).await;
+        revocations.remove_expired().await;
+        assert!(jwt.is_revoked(&revocations).await);
+    }
+
+    #[test]
+    fn test_validate_expired() {
+        let jwt = parse(claims(-3600 - *JWT_LEEWAY - 5));
+        assert!(matches!(
+            jwt.validate(&[&AUDIENCE]),
+            Err(Error::TokenExpired)
+        ));
+    }
+
+    #[test]
+    fn test_validate_not_yet_valid() {
+        let jwt = parse(claims(*JWT_LEEWAY + 5));
+        assert!(matches!(
+            jwt.validate(&[&AUDIENCE]),
+            Err(Error::TokenNotYetValid)
+        ));
+    }
+
+    #[test]
+    fn test_validate_issuer() {
+        let mut claims = claims(0);
+        claims.iss = "https://elsewhere.example".to_string();
+        assert!(matches!(
+            parse(claims).validate(&[&AUDIENCE]),
+            Err(Error::InvalidIssuer)
+        ));
+    }
+
+    #[test]
+    fn test_validate_audience() {
+        let jwt = parse(claims(0));
+        assert!(matches!(
+            jwt.validate(&["https://elsewhere.example"]),
+            Err(Error::InvalidAudience)
+        ));
+    }
 }
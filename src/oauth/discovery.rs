@@ -0,0 +1,198 @@
+use crate::config::{ISSUER, KEYS};
+use crate::oauth::client::TokenEndpointAuthMethod;
+use crate::oauth::grant_types::GrantType;
+use crate::oauth::jwe::{ContentEncryption, KeyManagement};
+use crate::oauth::jws::Algorithm;
+use crate::oauth::pkce::CodeChallengeMethod;
+use crate::oauth::scopes::Scope;
+use crate::oauth::BASE;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::Route;
+use std::convert::Infallible;
+
+// metadata field and handler name of every endpoint worth advertising
+const ENDPOINTS: [(&str, &str); 7] = [
+    ("authorization_endpoint", "authorize"),
+    ("token_endpoint", "token_endpoint"),
+    ("userinfo_endpoint", "userinfo_endpoint"),
+    ("jwks_uri", "get_keys"),
+    ("registration_endpoint", "register"),
+    ("introspection_endpoint", "introspect"),
+    ("revocation_endpoint", "revoke"),
+];
+
+// RFC 8414 section 2, plus the OpenID Connect Discovery 1.0 section 3 fields
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct ServerMetadata {
+    pub issuer: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authorization_endpoint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_endpoint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub userinfo_endpoint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwks_uri: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registration_endpoint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub introspection_endpoint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revocation_endpoint: Option<String>,
+    pub scopes_supported: Vec<String>,
+    pub response_types_supported: Vec<String>,
+    pub grant_types_supported: Vec<String>,
+    pub subject_types_supported: Vec<String>,
+    pub code_challenge_methods_supported: Vec<String>,
+    pub id_token_signing_alg_values_supported: Vec<Algorithm>,
+    pub userinfo_signing_alg_values_supported: Vec<Algorithm>,
+    pub id_token_encryption_alg_values_supported: Vec<KeyManagement>,
+    pub id_token_encryption_enc_values_supported: Vec<ContentEncryption>,
+    pub token_endpoint_auth_methods_supported: Vec<String>,
+    pub introspection_endpoint_auth_methods_supported: Vec<String>,
+    pub revocation_endpoint_auth_methods_supported: Vec<String>,
+    pub token_endpoint_auth_signing_alg_values_supported: Vec<Algorithm>,
+    // RFC 8705 section 3.3
+    pub tls_client_certificate_bound_access_tokens: bool,
+    // RFC 9449 section 5.1
+    pub dpop_signing_alg_values_supported: Vec<Algorithm>,
+}
+
+impl ServerMetadata {
+    // Only endpoints that are actually mounted under `base` end up in the document, so
+    // dropping a route from the stage drops it from discovery too.
+    pub fn new<'a>(routes: impl Iterator<Item = &'a Route>, base: &str) -> Self {
+        let base_url = ISSUER.trim_end_matches('/');
+        let routes = routes
+            .filter(|route| route.uri.base() == base)
+            .collect::<Vec<&Route>>();
+        let endpoint = |field: &str| {
+            let (_, handler) = ENDPOINTS.iter().find(|(f, _)| *f == field)?;
+            let route = routes
+                .iter()
+                .find(|route| route.name.as_deref() == Some(*handler))?;
+            Some(format!("{}{}", base_url, route.uri.path()))
+        };
+        // the next key may already use a newly configured algorithm
+        let keys = KEYS.current();
+        let mut signing_algs = vec![keys.active.alg];
+        if keys.next.alg != keys.active.alg {
+            signing_algs.push(keys.next.alg);
+        }
+        let auth_methods = || {
+            TokenEndpointAuthMethod::ALL
+                .iter()
+                .map(|m| m.to_string())
+                .collect()
+        };
+        // anything we can check a client_assertion with
+        let mut assertion_algs = Algorithm::ALL.to_vec();
+        assertion_algs.push(Algorithm::Hs256);
+
+        Self {
+            // has to match the iss of everything we sign, byte for byte
+            issuer: ISSUER.clone(),
+            authorization_endpoint: endpoint("authorization_endpoint"),
+            token_endpoint: endpoint("token_endpoint"),
+            userinfo_endpoint: endpoint("userinfo_endpoint"),
+            jwks_uri: endpoint("jwks_uri"),
+            registration_endpoint: endpoint("registration_endpoint"),
+            introspection_endpoint: endpoint("introspection_endpoint"),
+            revocation_endpoint: endpoint("revocation_endpoint"),
+            scopes_supported: Scope::ALL.iter().map(|s| s.to_string()).collect(),
+            response_types_supported: vec!["code".to_string()],
+            grant_types_supported: GrantType::ALL.iter().map(|g| g.to_string()).collect(),
+            subject_types_supported: vec!["public".to_string()],
+            code_challenge_methods_supported: CodeChallengeMethod::ALL
+                .iter()
+                .map(|m| m.to_string())
+                .collect(),
+            id_token_signing_alg_values_supported: signing_algs.clone(),
+            userinfo_signing_alg_values_supported: signing_algs,
+            id_token_encryption_alg_values_supported: KeyManagement::ALL.to_vec(),
+            id_token_encryption_enc_values_supported: ContentEncryption::ALL.to_vec(),
+            token_endpoint_auth_methods_supported: auth_methods(),
+            introspection_endpoint_auth_methods_supported: auth_methods(),
+            revocation_endpoint_auth_methods_supported: auth_methods(),
+            token_endpoint_auth_signing_alg_values_supported: assertion_algs,
+            tls_client_certificate_bound_access_tokens: true,
+            dpop_signing_alg_values_supported: Algorithm::ALL.to_vec(),
+        }
+    }
+}
+
+// built from whatever the running instance has mounted
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ServerMetadata {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Self::new(request.rocket().routes(), BASE))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[get("/token")]
+    fn token_endpoint() -> &'static str {
+        "token"
+    }
+
+    #[get("/keys")]
+    fn get_keys() -> &'static str {
+        "keys"
+    }
+
+    #[test]
+    fn test_only_mounted_endpoints() {
+        let routes = routes![token_endpoint, get_keys]
+            .into_iter()
+            .map(|route| route.map_base(|base| format!("/oauth{}", base)).unwrap())
+            .collect::<Vec<Route>>();
+        let metadata = ServerMetadata::new(routes.iter(), "/oauth");
+
+        let base_url = ISSUER.trim_end_matches('/');
+        assert_eq!(metadata.issuer, *ISSUER);
+        assert_eq!(
+            metadata.token_endpoint,
+            Some(format!("{}/oauth/token", base_url))
+        );
+        assert_eq!(metadata.jwks_uri, Some(format!("{}/oauth/keys", base_url)));
+        assert!(metadata.userinfo_endpoint.is_none());
+        assert!(ServerMetadata::new(routes.iter(), "/other")
+            .token_endpoint
+            .is_none());
+    }
+
+    #[test]
+    fn test_supported_values() {
+        let metadata = ServerMetadata::new(std::iter::empty(), "/oauth");
+        assert!(metadata.scopes_supported.contains(&"openid".to_string()));
+        assert!(metadata
+            .grant_types_supported
+            .contains(&"refresh_token".to_string()));
+        assert_eq!(metadata.code_challenge_methods_supported, vec!["S256"]);
+        assert!(metadata
+            .token_endpoint_auth_methods_supported
+            .contains(&"private_key_jwt".to_string()));
+        assert!(metadata
+            .token_endpoint_auth_methods_supported
+            .contains(&"self_signed_tls_client_auth".to_string()));
+        assert!(metadata.tls_client_certificate_bound_access_tokens);
+        assert_eq!(
+            metadata.dpop_signing_alg_values_supported,
+            Algorithm::ALL.to_vec()
+        );
+        assert!(metadata
+            .token_endpoint_auth_signing_alg_values_supported
+            .contains(&Algorithm::Hs256));
+        assert_eq!(
+            metadata.id_token_signing_alg_values_supported,
+            vec![KEYS.active().alg]
+        );
+    }
+}
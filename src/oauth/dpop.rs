@@ -0,0 +1,444 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::Rng;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Outcome, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::sync::Mutex;
+use rocket::State;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use crate::config::{DPOP_NONCE_TTL, DPOP_PROOF_TTL, ISSUER, JWT_LEEWAY};
+use crate::oauth::client_jwt::ClientJwt;
+use crate::oauth::error::Error;
+use crate::oauth::jwk::{Jwk, PublicJwk};
+use crate::oauth::jws::{self, Compact};
+
+// RFC 9449 section 4.2
+pub const DPOP_JWT_TYPE: &str = "dpop+jwt";
+// token_type of DPoP-bound access tokens, and the Authorization scheme they are sent with
+pub const DPOP_TOKEN_TYPE: &str = "DPoP";
+
+// the key a proof was signed with travels in its own header
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ProofHeader {
+    typ: String,
+    jwk: PublicJwk,
+}
+
+// RFC 9449 section 4.2
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ProofClaims {
+    jti: String,
+    htm: String,
+    htu: String,
+    iat: i64,
+    // base64url SHA-256 of the access token, when there is one
+    #[serde(default)]
+    ath: Option<String>,
+    #[serde(default)]
+    nonce: Option<String>,
+}
+
+pub type DpopProofs<'r> = &'r State<DpopStorage>;
+// Nonces are an HMAC over the current time window, so there is nothing to keep for them.
+// Proofs are kept by key thumbprint and jti until they'd be too old to use anyway.
+#[derive(Clone)]
+pub struct DpopStorage {
+    secret: Arc<[u8; 32]>,
+    proofs: Arc<Mutex<HashMap<String, i64>>>,
+}
+
+impl DpopStorage {
+    pub fn new() -> Self {
+        Self {
+            secret: Arc::new(rand::thread_rng().gen()),
+            proofs: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn nonce_for(&self, window: i64) -> Result<String, Error> {
+        let mac = jws::hmac(self.secret.as_slice(), window.to_string().as_bytes())?;
+        Ok(URL_SAFE_NO_PAD.encode(mac))
+    }
+
+    fn window() -> i64 {
+        chrono::offset::Utc::now().timestamp() / (*DPOP_NONCE_TTL).max(1)
+    }
+
+    // what goes out in DPoP-Nonce
+    pub fn nonce(&self) -> Result<String, Error> {
+        self.nonce_for(Self::window())
+    }
+
+    // the previous nonce too, or clients would trip over every rotation
+    pub fn is_valid_nonce(&self, nonce: &str) -> bool {
+        let window = Self::window();
+        [window, window - 1]
+            .into_iter()
+            .any(|window| self.nonce_for(window).is_ok_and(|n| n == nonce))
+    }
+
+    // false if the same key already made a proof with this jti
+    pub async fn use_once(&self, jkt: &str, jti: &str, expires_at: i64) -> bool {
+        self.proofs
+            .lock()
+            .await
+            .insert(format!("{} {}", jkt, jti), expires_at)
+            .is_none()
+    }
+
+    pub async fn remove_expired(&self) {
+        let now = chrono::offset::Utc::now().timestamp();
+        self.proofs
+            .lock()
+            .await
+            .retain(|_, expires_at| *expires_at > now);
+    }
+}
+
+// The DPoP header of a request, with the method and URL its proof has to be for. Checking it
+// waits until it's known which access token, if any, the proof has to cover.
+pub struct DpopProof {
+    proof: Option<String>,
+    htm: String,
+    htu: String,
+}
+
+impl DpopProof {
+    // RFC 9449 section 4.3, the thumbprint of the key the proof was signed with. No proof
+    // at all is fine, the request just isn't using DPoP.
+    pub async fn verify(
+        &self,
+        access_token: Option<&str>,
+        proofs: &DpopStorage,
+    ) -> Result<Option<String>, Error> {
+        let proof = match &self.proof {
+            Some(proof) => proof,
+            None => return Ok(None),
+        };
+        let compact = Compact::parse(proof).map_err(|_| Error::InvalidDpopProof)?;
+        let header = proof.split('.').next().unwrap_or_default();
+        let header: ProofHeader = jws::decode_part(header).map_err(|_| Error::InvalidDpopProof)?;
+        if header.typ != DPOP_JWT_TYPE {
+            return Err(Error::InvalidDpopProof);
+        }
+        // only asymmetric algorithms, which verify_public sees to
+        let claims: ProofClaims = compact
+            .verify_public(&header.jwk)
+            .map_err(|_| Error::InvalidDpopProof)?;
+
+        // query and fragment don't count
+        let htu = claims.htu.split(['?', '#']).next().unwrap_or_default();
+        if claims.htm != self.htm || htu != self.htu {
+            return Err(Error::InvalidDpopProof);
+        }
+        let now = chrono::offset::Utc::now().timestamp();
+        if claims.iat > now + *JWT_LEEWAY || claims.iat + *DPOP_PROOF_TTL + *JWT_LEEWAY < now {
+            return Err(Error::InvalidDpopProof);
+        }
+        if let Some(access_token) = access_token {
+            let ath = URL_SAFE_NO_PAD.encode(Sha256::digest(access_token.as_bytes()));
+            if claims.ath.as_deref() != Some(ath.as_str()) {
+                return Err(Error::InvalidDpopProof);
+            }
+        }
+        if !claims
+            .nonce
+            .as_deref()
+            .is_some_and(|nonce| proofs.is_valid_nonce(nonce))
+        {
+            return Err(Error::UseDpopNonce);
+        }
+
+        let jkt = header.jwk.thumbprint();
+        let expires_at = claims.iat + *DPOP_PROOF_TTL + *JWT_LEEWAY;
+        match proofs.use_once(&jkt, &claims.jti, expires_at).await {
+            true => Ok(Some(jkt)),
+            false => Err(Error::InvalidDpopProof),
+        }
+    }
+}
+
+// only ever fails for more than one DPoP header
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DpopProof {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let mut proofs = request.headers().get("DPoP");
+        let proof = proofs.next().map(str::to_string);
+        if proofs.next().is_some() {
+            return Outcome::Error((Status::BadRequest, Error::InvalidDpopProof));
+        }
+        Outcome::Success(DpopProof {
+            proof,
+            htm: request.method().as_str().to_string(),
+            htu: format!("{}{}", ISSUER.trim_end_matches('/'), request.uri().path()),
+        })
+    }
+}
+
+// RFC 9449 section 7: a ClientJwt sent as `Authorization: DPoP` along with a proof for it.
+// Bearer tokens still get through the way ClientJwt takes them, so long as they aren't
+// bound to a DPoP key.
+pub struct DpopJwt(ClientJwt);
+
+impl Deref for DpopJwt {
+    type Target = ClientJwt;
+
+    fn deref(&self) -> &ClientJwt {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DpopJwt {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let token = match request.headers().get_one("Authorization") {
+            Some(header) => match header.split_once(' ') {
+                Some((DPOP_TOKEN_TYPE, token)) => token,
+                _ => return request.guard::<ClientJwt>().await.map(DpopJwt),
+            },
+            None => return request.guard::<ClientJwt>().await.map(DpopJwt),
+        };
+
+        let jwt = match ClientJwt::authenticate(request, token).await {
+            Ok(jwt) => jwt,
+            Err(e) => return Outcome::Error(e),
+        };
+        let jkt = match jwt.claims().cnf.as_ref().and_then(|cnf| cnf.jkt.as_deref()) {
+            Some(jkt) => jkt,
+            None => return Outcome::Error((Status::Unauthorized, Error::InvalidToken)),
+        };
+        let proofs = match request.rocket().state::<DpopStorage>() {
+            Some(proofs) => proofs,
+            None => return Outcome::Error((Status::InternalServerError, Error::InvalidToken)),
+        };
+        let proof = match request.guard::<DpopProof>().await {
+            Outcome::Success(proof) => proof,
+            _ => return Outcome::Error((Status::Unauthorized, Error::InvalidDpopProof)),
+        };
+        match proof.verify(Some(token), proofs).await {
+            Ok(Some(proof_jkt)) if proof_jkt == jkt => Outcome::Success(DpopJwt(jwt)),
+            Ok(_) => Outcome::Error((Status::Unauthorized, Error::InvalidDpopProof)),
+            Err(e) => Outcome::Error((Status::Unauthorized, e)),
+        }
+    }
+}
+
+// the nonce the next proof should carry, for responses to requests that came with DPoP
+pub fn nonce_header(request: &Request<'_>) -> Option<String> {
+    request.headers().get_one("DPoP")?;
+    request.rocket().state::<DpopStorage>()?.nonce().ok()
+}
+
+// RFC 9449 section 8: hands out a DPoP-Nonce on whatever the token endpoint responds
+pub struct WithDpopNonce<R>(pub R);
+
+impl<'r, R: Responder<'r, 'static>> Responder<'r, 'static> for WithDpopNonce<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build_from(self.0.respond_to(request)?);
+        if let Some(nonce) = nonce_header(request) {
+            response.raw_header("DPoP-Nonce", nonce);
+        }
+        response.ok()
+    }
+}
+
+// how clients sign their proofs, the key goes along in the header
+#[allow(dead_code)] // used in unit tests
+pub fn encode_proof<C: Serialize>(jwk: &Jwk, claims: &C) -> Result<String, Error> {
+    let header = json!({
+        "typ": DPOP_JWT_TYPE,
+        "alg": jwk.alg,
+        "jwk": PublicJwk::from_key(&jwk.key)?,
+    });
+    let input = format!(
+        "{}.{}",
+        jws::encode_part(&header)?,
+        jws::encode_part(claims)?
+    );
+    let signature = jws::sign_bytes(&jwk.key, jwk.alg, input.as_bytes())?;
+    Ok(format!("{}.{}", input, URL_SAFE_NO_PAD.encode(signature)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::oauth::jws::Algorithm;
+    use rocket::serde::json::Value;
+    use uuid::Uuid;
+
+    fn proof_for(method: &str, path: &str) -> DpopProof {
+        DpopProof {
+            proof: None,
+            htm: method.to_string(),
+            htu: format!("{}{}", ISSUER.trim_end_matches('/'), path),
+        }
+    }
+
+    fn claims(proofs: &DpopStorage) -> Value {
+        json!({
+            "jti": Uuid::new_v4().to_string(),
+            "htm": "POST",
+            "htu": format!("{}/oauth/token", ISSUER.trim_end_matches('/')),
+            "iat": chrono::offset::Utc::now().timestamp(),
+            "nonce": proofs.nonce().unwrap(),
+        })
+    }
+
+    async fn verify(
+        proofs: &DpopStorage,
+        jwk: &Jwk,
+        claims: &Value,
+        access_token: Option<&str>,
+    ) -> Result<Option<String>, Error> {
+        let mut request = proof_for("POST", "/oauth/token");
+        request.proof = Some(encode_proof(jwk, claims).unwrap());
+        request.verify(access_token, proofs).await
+    }
+
+    #[rocket::async_test]
+    async fn test_verify() {
+        let proofs = DpopStorage::new();
+        for alg in Algorithm::ALL {
+            let jwk = Jwk::generate(alg).unwrap();
+            let jkt = verify(&proofs, &jwk, &claims(&proofs), None).await.unwrap();
+            assert_eq!(jkt, Some(jwk.thumbprint()));
+        }
+        // the query doesn't count
+        let jwk = Jwk::generate(Algorithm::Es256).unwrap();
+        let mut claims = claims(&proofs);
+        claims["htu"] = json!(format!("{}?a=b", claims["htu"].as_str().unwrap()));
+        assert!(verify(&proofs, &jwk, &claims, None).await.is_ok());
+        // no proof, no DPoP
+        let request = proof_for("POST", "/oauth/token");
+        assert_eq!(request.verify(None, &proofs).await.unwrap(), None);
+    }
+
+    #[rocket::async_test]
+    async fn test_verify_request() {
+        let proofs = DpopStorage::new();
+        let jwk = Jwk::generate(Algorithm::Es256).unwrap();
+        let now = chrono::offset::Utc::now().timestamp();
+        for (claim, value) in [
+            ("htm", json!("GET")),
+            ("htu", json!("https://elsewhere.example/oauth/token")),
+            ("iat", json!(now - *DPOP_PROOF_TTL - *JWT_LEEWAY - 5)),
+            ("iat", json!(now + *JWT_LEEWAY + 5)),
+        ] {
+            let mut claims = claims(&proofs);
+            claims[claim] = value;
+            assert!(matches!(
+                verify(&proofs, &jwk, &claims, None).await,
+                Err(Error::InvalidDpopProof)
+            ));
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_verify_nonce() {
+        let proofs = DpopStorage::new();
+        let jwk = Jwk::generate(Algorithm::Es256).unwrap();
+        let mut claims = claims(&proofs);
+        claims.as_object_mut().unwrap().remove("nonce");
+        assert!(matches!(
+            verify(&proofs, &jwk, &claims, None).await,
+            Err(Error::UseDpopNonce)
+        ));
+        // somebody else's
+        claims["nonce"] = json!(DpopStorage::new().nonce().unwrap());
+        assert!(matches!(
+            verify(&proofs, &jwk, &claims, None).await,
+            Err(Error::UseDpopNonce)
+        ));
+        assert!(proofs.is_valid_nonce(&proofs.nonce_for(DpopStorage::window() - 1).unwrap()));
+        assert!(!proofs.is_valid_nonce(&proofs.nonce_for(DpopStorage::window() - 2).unwrap()));
+    }
+
+    #[rocket::async_test]
+    async fn test_verify_replay() {
+        let proofs = DpopStorage::new();
+        let jwk = Jwk::generate(Algorithm::Es256).unwrap();
+        let claims = claims(&proofs);
+        assert!(verify(&proofs, &jwk, &claims, None).await.is_ok());
+        assert!(matches!(
+            verify(&proofs, &jwk, &claims, None).await,
+            Err(Error::InvalidDpopProof)
+        ));
+        // another key may well pick the same jti
+        let other = Jwk::generate(Algorithm::Es256).unwrap();
+        assert!(verify(&proofs, &other, &claims, None).await.is_ok());
+    }
+
+    #[rocket::async_test]
+    async fn test_verify_access_token_hash() {
+        let proofs = DpopStorage::new();
+        let jwk = Jwk::generate(Algorithm::Es256).unwrap();
+        let mut claims = claims(&proofs);
+        claims["ath"] = json!(URL_SAFE_NO_PAD.encode(Sha256::digest(b"token")));
+        assert!(verify(&proofs, &jwk, &claims, Some("token")).await.is_ok());
+
+        claims["jti"] = json!(Uuid::new_v4().to_string());
+        assert!(matches!(
+            verify(&proofs, &jwk, &claims, Some("other")).await,
+            Err(Error::InvalidDpopProof)
+        ));
+        claims.as_object_mut().unwrap().remove("ath");
+        assert!(matches!(
+            verify(&proofs, &jwk, &claims, Some("token")).await,
+            Err(Error::InvalidDpopProof)
+        ));
+    }
+
+    #[rocket::async_test]
+    async fn test_verify_malformed() {
+        let proofs = DpopStorage::new();
+        let jwk = Jwk::generate(Algorithm::Es256).unwrap();
+        // a plain JWT, no key in the header
+        let jwt = jws::encode(
+            &crate::oauth::claims::TokenHeader {
+                alg: jwk.alg,
+                kid: None,
+                typ: Some(DPOP_JWT_TYPE.to_string()),
+            },
+            &claims(&proofs),
+            &jwk.key,
+        )
+        .unwrap();
+        // signed by another key than the one in the header
+        let other = Jwk::generate(Algorithm::Es256).unwrap();
+        let proof = encode_proof(&jwk, &claims(&proofs)).unwrap();
+        let forged = encode_proof(&other, &claims(&proofs)).unwrap();
+        let (header, _) = proof.split_once('.').unwrap();
+        let (_, rest) = forged.split_once('.').unwrap();
+        for proof in [jwt, format!("{}.{}", header, rest), "nope".to_string()] {
+            let mut request = proof_for("POST", "/oauth/token");
+            request.proof = Some(proof);
+            assert!(matches!(
+                request.verify(None, &proofs).await,
+                Err(Error::InvalidDpopProof)
+            ));
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_remove_expired() {
+        let proofs = DpopStorage::new();
+        assert!(proofs.use_once("jkt", "old", 0).await);
+        assert!(proofs.use_once("jkt", "new", i64::MAX).await);
+        proofs.remove_expired().await;
+        assert!(proofs.use_once("jkt", "old", 0).await);
+        assert!(!proofs.use_once("jkt", "new", i64::MAX).await);
+    }
+}
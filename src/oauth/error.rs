@@ -1,27 +1,79 @@
-use jwt;
 use openssl::error::ErrorStack;
 use rocket::http::Status;
+use rocket::response::status::Custom;
+use rocket::serde::json::{json, Value};
 
 #[derive(Debug)]
 pub enum Error {
     InvalidGrantType,
+    // RFC 8693 section 2.2.2 uses it for any subject or actor token we won't accept
+    InvalidRequest,
     RateLimited,
     InvalidSecret,
     InvalidClient,
     InvalidToken,
     InvalidClientName,
+    InvalidClientMetadata,
     InvalidAuthHeader,
     InvalidAuthType,
     InvalidResourceAccess,
     InvalidCode,
     InvalidCodeChallengeMethod,
-    Jwt(jwt::Error),
+    InvalidGrant,
+    InvalidScope,
+    InvalidRedirectUri,
+    TokenExpired,
+    TokenNotYetValid,
+    InvalidIssuer,
+    InvalidAudience,
+    // RFC 8707 section 2: a resource we don't know or that wasn't granted
+    InvalidTarget,
+    // RFC 9449 section 5, a DPoP proof that is missing, malformed or doesn't fit the request
+    InvalidDpopProof,
+    // RFC 9449 section 8, the proof needs the nonce we hand out in DPoP-Nonce
+    UseDpopNonce,
+    // a configured signing key we have no algorithm for
+    UnsupportedKey,
+    // the retired key may still have tokens out there that a rotation would orphan
+    KeyRotationTooSoon,
     OpenSSLError(ErrorStack),
+    Io(std::io::Error),
+    Database(sqlx::Error),
 }
 
-impl From<jwt::Error> for Error {
-    fn from(e: jwt::Error) -> Self {
-        Error::Jwt(e)
+impl Error {
+    // the `error` code from RFC 6749 section 5.2 for token endpoint responses
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::InvalidGrantType => "unsupported_grant_type",
+            Error::InvalidRequest => "invalid_request",
+            Error::RateLimited => "temporarily_unavailable",
+            Error::InvalidSecret => "invalid_client",
+            Error::InvalidClient => "invalid_client",
+            Error::InvalidToken => "invalid_grant",
+            Error::InvalidClientName => "invalid_client_metadata",
+            Error::InvalidClientMetadata => "invalid_client_metadata",
+            Error::InvalidAuthHeader => "invalid_request",
+            Error::InvalidAuthType => "invalid_request",
+            Error::InvalidResourceAccess => "access_denied",
+            Error::InvalidCode => "invalid_grant",
+            Error::InvalidCodeChallengeMethod => "invalid_request",
+            Error::InvalidGrant => "invalid_grant",
+            Error::InvalidScope => "invalid_scope",
+            Error::InvalidRedirectUri => "invalid_request",
+            Error::TokenExpired => "invalid_token",
+            Error::TokenNotYetValid => "invalid_token",
+            Error::InvalidIssuer => "invalid_token",
+            Error::InvalidAudience => "invalid_token",
+            Error::InvalidTarget => "invalid_target",
+            Error::InvalidDpopProof => "invalid_dpop_proof",
+            Error::UseDpopNonce => "use_dpop_nonce",
+            Error::UnsupportedKey => "server_error",
+            Error::KeyRotationTooSoon => "invalid_request",
+            Error::OpenSSLError(_) => "server_error",
+            Error::Io(_) => "server_error",
+            Error::Database(_) => "server_error",
+        }
     }
 }
 
@@ -31,22 +83,56 @@ impl From<ErrorStack> for Error {
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(e: sqlx::Error) -> Self {
+        Error::Database(e)
+    }
+}
+
 impl From<Error> for Status {
     fn from(e: Error) -> Self {
         match e {
             Error::InvalidGrantType => Status::BadRequest,
+            Error::InvalidRequest => Status::BadRequest,
             Error::RateLimited => Status::TooManyRequests,
             Error::InvalidSecret => Status::Unauthorized,
             Error::InvalidClient => Status::Unauthorized,
             Error::InvalidToken => Status::Unauthorized,
             Error::InvalidClientName => Status::BadRequest,
+            Error::InvalidClientMetadata => Status::BadRequest,
             Error::InvalidAuthHeader => Status::BadRequest,
             Error::InvalidAuthType => Status::BadRequest,
             Error::InvalidResourceAccess => Status::Forbidden,
             Error::InvalidCode => Status::Forbidden,
             Error::InvalidCodeChallengeMethod => Status::BadRequest,
-            Error::Jwt(_) => Status::Unauthorized,
+            Error::InvalidGrant => Status::BadRequest,
+            Error::InvalidScope => Status::BadRequest,
+            Error::InvalidRedirectUri => Status::BadRequest,
+            Error::TokenExpired => Status::Unauthorized,
+            Error::TokenNotYetValid => Status::Unauthorized,
+            Error::InvalidIssuer => Status::Unauthorized,
+            Error::InvalidAudience => Status::Unauthorized,
+            Error::InvalidTarget => Status::BadRequest,
+            Error::InvalidDpopProof => Status::BadRequest,
+            Error::UseDpopNonce => Status::BadRequest,
+            Error::UnsupportedKey => Status::InternalServerError,
+            Error::KeyRotationTooSoon => Status::Conflict,
             Error::OpenSSLError(_) => Status::InternalServerError,
+            Error::Io(_) => Status::InternalServerError,
+            Error::Database(_) => Status::InternalServerError,
         }
     }
 }
+
+impl From<Error> for Custom<Value> {
+    fn from(e: Error) -> Self {
+        let body = json!({ "error": e.code() });
+        Custom(e.into(), body)
+    }
+}
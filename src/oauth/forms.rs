@@ -3,19 +3,60 @@ use rocket::serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use uuid::Uuid;
 
+use super::assertion::TrustedIssuer;
+use super::client::TokenExchanger;
+use super::jwk::JwkSet;
 use super::pkce::CodeChallengeMethod;
 
 pub type TokenRequestForm<'r> = Form<TokenRequest<'r>>;
 pub type AuthorizationRequestForm<'r> = Form<AuthorizationRequest<'r>>;
+pub type IntrospectionRequestForm<'r> = Form<IntrospectionRequest<'r>>;
+pub type RevocationRequestForm<'r> = Form<RevocationRequest<'r>>;
 
 #[derive(Debug, FromForm)]
 pub struct TokenRequest<'r> {
-    pub client_id: Uuid,
-    pub client_secret: String,
+    // all three can come in an Authorization: Basic header instead
+    pub client_id: Option<Uuid>,
+    // one of these two, unless the JWT bearer grant's assertion is the proof
+    pub client_secret: Option<String>,
+    pub client_assertion_type: Option<&'r str>,
+    pub client_assertion: Option<&'r str>,
     pub grant_type: &'r str,
     pub scope: Option<&'r str>,
     pub code: Option<&'r str>,
+    pub code_verifier: Option<&'r str>,
     pub redirect_uri: Option<&'r str>,
+    pub refresh_token: Option<&'r str>,
+    // RFC 8707, the one resource this access token is for
+    pub resource: Option<&'r str>,
+    // RFC 8693 section 2.1, for token exchange
+    pub subject_token: Option<&'r str>,
+    pub subject_token_type: Option<&'r str>,
+    pub actor_token: Option<&'r str>,
+    pub actor_token_type: Option<&'r str>,
+    pub requested_token_type: Option<&'r str>,
+    // RFC 7523 section 2.1
+    pub assertion: Option<&'r str>,
+}
+
+#[derive(Debug, FromForm)]
+pub struct IntrospectionRequest<'r> {
+    pub client_id: Option<Uuid>,
+    pub client_secret: Option<String>,
+    pub client_assertion_type: Option<&'r str>,
+    pub client_assertion: Option<&'r str>,
+    pub token: &'r str,
+    pub token_type_hint: Option<&'r str>,
+}
+
+#[derive(Debug, FromForm)]
+pub struct RevocationRequest<'r> {
+    pub client_id: Option<Uuid>,
+    pub client_secret: Option<String>,
+    pub client_assertion_type: Option<&'r str>,
+    pub client_assertion: Option<&'r str>,
+    pub token: &'r str,
+    pub token_type_hint: Option<&'r str>,
 }
 
 #[derive(Debug, FromForm)]
@@ -27,6 +68,9 @@ pub struct AuthorizationRequest<'r> {
     pub state: &'r str,
     pub code_challenge: &'r str,
     pub code_challenge_method: CodeChallengeMethod,
+    pub nonce: Option<&'r str>,
+    // RFC 8707, every resource the grant should cover
+    pub resource: Vec<&'r str>,
 }
 
 // JSON-y stuff here
@@ -36,4 +80,20 @@ pub struct AuthorizationRequest<'r> {
 pub struct RegisterRequest<'r> {
     pub name: Cow<'r, str>,
     pub description: Cow<'r, str>,
+    #[serde(default)]
+    pub redirect_uris: Vec<Cow<'r, str>>,
+    pub userinfo_signed_response_alg: Option<Cow<'r, str>>,
+    pub jwks: Option<JwkSet>,
+    pub id_token_encrypted_response_alg: Option<Cow<'r, str>>,
+    pub id_token_encrypted_response_enc: Option<Cow<'r, str>>,
+    pub access_token_format: Option<Cow<'r, str>>,
+    pub access_token_ttl: Option<i64>,
+    pub refresh_token_ttl: Option<i64>,
+    pub id_token_ttl: Option<i64>,
+    #[serde(default)]
+    pub token_exchangers: Vec<TokenExchanger>,
+    #[serde(default)]
+    pub trusted_issuers: Vec<TrustedIssuer>,
+    pub token_endpoint_auth_method: Option<Cow<'r, str>>,
+    pub tls_client_auth_subject_dn: Option<Cow<'r, str>>,
 }
@@ -1,10 +1,40 @@
 use crate::oauth::Error;
+use std::fmt::Display;
 use std::str::FromStr;
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum GrantType {
     ClientCredentials,
     AuthorizationCode,
+    RefreshToken,
+    // RFC 8693
+    TokenExchange,
+    // RFC 7523
+    JwtBearer,
+}
+
+impl GrantType {
+    pub const ALL: [GrantType; 5] = [
+        GrantType::AuthorizationCode,
+        GrantType::ClientCredentials,
+        GrantType::RefreshToken,
+        GrantType::TokenExchange,
+        GrantType::JwtBearer,
+    ];
+}
+
+impl Display for GrantType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GrantType::ClientCredentials => write!(f, "client_credentials"),
+            GrantType::AuthorizationCode => write!(f, "authorization_code"),
+            GrantType::RefreshToken => write!(f, "refresh_token"),
+            GrantType::TokenExchange => {
+                write!(f, "urn:ietf:params:oauth:grant-type:token-exchange")
+            }
+            GrantType::JwtBearer => write!(f, "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+        }
+    }
 }
 
 impl FromStr for GrantType {
@@ -13,6 +43,9 @@ impl FromStr for GrantType {
         match s {
             "client_credentials" => Ok(GrantType::ClientCredentials),
             "authorization_code" => Ok(GrantType::AuthorizationCode),
+            "refresh_token" => Ok(GrantType::RefreshToken),
+            "urn:ietf:params:oauth:grant-type:token-exchange" => Ok(GrantType::TokenExchange),
+            "urn:ietf:params:oauth:grant-type:jwt-bearer" => Ok(GrantType::JwtBearer),
             _ => Err(Self::Err::InvalidGrantType),
         }
     }
@@ -30,7 +63,22 @@ mod test {
         let gt: GrantType = "authorization_code".parse().unwrap();
         assert!(gt == GrantType::AuthorizationCode);
 
+        let gt: GrantType = "refresh_token".parse().unwrap();
+        assert!(gt == GrantType::RefreshToken);
+
+        let gt: GrantType = "urn:ietf:params:oauth:grant-type:token-exchange"
+            .parse()
+            .unwrap();
+        assert!(gt == GrantType::TokenExchange);
+
         let gt: Result<GrantType, Error> = "bad_grant_type".parse();
         assert!(gt.is_err());
     }
+
+    #[test]
+    fn test_display_round_trip() {
+        for gt in GrantType::ALL {
+            assert_eq!(gt.to_string().parse::<GrantType>().unwrap(), gt);
+        }
+    }
 }
@@ -0,0 +1,125 @@
+use rocket::serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::oauth::claims::{Actor, Confirmation};
+use crate::oauth::client_jwt::ClientJwt;
+use crate::oauth::dpop::DPOP_TOKEN_TYPE;
+use crate::oauth::refresh::RefreshToken;
+use crate::oauth::scopes::Scope;
+
+// RFC 7662 section 2.2, anything but an active token is just {"active": false}
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct Introspection {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<String>,
+    // lets a resource server check the token was meant for it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>,
+    // RFC 8705 section 3.2 and RFC 9449 section 6.2, so resource servers can check the
+    // certificate or DPoP key too
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cnf: Option<Confirmation>,
+}
+
+impl Introspection {
+    pub fn inactive() -> Self {
+        Self::default()
+    }
+
+    pub fn from_access_token(jwt: &ClientJwt) -> Self {
+        let claims = jwt.claims();
+        if claims.exp <= chrono::offset::Utc::now().timestamp() {
+            return Self::inactive();
+        }
+        Self {
+            active: true,
+            scope: Some(claims.scope.clone()),
+            client_id: Some(claims.client_id),
+            sub: Some(claims.sub.clone()),
+            exp: Some(claims.exp),
+            iat: Some(claims.iat),
+            token_type: Some(match claims.cnf.as_ref().and_then(|cnf| cnf.jkt.as_ref()) {
+                Some(_) => DPOP_TOKEN_TYPE.to_string(),
+                None => "Bearer".to_string(),
+            }),
+            aud: Some(claims.aud.clone()),
+            act: claims.act.clone(),
+            cnf: claims.cnf.clone(),
+        }
+    }
+
+    pub fn from_refresh_token(refresh_token: &RefreshToken) -> Self {
+        let now = chrono::offset::Utc::now().timestamp();
+        if refresh_token.used || refresh_token.expires_at <= now {
+            return Self::inactive();
+        }
+        Self {
+            active: true,
+            scope: Some(
+                refresh_token
+                    .scope
+                    .iter()
+                    .map(Scope::to_string)
+                    .collect::<Vec<String>>()
+                    .join(" "),
+            ),
+            client_id: Some(refresh_token.client_id),
+            sub: Some(refresh_token.account_id.to_string()),
+            exp: Some(refresh_token.expires_at),
+            iat: Some(refresh_token.issued_at),
+            token_type: None,
+            aud: None,
+            act: None,
+            cnf: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_inactive_serializes_to_active_false() {
+        assert_eq!(
+            serde_json::to_value(Introspection::inactive()).unwrap(),
+            serde_json::json!({ "active": false })
+        );
+    }
+
+    #[test]
+    fn test_from_refresh_token() {
+        let mut refresh_token = RefreshToken::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            vec![Scope::OpenId, Scope::OfflineAccess],
+            None,
+            3600,
+        );
+        let introspection = Introspection::from_refresh_token(&refresh_token);
+        assert!(introspection.active);
+        assert_eq!(introspection.scope.unwrap(), "openid offline_access");
+        assert_eq!(introspection.client_id, Some(refresh_token.client_id));
+        assert_eq!(
+            introspection.sub,
+            Some(refresh_token.account_id.to_string())
+        );
+
+        refresh_token.used = true;
+        assert!(!Introspection::from_refresh_token(&refresh_token).active);
+    }
+}
@@ -0,0 +1,454 @@
+use crate::oauth::error::Error;
+use crate::oauth::jwk::{JwkKeyType, PublicJwk, PublicKeyUse};
+use crate::oauth::jws::{decode_part, encode_part};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use openssl::derive::Deriver;
+use openssl::ec::EcKey;
+use openssl::encrypt::{Decrypter, Encrypter};
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::{HasPrivate, HasPublic, Id, PKey, PKeyRef, Private};
+use openssl::rand::rand_bytes;
+use openssl::rsa::Padding;
+use openssl::sign::Signer;
+use openssl::symm::{self, decrypt_aead, encrypt_aead, Cipher};
+use rocket::serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+// RFC 7518 section 4.1, how the content encryption key gets to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum KeyManagement {
+    #[serde(rename = "RSA-OAEP-256")]
+    RsaOaep256,
+    // direct key agreement, there is no encrypted key in the token
+    #[serde(rename = "ECDH-ES")]
+    EcdhEs,
+}
+
+impl KeyManagement {
+    pub const ALL: [KeyManagement; 2] = [KeyManagement::RsaOaep256, KeyManagement::EcdhEs];
+
+    fn key_type(&self) -> JwkKeyType {
+        match self {
+            KeyManagement::RsaOaep256 => JwkKeyType::Rsa,
+            KeyManagement::EcdhEs => JwkKeyType::Ec,
+        }
+    }
+}
+
+impl fmt::Display for KeyManagement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyManagement::RsaOaep256 => write!(f, "RSA-OAEP-256"),
+            KeyManagement::EcdhEs => write!(f, "ECDH-ES"),
+        }
+    }
+}
+
+impl FromStr for KeyManagement {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        KeyManagement::ALL
+            .into_iter()
+            .find(|alg| alg.to_string() == s)
+            .ok_or(())
+    }
+}
+
+// RFC 7518 section 5.1, what the content itself is encrypted with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum ContentEncryption {
+    // the default when a client names no `enc`, OpenID Connect Registration section 2
+    #[serde(rename = "A128CBC-HS256")]
+    A128CbcHs256,
+    #[serde(rename = "A256GCM")]
+    A256Gcm,
+}
+
+impl ContentEncryption {
+    pub const ALL: [ContentEncryption; 2] =
+        [ContentEncryption::A128CbcHs256, ContentEncryption::A256Gcm];
+
+    // both happen to want a 256 bit key, the CBC one splits it between HMAC and AES
+    const KEY_SIZE: usize = 32;
+
+    fn iv_size(&self) -> usize {
+        match self {
+            ContentEncryption::A128CbcHs256 => 16,
+            ContentEncryption::A256Gcm => 12,
+        }
+    }
+
+    // ciphertext and authentication tag
+    fn seal(
+        &self,
+        cek: &[u8],
+        iv: &[u8],
+        aad: &[u8],
+        plaintext: &[u8],
+    ) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        match self {
+            ContentEncryption::A128CbcHs256 => {
+                let (mac_key, enc_key) = cek.split_at(16);
+                let ciphertext =
+                    symm::encrypt(Cipher::aes_128_cbc(), enc_key, Some(iv), plaintext)?;
+                let tag = cbc_hmac_tag(mac_key, iv, aad, &ciphertext)?;
+                Ok((ciphertext, tag))
+            }
+            ContentEncryption::A256Gcm => {
+                let mut tag = vec![0; 16];
+                let ciphertext = encrypt_aead(
+                    Cipher::aes_256_gcm(),
+                    cek,
+                    Some(iv),
+                    aad,
+                    plaintext,
+                    &mut tag,
+                )?;
+                Ok((ciphertext, tag))
+            }
+        }
+    }
+
+    fn open(
+        &self,
+        cek: &[u8],
+        iv: &[u8],
+        aad: &[u8],
+        ciphertext: &[u8],
+        tag: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        match self {
+            ContentEncryption::A128CbcHs256 => {
+                let (mac_key, enc_key) = cek.split_at(16);
+                let expected = cbc_hmac_tag(mac_key, iv, aad, ciphertext)?;
+                if tag.len() != expected.len() || !openssl::memcmp::eq(tag, &expected) {
+                    return Err(Error::InvalidToken);
+                }
+                Ok(symm::decrypt(
+                    Cipher::aes_128_cbc(),
+                    enc_key,
+                    Some(iv),
+                    ciphertext,
+                )?)
+            }
+            ContentEncryption::A256Gcm => {
+                decrypt_aead(Cipher::aes_256_gcm(), cek, Some(iv), aad, ciphertext, tag)
+                    .map_err(|_| Error::InvalidToken)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ContentEncryption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ContentEncryption::A128CbcHs256 => write!(f, "A128CBC-HS256"),
+            ContentEncryption::A256Gcm => write!(f, "A256GCM"),
+        }
+    }
+}
+
+impl FromStr for ContentEncryption {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ContentEncryption::ALL
+            .into_iter()
+            .find(|enc| enc.to_string() == s)
+            .ok_or(())
+    }
+}
+
+// RFC 7516 section 4.1
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct JweHeader {
+    pub alg: KeyManagement,
+    pub enc: ContentEncryption,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    // "JWT" for a signed token nested inside, RFC 7519 section 5.2
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cty: Option<String>,
+    // the ephemeral public key for ECDH-ES
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub epk: Option<PublicJwk>,
+}
+
+// what a client asked for, and the registered key that goes with it
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct Encryption {
+    pub alg: KeyManagement,
+    pub enc: ContentEncryption,
+    pub key: PublicJwk,
+}
+
+impl Encryption {
+    // Picks the first of the client's keys that fits `alg`: the right type, not meant for
+    // signatures and not registered for another algorithm.
+    pub fn select(keys: &[PublicJwk], alg: KeyManagement, enc: ContentEncryption) -> Option<Self> {
+        let key = keys.iter().find(|key| {
+            key.kty == alg.key_type()
+                && key.pk_use != Some(PublicKeyUse::Sig)
+                && key.alg.as_deref().is_none_or(|a| a == alg.to_string())
+                && key.public_key().is_ok()
+        })?;
+        Some(Self {
+            alg,
+            enc,
+            key: key.clone(),
+        })
+    }
+
+    // a nested JWT, RFC 7519 section 5.2, `jwt` being the signed token
+    pub fn encrypt_jwt(&self, jwt: &str) -> Result<String, Error> {
+        let recipient = self.key.public_key()?;
+        let mut header = JweHeader {
+            alg: self.alg,
+            enc: self.enc,
+            kid: self.key.kid.clone(),
+            cty: Some("JWT".to_string()),
+            epk: None,
+        };
+        let (cek, encrypted_key) = match self.alg {
+            KeyManagement::RsaOaep256 => {
+                let mut cek = vec![0; ContentEncryption::KEY_SIZE];
+                rand_bytes(&mut cek)?;
+                // RSA-OAEP-256 is SHA-256 for both OAEP and MGF1, RFC 7518 section 4.3
+                let mut encrypter = Encrypter::new(&recipient)?;
+                encrypter.set_rsa_padding(Padding::PKCS1_OAEP)?;
+                encrypter.set_rsa_oaep_md(MessageDigest::sha256())?;
+                encrypter.set_rsa_mgf1_md(MessageDigest::sha256())?;
+                let mut encrypted_key = vec![0; encrypter.encrypt_len(&cek)?];
+                let len = encrypter.encrypt(&cek, &mut encrypted_key)?;
+                encrypted_key.truncate(len);
+                (cek, encrypted_key)
+            }
+            KeyManagement::EcdhEs => {
+                let ec = recipient.ec_key()?;
+                let ephemeral = PKey::from_ec_key(EcKey::generate(ec.group())?)?;
+                header.epk = Some(PublicJwk::from_key(&ephemeral)?);
+                (ecdh_es_key(&ephemeral, &recipient, self.enc)?, Vec::new())
+            }
+        };
+
+        let protected = encode_part(&header)?;
+        let mut iv = vec![0; self.enc.iv_size()];
+        rand_bytes(&mut iv)?;
+        let (ciphertext, tag) = self
+            .enc
+            .seal(&cek, &iv, protected.as_bytes(), jwt.as_bytes())?;
+        Ok([
+            protected,
+            URL_SAFE_NO_PAD.encode(encrypted_key),
+            URL_SAFE_NO_PAD.encode(iv),
+            URL_SAFE_NO_PAD.encode(ciphertext),
+            URL_SAFE_NO_PAD.encode(tag),
+        ]
+        .join("."))
+    }
+}
+
+// What the client does on its end. Nothing sends us encrypted tokens yet, but the tests
+// need to read what we produce.
+#[allow(dead_code)] // used in unit tests
+pub fn decrypt(token: &str, key: &PKey<Private>) -> Result<(JweHeader, Vec<u8>), Error> {
+    let parts = token.split('.').collect::<Vec<&str>>();
+    if parts.len() != 5 {
+        return Err(Error::InvalidToken);
+    }
+    let header: JweHeader = decode_part(parts[0])?;
+    let decode = |part: &str| {
+        URL_SAFE_NO_PAD
+            .decode(part)
+            .map_err(|_| Error::InvalidToken)
+    };
+    let (encrypted_key, iv, ciphertext, tag) = (
+        decode(parts[1])?,
+        decode(parts[2])?,
+        decode(parts[3])?,
+        decode(parts[4])?,
+    );
+    let cek = match header.alg {
+        KeyManagement::RsaOaep256 => {
+            let mut decrypter = Decrypter::new(key)?;
+            decrypter.set_rsa_padding(Padding::PKCS1_OAEP)?;
+            decrypter.set_rsa_oaep_md(MessageDigest::sha256())?;
+            decrypter.set_rsa_mgf1_md(MessageDigest::sha256())?;
+            let mut cek = vec![0; decrypter.decrypt_len(&encrypted_key)?];
+            let len = decrypter
+                .decrypt(&encrypted_key, &mut cek)
+                .map_err(|_| Error::InvalidToken)?;
+            cek.truncate(len);
+            cek
+        }
+        KeyManagement::EcdhEs => {
+            let epk = header.epk.as_ref().ok_or(Error::InvalidToken)?;
+            let epk = epk.public_key().map_err(|_| Error::InvalidToken)?;
+            ecdh_es_key(key, &epk, header.enc)?
+        }
+    };
+    if cek.len() != ContentEncryption::KEY_SIZE || iv.len() != header.enc.iv_size() {
+        return Err(Error::InvalidToken);
+    }
+    let plaintext = header
+        .enc
+        .open(&cek, &iv, parts[0].as_bytes(), &ciphertext, &tag)?;
+    Ok((header, plaintext))
+}
+
+// RFC 7518 section 4.6.2: the Concat KDF from NIST SP 800-56A over the shared secret. A 256
+// bit key is a single round of SHA-256, and we send no apu or apv.
+fn ecdh_es_key<T: HasPrivate, U: HasPublic>(
+    private: &PKeyRef<T>,
+    public: &PKeyRef<U>,
+    enc: ContentEncryption,
+) -> Result<Vec<u8>, Error> {
+    if private.id() != Id::EC || public.id() != Id::EC {
+        return Err(Error::InvalidToken);
+    }
+    let mut deriver = Deriver::new(private)?;
+    deriver.set_peer(public).map_err(|_| Error::InvalidToken)?;
+    let z = deriver.derive_to_vec().map_err(|_| Error::InvalidToken)?;
+
+    let algorithm_id = enc.to_string();
+    let mut input = Vec::new();
+    input.extend_from_slice(&1u32.to_be_bytes());
+    input.extend_from_slice(&z);
+    input.extend_from_slice(&(algorithm_id.len() as u32).to_be_bytes());
+    input.extend_from_slice(algorithm_id.as_bytes());
+    input.extend_from_slice(&0u32.to_be_bytes());
+    input.extend_from_slice(&0u32.to_be_bytes());
+    input.extend_from_slice(&((ContentEncryption::KEY_SIZE * 8) as u32).to_be_bytes());
+    Ok(hash(MessageDigest::sha256(), &input)?.to_vec())
+}
+
+// RFC 7518 section 5.2.2.1, the first half of HMAC-SHA-256 over AAD, IV, ciphertext and
+// the AAD length in bits
+fn cbc_hmac_tag(
+    mac_key: &[u8],
+    iv: &[u8],
+    aad: &[u8],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let key = PKey::hmac(mac_key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(aad)?;
+    signer.update(iv)?;
+    signer.update(ciphertext)?;
+    signer.update(&((aad.len() as u64) * 8).to_be_bytes())?;
+    let mut tag = signer.sign_to_vec()?;
+    tag.truncate(16);
+    Ok(tag)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::oauth::jwk::Jwk;
+    use crate::oauth::jws::Algorithm;
+
+    fn recipient(alg: Algorithm) -> (PKey<Private>, PublicJwk) {
+        let jwk = Jwk::generate(alg).unwrap();
+        let mut public = PublicJwk::from_key(&jwk.key).unwrap();
+        public.kid = Some("client-key".to_string());
+        (jwk.key, public)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for (alg, key_alg) in [
+            (KeyManagement::RsaOaep256, Algorithm::Rs256),
+            (KeyManagement::EcdhEs, Algorithm::Es256),
+            (KeyManagement::EcdhEs, Algorithm::Es384),
+        ] {
+            for enc in ContentEncryption::ALL {
+                let (private, public) = recipient(key_alg);
+                let encryption = Encryption::select(&[public], alg, enc).unwrap();
+                let token = encryption.encrypt_jwt("a.signed.jwt").unwrap();
+                assert_eq!(token.split('.').count(), 5);
+
+                let (header, plaintext) = decrypt(&token, &private).unwrap();
+                assert_eq!(plaintext, b"a.signed.jwt");
+                assert_eq!(header.alg, alg);
+                assert_eq!(header.enc, enc);
+                assert_eq!(header.kid.as_deref(), Some("client-key"));
+                assert_eq!(header.cty.as_deref(), Some("JWT"));
+                assert_eq!(header.epk.is_some(), alg == KeyManagement::EcdhEs);
+
+                let (other, _) = recipient(key_alg);
+                assert!(decrypt(&token, &other).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_rejects_tampering() {
+        for enc in ContentEncryption::ALL {
+            let (private, public) = recipient(Algorithm::Es256);
+            let encryption = Encryption::select(&[public], KeyManagement::EcdhEs, enc).unwrap();
+            let token = encryption.encrypt_jwt("a.signed.jwt").unwrap();
+            let mut parts = token.split('.').map(str::to_string).collect::<Vec<_>>();
+            let mut ciphertext = URL_SAFE_NO_PAD.decode(&parts[3]).unwrap();
+            ciphertext[0] ^= 1;
+            parts[3] = URL_SAFE_NO_PAD.encode(ciphertext);
+            assert!(decrypt(&parts.join("."), &private).is_err());
+        }
+    }
+
+    #[test]
+    fn test_select() {
+        let (_, rsa) = recipient(Algorithm::Rs256);
+        let (_, mut ec) = recipient(Algorithm::Es256);
+        let keys = [rsa.clone(), ec.clone()];
+        let select = |keys: &[PublicJwk], alg| {
+            Encryption::select(keys, alg, ContentEncryption::A256Gcm).map(|e| e.key)
+        };
+        assert_eq!(select(&keys, KeyManagement::RsaOaep256), Some(rsa.clone()));
+        assert_eq!(select(&keys, KeyManagement::EcdhEs), Some(ec.clone()));
+
+        // not for signature keys, or keys meant for another algorithm
+        ec.pk_use = Some(PublicKeyUse::Sig);
+        assert!(select(&[ec.clone()], KeyManagement::EcdhEs).is_none());
+        ec.pk_use = Some(PublicKeyUse::Enc);
+        ec.alg = Some("ECDH-ES+A128KW".to_string());
+        assert!(select(&[ec], KeyManagement::EcdhEs).is_none());
+    }
+
+    #[test]
+    fn test_cbc_hmac_tag() {
+        // RFC 7516 appendix B.5
+        let mac_key = [
+            4, 211, 31, 197, 84, 157, 252, 254, 11, 100, 157, 250, 63, 170, 106, 206,
+        ];
+        let aad = b"eyJhbGciOiJBMTI4S1ciLCJlbmMiOiJBMTI4Q0JDLUhTMjU2In0";
+        let iv = [
+            3, 22, 60, 12, 43, 67, 104, 105, 108, 108, 105, 99, 111, 116, 104, 101,
+        ];
+        let ciphertext = [
+            40, 57, 83, 181, 119, 33, 133, 148, 198, 185, 243, 24, 152, 230, 6, 75, 129, 223, 127,
+            19, 210, 82, 183, 230, 168, 33, 215, 104, 143, 112, 56, 102,
+        ];
+        assert_eq!(
+            cbc_hmac_tag(&mac_key, &iv, aad, &ciphertext).unwrap(),
+            vec![83, 73, 191, 98, 104, 205, 211, 128, 201, 189, 199, 133, 32, 38, 194, 85]
+        );
+    }
+
+    #[test]
+    fn test_from_str() {
+        for alg in KeyManagement::ALL {
+            assert_eq!(alg.to_string().parse::<KeyManagement>(), Ok(alg));
+        }
+        for enc in ContentEncryption::ALL {
+            assert_eq!(enc.to_string().parse::<ContentEncryption>(), Ok(enc));
+        }
+        assert!("RSA1_5".parse::<KeyManagement>().is_err());
+    }
+}
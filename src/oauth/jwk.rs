@@ -1,66 +1,537 @@
+use crate::oauth::jws::Algorithm;
 use crate::oauth::Error;
-use jwt::AlgorithmType;
-use openssl::pkey::PKey;
-use openssl::pkey::Private;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, BigNumContext, MsbOption};
+use openssl::ec::{EcGroup, EcKey, EcKeyRef};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{HasPublic, Id, PKey, Private, Public};
 use openssl::rsa::Rsa;
-use rocket::serde::{uuid::Uuid, Serialize};
+use openssl::x509::{X509Builder, X509NameBuilder, X509};
+use rocket::serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-#[derive(Debug, Clone, Serialize, PartialEq)]
+const CERTIFICATE_DAYS: u32 = 365;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(crate = "rocket::serde")]
 pub enum JwkKeyType {
+    #[serde(rename = "RSA")]
     Rsa,
-    // EC,
+    #[serde(rename = "EC")]
+    Ec,
+    // RFC 8037, Edwards curve keys
+    #[serde(rename = "OKP")]
+    Okp,
     // OCT,
 }
 
-#[derive(Debug, Clone, Serialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(crate = "rocket::serde")]
 pub enum PublicKeyUse {
+    #[serde(rename = "sig")]
     Sig,
-    // ENC,
+    #[serde(rename = "enc")]
+    Enc,
 }
 
+// RFC 7517 section 4 and RFC 7518 section 6, everything but the private key
 #[derive(Debug, Clone, Serialize)]
 #[serde(crate = "rocket::serde")]
 pub struct Jwk {
     pub kty: JwkKeyType,
+    #[serde(rename = "use")]
     pub pk_use: PublicKeyUse,
-    pub n: String,
-    pub e: String,
-    pub kid: uuid::Uuid,
-    pub alg: AlgorithmType,
+    // P-256, P-384 or Ed25519, RSA keys have none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crv: Option<String>,
+    // RSA modulus and exponent, base64url big-endian integers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>,
+    // EC point coordinates padded to the curve size, or the raw Ed25519 public key in x
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>,
+    pub kid: String,
+    pub alg: Algorithm,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub x5c: Vec<String>,
+    #[serde(rename = "x5t#S256", skip_serializing_if = "Option::is_none")]
+    pub x5t_s256: Option<String>,
     #[serde(skip)]
     pub key: PKey<Private>,
+    // what x5c is made of, stored along with the key so x5c and x5t#S256 stay put
+    #[serde(skip)]
+    pub certificate: X509,
 }
 
 impl Jwk {
-    pub fn new() -> Result<Self, Error> {
-        let key = Rsa::generate(2048)?;
-        let n = key.n().to_dec_str()?.to_string();
-        let e = key.e().to_dec_str()?.to_string();
-        let kid = Uuid::new_v4();
-        let key = PKey::from_rsa(key)?;
-        Ok(Jwk {
+    pub fn generate(alg: Algorithm) -> Result<Self, Error> {
+        let key = match alg {
+            Algorithm::Rs256 => PKey::from_rsa(Rsa::generate(2048)?)?,
+            Algorithm::Es256 => ec_key(Nid::X9_62_PRIME256V1)?,
+            Algorithm::Es384 => ec_key(Nid::SECP384R1)?,
+            Algorithm::EdDsa => PKey::generate_ed25519()?,
+            Algorithm::Hs256 => return Err(Error::UnsupportedKey),
+        };
+        Self::from_key(key)
+    }
+
+    pub fn from_key(key: PKey<Private>) -> Result<Self, Error> {
+        let certificate = self_signed(&key)?;
+        Self::with_certificate(key, certificate)
+    }
+
+    // the algorithm follows from the key, there is only one we use per key type and curve
+    pub fn with_certificate(key: PKey<Private>, certificate: X509) -> Result<Self, Error> {
+        if !certificate.public_key()?.public_eq(&key) {
+            return Err(Error::UnsupportedKey);
+        }
+        let der = certificate.to_der()?;
+        let mut jwk = Jwk {
             kty: JwkKeyType::Rsa,
             pk_use: PublicKeyUse::Sig,
-            n,
-            e,
-            kid,
-            alg: AlgorithmType::Rs256,
+            crv: None,
+            n: None,
+            e: None,
+            x: None,
+            y: None,
+            kid: String::new(),
+            alg: Algorithm::Rs256,
+            x5c: vec![STANDARD.encode(&der)],
+            x5t_s256: Some(URL_SAFE_NO_PAD.encode(Sha256::digest(&der))),
             key,
-        })
+            certificate,
+        };
+        match jwk.key.id() {
+            Id::RSA => {
+                let rsa = jwk.key.rsa()?;
+                jwk.n = Some(URL_SAFE_NO_PAD.encode(rsa.n().to_vec()));
+                jwk.e = Some(URL_SAFE_NO_PAD.encode(rsa.e().to_vec()));
+            }
+            Id::EC => {
+                let ec = jwk.key.ec_key()?;
+                let (crv, x, y) = ec_members(&ec)?;
+                jwk.kty = JwkKeyType::Ec;
+                jwk.alg = match crv.as_str() {
+                    "P-256" => Algorithm::Es256,
+                    _ => Algorithm::Es384,
+                };
+                jwk.crv = Some(crv);
+                jwk.x = Some(x);
+                jwk.y = Some(y);
+            }
+            Id::ED25519 => {
+                jwk.kty = JwkKeyType::Okp;
+                jwk.crv = Some("Ed25519".to_string());
+                jwk.x = Some(URL_SAFE_NO_PAD.encode(jwk.key.raw_public_key()?));
+                jwk.alg = Algorithm::EdDsa;
+            }
+            _ => return Err(Error::UnsupportedKey),
+        }
+        jwk.kid = jwk.thumbprint();
+        Ok(jwk)
+    }
+
+    pub fn thumbprint(&self) -> String {
+        thumbprint(&self.kty, &self.crv, &self.n, &self.e, &self.x, &self.y)
+    }
+
+    pub fn public_key(&self) -> Result<PKey<Public>, Error> {
+        Ok(PKey::public_key_from_der(&self.key.public_key_to_der()?)?)
     }
 }
 
+// A public key somebody else holds the private half of, like the encryption keys clients
+// register. Unlike `Jwk` it only knows what it was given.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct PublicJwk {
+    pub kty: JwkKeyType,
+    #[serde(rename = "use", default, skip_serializing_if = "Option::is_none")]
+    pub pk_use: Option<PublicKeyUse>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    // whatever the client wrote, we only compare it against what we are asked to use
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alg: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crv: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>,
+}
+
+// RFC 7517 section 5
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct JwkSet {
+    pub keys: Vec<PublicJwk>,
+}
+
+impl PublicJwk {
+    // RSA, EC and Ed25519 public keys, with everything else left empty
+    pub fn from_key<T: HasPublic>(key: &PKey<T>) -> Result<Self, Error> {
+        let mut jwk = PublicJwk {
+            kty: JwkKeyType::Rsa,
+            pk_use: None,
+            kid: None,
+            alg: None,
+            crv: None,
+            n: None,
+            e: None,
+            x: None,
+            y: None,
+        };
+        match key.id() {
+            Id::RSA => {
+                let rsa = key.rsa()?;
+                jwk.n = Some(URL_SAFE_NO_PAD.encode(rsa.n().to_vec()));
+                jwk.e = Some(URL_SAFE_NO_PAD.encode(rsa.e().to_vec()));
+            }
+            Id::EC => {
+                let ec = key.ec_key()?;
+                let (crv, x, y) = ec_members(&ec)?;
+                jwk.kty = JwkKeyType::Ec;
+                jwk.crv = Some(crv);
+                jwk.x = Some(x);
+                jwk.y = Some(y);
+            }
+            Id::ED25519 => {
+                jwk.kty = JwkKeyType::Okp;
+                jwk.crv = Some("Ed25519".to_string());
+                jwk.x = Some(URL_SAFE_NO_PAD.encode(key.raw_public_key()?));
+            }
+            _ => return Err(Error::UnsupportedKey),
+        }
+        Ok(jwk)
+    }
+
+    // what DPoP proofs get bound to, the same way our own kids are made
+    pub fn thumbprint(&self) -> String {
+        thumbprint(&self.kty, &self.crv, &self.n, &self.e, &self.x, &self.y)
+    }
+
+    // whether a signature made with `alg` can have come from this key
+    pub fn verifies(&self, alg: Algorithm) -> bool {
+        let fits = matches!(
+            (alg, &self.kty, self.crv.as_deref()),
+            (Algorithm::Rs256, JwkKeyType::Rsa, _)
+                | (Algorithm::Es256, JwkKeyType::Ec, Some("P-256"))
+                | (Algorithm::Es384, JwkKeyType::Ec, Some("P-384"))
+                | (Algorithm::EdDsa, JwkKeyType::Okp, Some("Ed25519"))
+        );
+        fits && self.pk_use != Some(PublicKeyUse::Enc)
+            && self.alg.as_ref().is_none_or(|a| *a == alg.to_string())
+    }
+
+    // EC points are checked to be on the curve, so nobody gets to hand us a weak one
+    pub fn public_key(&self) -> Result<PKey<Public>, Error> {
+        let bytes = |member: &Option<String>| -> Result<Vec<u8>, Error> {
+            URL_SAFE_NO_PAD
+                .decode(member.as_deref().ok_or(Error::UnsupportedKey)?)
+                .map_err(|_| Error::UnsupportedKey)
+        };
+        let member = |member: &Option<String>| -> Result<BigNum, Error> {
+            Ok(BigNum::from_slice(&bytes(member)?)?)
+        };
+        match self.kty {
+            JwkKeyType::Rsa => Ok(PKey::from_rsa(Rsa::from_public_components(
+                member(&self.n)?,
+                member(&self.e)?,
+            )?)?),
+            JwkKeyType::Ec => {
+                let curve = match self.crv.as_deref() {
+                    Some("P-256") => Nid::X9_62_PRIME256V1,
+                    Some("P-384") => Nid::SECP384R1,
+                    _ => return Err(Error::UnsupportedKey),
+                };
+                let group = EcGroup::from_curve_name(curve)?;
+                let (x, y) = (member(&self.x)?, member(&self.y)?);
+                let ec = EcKey::from_public_key_affine_coordinates(&group, &x, &y)?;
+                Ok(PKey::from_ec_key(ec)?)
+            }
+            JwkKeyType::Okp => match self.crv.as_deref() {
+                Some("Ed25519") => Ok(PKey::public_key_from_raw_bytes(
+                    &bytes(&self.x)?,
+                    Id::ED25519,
+                )?),
+                _ => Err(Error::UnsupportedKey),
+            },
+        }
+    }
+}
+
+// RFC 7638 section 3 (and RFC 8037 section 2 for OKP): the required members in
+// lexicographic order, no whitespace
+fn thumbprint(
+    kty: &JwkKeyType,
+    crv: &Option<String>,
+    n: &Option<String>,
+    e: &Option<String>,
+    x: &Option<String>,
+    y: &Option<String>,
+) -> String {
+    let member = |value: &Option<String>| value.clone().unwrap_or_default();
+    let members = match kty {
+        JwkKeyType::Rsa => format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, member(e), member(n)),
+        JwkKeyType::Ec => format!(
+            r#"{{"crv":"{}","kty":"EC","x":"{}","y":"{}"}}"#,
+            member(crv),
+            member(x),
+            member(y)
+        ),
+        JwkKeyType::Okp => format!(
+            r#"{{"crv":"{}","kty":"OKP","x":"{}"}}"#,
+            member(crv),
+            member(x)
+        ),
+    };
+    URL_SAFE_NO_PAD.encode(Sha256::digest(members.as_bytes()))
+}
+
+fn ec_key(curve: Nid) -> Result<PKey<Private>, Error> {
+    let group = EcGroup::from_curve_name(curve)?;
+    Ok(PKey::from_ec_key(EcKey::generate(&group)?)?)
+}
+
+// crv, x and y of the EC curves we know, coordinates padded to the curve size
+fn ec_members<T: HasPublic>(ec: &EcKeyRef<T>) -> Result<(String, String, String), Error> {
+    let (crv, size) = match ec.group().curve_name() {
+        Some(Nid::X9_62_PRIME256V1) => ("P-256", 32),
+        Some(Nid::SECP384R1) => ("P-384", 48),
+        _ => return Err(Error::UnsupportedKey),
+    };
+    let mut x = BigNum::new()?;
+    let mut y = BigNum::new()?;
+    let mut ctx = BigNumContext::new()?;
+    ec.public_key()
+        .affine_coordinates(ec.group(), &mut x, &mut y, &mut ctx)?;
+    Ok((
+        crv.to_string(),
+        URL_SAFE_NO_PAD.encode(x.to_vec_padded(size)?),
+        URL_SAFE_NO_PAD.encode(y.to_vec_padded(size)?),
+    ))
+}
+
+// Nobody vouches for this certificate, it only gives x5c consumers the key in a form they
+// know how to read.
+fn self_signed(key: &PKey<Private>) -> Result<X509, Error> {
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, "rockettest")?;
+    let name = name.build();
+    let mut serial = BigNum::new()?;
+    serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+    let serial = serial.to_asn1_integer()?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(CERTIFICATE_DAYS)?;
+
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(key)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    // Ed25519 signs the certificate without a separate digest
+    let digest = match key.id() {
+        Id::ED25519 => MessageDigest::null(),
+        _ => MessageDigest::sha256(),
+    };
+    builder.sign(key, digest)?;
+    Ok(builder.build())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use rocket::serde::json::json;
+
+    #[test]
+    fn test_generate() {
+        for (alg, kty) in [
+            (Algorithm::Rs256, JwkKeyType::Rsa),
+            (Algorithm::Es256, JwkKeyType::Ec),
+            (Algorithm::Es384, JwkKeyType::Ec),
+            (Algorithm::EdDsa, JwkKeyType::Okp),
+        ] {
+            let jwk = Jwk::generate(alg).unwrap();
+            assert_eq!(jwk.kty, kty);
+            assert_eq!(jwk.alg, alg);
+            assert_eq!(jwk.pk_use, PublicKeyUse::Sig);
+            assert_eq!(jwk.kid, jwk.thumbprint());
+            // what a key store reads back has to come out the same
+            let pem = jwk.key.private_key_to_pem_pkcs8().unwrap();
+            let loaded = Jwk::from_key(PKey::private_key_from_pem(&pem).unwrap()).unwrap();
+            assert_eq!(loaded.alg, alg);
+            assert_eq!(loaded.kid, jwk.kid);
+        }
+    }
+
+    #[test]
+    fn test_serialization() {
+        let jwk = Jwk::generate(Algorithm::Rs256).unwrap();
+        let value = json!(jwk);
+        assert_eq!(value["kty"], "RSA");
+        assert_eq!(value["use"], "sig");
+        assert_eq!(value["alg"], "RS256");
+        assert_eq!(value["e"], "AQAB");
+        assert!(value.get("pk_use").is_none());
+        assert!(value.get("key").is_none());
+        assert!(value.get("crv").is_none());
+        assert!(value.get("x").is_none());
+
+        let n = URL_SAFE_NO_PAD
+            .decode(value["n"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(n, jwk.key.rsa().unwrap().n().to_vec());
+
+        let certificate = STANDARD.decode(value["x5c"][0].as_str().unwrap()).unwrap();
+        let certificate = X509::from_der(&certificate).unwrap();
+        assert!(certificate
+            .public_key()
+            .unwrap()
+            .public_eq(&jwk.public_key().unwrap()));
+        assert_eq!(
+            value["x5t#S256"],
+            URL_SAFE_NO_PAD.encode(Sha256::digest(certificate.to_der().unwrap()))
+        );
+    }
+
+    #[test]
+    fn test_serialization_ec() {
+        let jwk = Jwk::generate(Algorithm::Es384).unwrap();
+        let value = json!(jwk);
+        assert_eq!(value["kty"], "EC");
+        assert_eq!(value["crv"], "P-384");
+        assert_eq!(value["alg"], "ES384");
+        assert!(value.get("n").is_none());
+        for coordinate in ["x", "y"] {
+            let decoded = URL_SAFE_NO_PAD
+                .decode(value[coordinate].as_str().unwrap())
+                .unwrap();
+            assert_eq!(decoded.len(), 48);
+        }
+
+        let certificate = STANDARD.decode(value["x5c"][0].as_str().unwrap()).unwrap();
+        let certificate = X509::from_der(&certificate).unwrap();
+        assert!(certificate
+            .public_key()
+            .unwrap()
+            .public_eq(&jwk.public_key().unwrap()));
+    }
+
+    #[test]
+    fn test_serialization_okp() {
+        let jwk = Jwk::generate(Algorithm::EdDsa).unwrap();
+        let value = json!(jwk);
+        assert_eq!(value["kty"], "OKP");
+        assert_eq!(value["crv"], "Ed25519");
+        assert_eq!(value["alg"], "EdDSA");
+        assert!(value.get("y").is_none());
+        let x = URL_SAFE_NO_PAD
+            .decode(value["x"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(x, jwk.key.raw_public_key().unwrap());
+        assert!(value["x5c"][0].is_string());
+    }
+
+    #[test]
+    fn test_from_key_rejects_other_curves() {
+        let group = EcGroup::from_curve_name(Nid::SECP521R1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        assert!(matches!(Jwk::from_key(key), Err(Error::UnsupportedKey)));
+    }
+
+    #[test]
+    fn test_with_certificate() {
+        let jwk = Jwk::generate(Algorithm::Es256).unwrap();
+        let again = Jwk::with_certificate(jwk.key.clone(), jwk.certificate.clone()).unwrap();
+        assert_eq!(again.x5c, jwk.x5c);
+        assert_eq!(again.x5t_s256, jwk.x5t_s256);
+
+        let other = Jwk::generate(Algorithm::Es256).unwrap();
+        assert!(matches!(
+            Jwk::with_certificate(jwk.key, other.certificate),
+            Err(Error::UnsupportedKey)
+        ));
+    }
+
+    #[test]
+    fn test_public_jwk_round_trip() {
+        for alg in Algorithm::ALL {
+            let jwk = Jwk::generate(alg).unwrap();
+            let public = PublicJwk::from_key(&jwk.key).unwrap();
+            assert!(public
+                .public_key()
+                .unwrap()
+                .public_eq(&jwk.public_key().unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_public_jwk_verifies() {
+        let mut public =
+            PublicJwk::from_key(&Jwk::generate(Algorithm::Es384).unwrap().key).unwrap();
+        assert!(public.verifies(Algorithm::Es384));
+        assert!(!public.verifies(Algorithm::Es256));
+        assert!(!public.verifies(Algorithm::Rs256));
+
+        public.alg = Some("ES256".to_string());
+        assert!(!public.verifies(Algorithm::Es384));
+        public.alg = None;
+        public.pk_use = Some(PublicKeyUse::Enc);
+        assert!(!public.verifies(Algorithm::Es384));
+    }
+
+    #[test]
+    fn test_public_jwk_rejects_point_off_curve() {
+        let jwk = Jwk::generate(Algorithm::Es256).unwrap();
+        let mut public = PublicJwk::from_key(&jwk.key).unwrap();
+        public.y = public.x.clone();
+        assert!(public.public_key().is_err());
+    }
+
+    #[test]
+    fn test_thumbprint() {
+        // RFC 7638 section 3.1
+        let mut jwk = Jwk::generate(Algorithm::Rs256).unwrap();
+        jwk.n = Some("0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw".to_string());
+        jwk.e = Some("AQAB".to_string());
+        assert_eq!(
+            jwk.thumbprint(),
+            "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs"
+        );
+    }
+
+    #[test]
+    fn test_public_jwk_thumbprint() {
+        for alg in Algorithm::ALL {
+            let jwk = Jwk::generate(alg).unwrap();
+            let public = PublicJwk::from_key(&jwk.key).unwrap();
+            assert_eq!(public.thumbprint(), jwk.thumbprint());
+        }
+    }
 
     #[test]
-    fn test_new() {
-        let jwk = Jwk::new().unwrap();
-        assert_eq!(jwk.kty, JwkKeyType::Rsa);
-        assert_eq!(jwk.alg, AlgorithmType::Rs256);
-        assert_eq!(jwk.pk_use, PublicKeyUse::Sig);
+    fn test_thumbprint_okp() {
+        // RFC 8037 appendix A.3
+        let mut jwk = Jwk::generate(Algorithm::EdDsa).unwrap();
+        jwk.x = Some("11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo".to_string());
+        assert_eq!(
+            jwk.thumbprint(),
+            "kPrK_qmxVWaYVA9wwBF6Iuo3vVzz7TxHCTwXBygrS4k"
+        );
     }
 }
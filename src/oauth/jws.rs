@@ -0,0 +1,332 @@
+use crate::oauth::claims::TokenHeader;
+use crate::oauth::error::Error;
+use crate::oauth::jwk::{Jwk, PublicJwk};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use openssl::bn::BigNum;
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::{PKey, Private, Public};
+use openssl::sign::{Signer, Verifier};
+use rocket::serde::de::DeserializeOwned;
+use rocket::serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+// RFC 7518 section 3.1 and RFC 8037 section 3.1, the ones we can sign with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum Algorithm {
+    #[serde(rename = "RS256")]
+    Rs256,
+    #[serde(rename = "ES256")]
+    Es256,
+    #[serde(rename = "ES384")]
+    Es384,
+    #[serde(rename = "EdDSA")]
+    EdDsa,
+    // only ever checked, for client_secret_jwt assertions HMAC-ed with the client secret
+    #[serde(rename = "HS256")]
+    Hs256,
+}
+
+impl Algorithm {
+    pub const ALL: [Algorithm; 4] = [
+        Algorithm::Rs256,
+        Algorithm::Es256,
+        Algorithm::Es384,
+        Algorithm::EdDsa,
+    ];
+
+    // EdDSA hashes internally, so it gets none
+    fn digest(&self) -> Option<MessageDigest> {
+        match self {
+            Algorithm::Rs256 | Algorithm::Es256 | Algorithm::Hs256 => Some(MessageDigest::sha256()),
+            Algorithm::Es384 => Some(MessageDigest::sha384()),
+            Algorithm::EdDsa => None,
+        }
+    }
+
+    // bytes of each of R and S in an ECDSA signature, RFC 7518 section 3.4
+    fn ecdsa_size(&self) -> Option<usize> {
+        match self {
+            Algorithm::Es256 => Some(32),
+            Algorithm::Es384 => Some(48),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Algorithm::Rs256 => write!(f, "RS256"),
+            Algorithm::Es256 => write!(f, "ES256"),
+            Algorithm::Es384 => write!(f, "ES384"),
+            Algorithm::EdDsa => write!(f, "EdDSA"),
+            Algorithm::Hs256 => write!(f, "HS256"),
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Algorithm::ALL
+            .into_iter()
+            .find(|alg| alg.to_string() == s)
+            .ok_or(())
+    }
+}
+
+pub fn sign_bytes(key: &PKey<Private>, alg: Algorithm, input: &[u8]) -> Result<Vec<u8>, Error> {
+    if alg == Algorithm::Hs256 {
+        return Err(Error::UnsupportedKey);
+    }
+    let mut signer = match alg.digest() {
+        Some(digest) => Signer::new(digest, key)?,
+        None => Signer::new_without_digest(key)?,
+    };
+    let signature = signer.sign_oneshot_to_vec(input)?;
+    // OpenSSL speaks DER, JOSE wants R and S back to back at a fixed width
+    match alg.ecdsa_size() {
+        Some(size) => {
+            let signature = EcdsaSig::from_der(&signature)?;
+            let r = signature.r().to_vec_padded(size as i32)?;
+            let s = signature.s().to_vec_padded(size as i32)?;
+            Ok([r, s].concat())
+        }
+        None => Ok(signature),
+    }
+}
+
+fn verify_bytes(
+    key: &PKey<Public>,
+    alg: Algorithm,
+    input: &[u8],
+    signature: &[u8],
+) -> Result<bool, Error> {
+    if alg == Algorithm::Hs256 {
+        return Ok(false);
+    }
+    let signature = match alg.ecdsa_size() {
+        Some(size) if signature.len() != size * 2 => return Ok(false),
+        Some(size) => {
+            let (r, s) = signature.split_at(size);
+            EcdsaSig::from_private_components(BigNum::from_slice(r)?, BigNum::from_slice(s)?)?
+                .to_der()?
+        }
+        None => signature.to_vec(),
+    };
+    let mut verifier = match alg.digest() {
+        Some(digest) => Verifier::new(digest, key)?,
+        None => Verifier::new_without_digest(key)?,
+    };
+    Ok(verifier.verify_oneshot(&signature, input)?)
+}
+
+pub fn hmac(secret: &[u8], input: &[u8]) -> Result<Vec<u8>, Error> {
+    let key = PKey::hmac(secret)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(input)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+pub fn encode_part<T: Serialize>(part: &T) -> Result<String, Error> {
+    let json = serde_json::to_vec(part).map_err(|_| Error::InvalidToken)?;
+    Ok(URL_SAFE_NO_PAD.encode(json))
+}
+
+pub fn decode_part<T: DeserializeOwned>(part: &str) -> Result<T, Error> {
+    let json = URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_| Error::InvalidToken)?;
+    serde_json::from_slice(&json).map_err(|_| Error::InvalidToken)
+}
+
+// RFC 7515 compact serialization, signed with whatever algorithm the header names
+pub fn encode<C: Serialize>(
+    header: &TokenHeader,
+    claims: &C,
+    key: &PKey<Private>,
+) -> Result<String, Error> {
+    let input = format!("{}.{}", encode_part(header)?, encode_part(claims)?);
+    let signature = sign_bytes(key, header.alg, input.as_bytes())?;
+    Ok(format!("{}.{}", input, URL_SAFE_NO_PAD.encode(signature)))
+}
+
+// HS256 with a shared secret, the way clients write client_secret_jwt assertions
+#[allow(dead_code)] // used in unit tests
+pub fn encode_hmac<C: Serialize>(
+    header: &TokenHeader,
+    claims: &C,
+    secret: &[u8],
+) -> Result<String, Error> {
+    let input = format!("{}.{}", encode_part(header)?, encode_part(claims)?);
+    let signature = hmac(secret, input.as_bytes())?;
+    Ok(format!("{}.{}", input, URL_SAFE_NO_PAD.encode(signature)))
+}
+
+// A compact JWS taken apart but not checked yet. The header is needed first to know which
+// key to check it with.
+pub struct Compact<'a> {
+    pub header: TokenHeader,
+    input: &'a str,
+    payload: &'a str,
+    signature: Vec<u8>,
+}
+
+impl<'a> Compact<'a> {
+    pub fn parse(token: &'a str) -> Result<Self, Error> {
+        let (input, signature) = token.rsplit_once('.').ok_or(Error::InvalidToken)?;
+        let (header, payload) = input.split_once('.').ok_or(Error::InvalidToken)?;
+        // five parts would be a JWE
+        if payload.contains('.') {
+            return Err(Error::InvalidToken);
+        }
+        Ok(Self {
+            header: decode_part(header)?,
+            input,
+            payload,
+            signature: URL_SAFE_NO_PAD
+                .decode(signature)
+                .map_err(|_| Error::InvalidToken)?,
+        })
+    }
+
+    // the header has to name the key's own algorithm, nobody gets to pick a weaker one
+    pub fn verify<C: DeserializeOwned>(&self, jwk: &Jwk) -> Result<C, Error> {
+        if self.header.alg != jwk.alg {
+            return Err(Error::InvalidToken);
+        }
+        self.verify_with(&jwk.public_key()?)
+    }
+
+    // someone else's key, which has to fit the algorithm the header names
+    pub fn verify_public<C: DeserializeOwned>(&self, jwk: &PublicJwk) -> Result<C, Error> {
+        if !jwk.verifies(self.header.alg) {
+            return Err(Error::InvalidToken);
+        }
+        self.verify_with(&jwk.public_key()?)
+    }
+
+    pub fn verify_hmac<C: DeserializeOwned>(&self, secret: &[u8]) -> Result<C, Error> {
+        if self.header.alg != Algorithm::Hs256 {
+            return Err(Error::InvalidToken);
+        }
+        let expected = hmac(secret, self.input.as_bytes())?;
+        match expected.len() == self.signature.len() && memcmp::eq(&expected, &self.signature) {
+            true => decode_part(self.payload),
+            false => Err(Error::InvalidToken),
+        }
+    }
+
+    fn verify_with<C: DeserializeOwned>(&self, key: &PKey<Public>) -> Result<C, Error> {
+        match verify_bytes(key, self.header.alg, self.input.as_bytes(), &self.signature)? {
+            true => decode_part(self.payload),
+            false => Err(Error::InvalidToken),
+        }
+    }
+
+    #[allow(dead_code)] // used in unit tests
+    pub fn claims_unverified<C: DeserializeOwned>(&self) -> Result<C, Error> {
+        decode_part(self.payload)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rocket::serde::json::{json, Value};
+
+    fn encode_with(jwk: &Jwk, claims: Value) -> String {
+        let header = TokenHeader {
+            alg: jwk.alg,
+            kid: Some(jwk.kid.clone()),
+            typ: Some("JWT".to_string()),
+        };
+        encode(&header, &claims, &jwk.key).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for alg in Algorithm::ALL {
+            let jwk = Jwk::generate(alg).unwrap();
+            let token = encode_with(&jwk, json!({ "sub": "grant" }));
+            let compact = Compact::parse(&token).unwrap();
+            assert_eq!(compact.header.alg, alg);
+            assert_eq!(compact.header.kid.as_deref(), Some(jwk.kid.as_str()));
+            let claims: Value = compact.verify(&jwk).unwrap();
+            assert_eq!(claims["sub"], "grant");
+
+            let other = Jwk::generate(alg).unwrap();
+            assert!(compact.verify::<Value>(&other).is_err());
+        }
+    }
+
+    #[test]
+    fn test_ecdsa_signature_size() {
+        let jwk = Jwk::generate(Algorithm::Es384).unwrap();
+        for _ in 0..16 {
+            let token = encode_with(&jwk, json!({}));
+            let signature = token.rsplit('.').next().unwrap();
+            assert_eq!(URL_SAFE_NO_PAD.decode(signature).unwrap().len(), 96);
+        }
+    }
+
+    #[test]
+    fn test_rejects_algorithm_mismatch() {
+        let jwk = Jwk::generate(Algorithm::Es256).unwrap();
+        let token = encode_with(&jwk, json!({}));
+        let mut compact = Compact::parse(&token).unwrap();
+        compact.header.alg = Algorithm::Rs256;
+        assert!(compact.verify::<Value>(&jwk).is_err());
+    }
+
+    #[test]
+    fn test_rejects_tampering() {
+        let jwk = Jwk::generate(Algorithm::EdDsa).unwrap();
+        let token = encode_with(&jwk, json!({ "admin": false }));
+        let mut parts = token.split('.').collect::<Vec<&str>>();
+        let tampered = encode_part(&json!({ "admin": true })).unwrap();
+        parts[1] = &tampered;
+        let compact_token = parts.join(".");
+        let compact = Compact::parse(&compact_token).unwrap();
+        assert!(compact.verify::<Value>(&jwk).is_err());
+        assert!(Compact::parse("not.a-token").is_err());
+    }
+
+    #[test]
+    fn test_hmac() {
+        let header = TokenHeader {
+            alg: Algorithm::Hs256,
+            kid: None,
+            typ: None,
+        };
+        let token = encode_hmac(&header, &json!({ "sub": "grant" }), b"secret").unwrap();
+        let compact = Compact::parse(&token).unwrap();
+        let claims: Value = compact.verify_hmac(b"secret").unwrap();
+        assert_eq!(claims["sub"], "grant");
+        assert!(compact.verify_hmac::<Value>(b"other").is_err());
+
+        // never with a key pair, and no key pair's token passes as HS256
+        let jwk = Jwk::generate(Algorithm::Es256).unwrap();
+        assert!(compact.verify::<Value>(&jwk).is_err());
+        assert!(encode(&header, &json!({}), &jwk.key).is_err());
+        let token = encode_with(&jwk, json!({}));
+        assert!(Compact::parse(&token)
+            .unwrap()
+            .verify_hmac::<Value>(b"secret")
+            .is_err());
+    }
+
+    #[test]
+    fn test_algorithm_from_str() {
+        for alg in Algorithm::ALL {
+            assert_eq!(alg.to_string().parse::<Algorithm>(), Ok(alg));
+        }
+        assert!("none".parse::<Algorithm>().is_err());
+    }
+}
@@ -0,0 +1,259 @@
+use crate::config::{JWT_LEEWAY, MAX_ACCESS_TOKEN_TTL, MAX_ID_TOKEN_TTL, SIGNING_ALG};
+use crate::oauth::error::Error;
+use crate::oauth::jwk::Jwk;
+use crate::oauth::jws::Algorithm;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock, RwLock};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyState {
+    // published ahead of time so caches already know it when it takes over
+    Next,
+    // the one everything gets signed with
+    Active,
+    // still verifies what it signed until the next rotation
+    Retired,
+}
+
+// Seconds a key has to keep verifying after it stopped signing: the longest lived token we
+// sign, plus the leeway verifiers give it.
+pub fn verification_overlap() -> i64 {
+    (*MAX_ACCESS_TOKEN_TTL).max(*MAX_ID_TOKEN_TTL) + *JWT_LEEWAY
+}
+
+impl fmt::Display for KeyState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyState::Next => write!(f, "next"),
+            KeyState::Active => write!(f, "active"),
+            KeyState::Retired => write!(f, "retired"),
+        }
+    }
+}
+
+impl FromStr for KeyState {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "next" => Ok(KeyState::Next),
+            "active" => Ok(KeyState::Active),
+            "retired" => Ok(KeyState::Retired),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KeySet {
+    pub active: Arc<Jwk>,
+    pub next: Arc<Jwk>,
+    pub retired: Option<Arc<Jwk>>,
+    // when the active key took over, for scheduled rotation
+    pub activated_at: i64,
+}
+
+impl KeySet {
+    pub fn generate() -> Result<Self, Error> {
+        Ok(Self {
+            active: Arc::new(Jwk::generate(*SIGNING_ALG)?),
+            next: Arc::new(Jwk::generate(*SIGNING_ALG)?),
+            retired: None,
+            activated_at: chrono::offset::Utc::now().timestamp(),
+        })
+    }
+
+    // Next takes over and a new next is made. Whatever was retired before is dropped, so
+    // that has to wait until nothing it signed is still valid.
+    pub fn rotated(&self) -> Result<Self, Error> {
+        if !self.may_rotate(chrono::offset::Utc::now().timestamp()) {
+            return Err(Error::KeyRotationTooSoon);
+        }
+        Ok(Self {
+            active: self.next.clone(),
+            next: Arc::new(Jwk::generate(*SIGNING_ALG)?),
+            retired: Some(self.active.clone()),
+            activated_at: chrono::offset::Utc::now().timestamp(),
+        })
+    }
+
+    // the retired key stopped signing when the active one took over
+    pub fn may_rotate(&self, now: i64) -> bool {
+        self.retired.is_none() || self.activated_at + verification_overlap() <= now
+    }
+
+    // The set without a compromised key, or None when the kid isn't ours. Losing the active
+    // key means next has to take over right away.
+    pub fn without(&self, kid: &str) -> Result<Option<Self>, Error> {
+        let set = match self.state(kid) {
+            Some(KeyState::Active) => Self {
+                active: self.next.clone(),
+                next: Arc::new(Jwk::generate(*SIGNING_ALG)?),
+                retired: self.retired.clone(),
+                activated_at: chrono::offset::Utc::now().timestamp(),
+            },
+            Some(KeyState::Next) => Self {
+                next: Arc::new(Jwk::generate(*SIGNING_ALG)?),
+                ..self.clone()
+            },
+            Some(KeyState::Retired) => Self {
+                retired: None,
+                ..self.clone()
+            },
+            None => return Ok(None),
+        };
+        Ok(Some(set))
+    }
+
+    pub fn keys(&self) -> Vec<(KeyState, &Arc<Jwk>)> {
+        let mut keys = vec![
+            (KeyState::Active, &self.active),
+            (KeyState::Next, &self.next),
+        ];
+        if let Some(retired) = &self.retired {
+            keys.push((KeyState::Retired, retired));
+        }
+        keys
+    }
+
+    pub fn state(&self, kid: &str) -> Option<KeyState> {
+        self.keys()
+            .into_iter()
+            .find(|(_, jwk)| jwk.kid == kid)
+            .map(|(state, _)| state)
+    }
+
+    pub fn find(&self, kid: &str) -> Option<Arc<Jwk>> {
+        self.keys()
+            .into_iter()
+            .find(|(_, jwk)| jwk.kid == kid)
+            .map(|(_, jwk)| jwk.clone())
+    }
+
+    // a published key for `alg`, the active one if it will do
+    pub fn find_alg(&self, alg: Algorithm) -> Option<Arc<Jwk>> {
+        self.keys()
+            .into_iter()
+            .find(|(_, jwk)| jwk.alg == alg)
+            .map(|(_, jwk)| jwk.clone())
+    }
+}
+
+// The key set everything gets signed and verified with. `key_store` fills it at ignition,
+// anything reading it before that (unit tests mostly) gets a throwaway set.
+pub struct SigningKeys(OnceLock<RwLock<KeySet>>);
+
+impl SigningKeys {
+    pub const fn new() -> Self {
+        Self(OnceLock::new())
+    }
+
+    fn lock(&self) -> &RwLock<KeySet> {
+        self.0
+            .get_or_init(|| RwLock::new(KeySet::generate().unwrap()))
+    }
+
+    pub fn current(&self) -> KeySet {
+        self.lock().read().unwrap().clone()
+    }
+
+    pub fn active(&self) -> Arc<Jwk> {
+        self.lock().read().unwrap().active.clone()
+    }
+
+    pub fn find(&self, kid: &str) -> Option<Arc<Jwk>> {
+        self.lock().read().unwrap().find(kid)
+    }
+
+    pub fn find_alg(&self, alg: Algorithm) -> Option<Arc<Jwk>> {
+        self.lock().read().unwrap().find_alg(alg)
+    }
+
+    // whichever key the `kid` header names, tokens without one predate rotation
+    pub fn verifying_key(&self, kid: Option<&str>) -> Result<Arc<Jwk>, Error> {
+        match kid {
+            Some(kid) => self.find(kid).ok_or(Error::InvalidToken),
+            None => Ok(self.active()),
+        }
+    }
+
+    pub fn replace(&self, set: KeySet) {
+        *self.lock().write().unwrap() = set;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rotated() {
+        let set = KeySet::generate().unwrap();
+        let rotated = set.rotated().unwrap();
+        assert_eq!(rotated.active.kid, set.next.kid);
+        assert_eq!(rotated.retired.as_ref().unwrap().kid, set.active.kid);
+        assert_ne!(rotated.next.kid, set.next.kid);
+
+        // tokens the retired key signed could still be valid
+        assert!(matches!(rotated.rotated(), Err(Error::KeyRotationTooSoon)));
+        let now = chrono::offset::Utc::now().timestamp();
+        assert!(!rotated.may_rotate(now + verification_overlap() - 60));
+        assert!(rotated.may_rotate(now + verification_overlap() + 60));
+
+        let rotated = KeySet {
+            activated_at: rotated.activated_at - verification_overlap(),
+            ..rotated
+        };
+        let twice = rotated.rotated().unwrap();
+        assert!(twice.find(&set.active.kid).is_none());
+        assert_eq!(twice.state(&set.next.kid), Some(KeyState::Retired));
+    }
+
+    #[test]
+    fn test_without_active() {
+        let set = KeySet::generate().unwrap().rotated().unwrap();
+        let revoked = set.without(&set.active.kid).unwrap().unwrap();
+        assert!(revoked.find(&set.active.kid).is_none());
+        assert_eq!(revoked.active.kid, set.next.kid);
+        assert_eq!(
+            revoked.retired.as_ref().unwrap().kid,
+            set.retired.as_ref().unwrap().kid
+        );
+    }
+
+    #[test]
+    fn test_without_next_and_retired() {
+        let set = KeySet::generate().unwrap().rotated().unwrap();
+        let revoked = set.without(&set.next.kid).unwrap().unwrap();
+        assert_eq!(revoked.active.kid, set.active.kid);
+        assert!(revoked.find(&set.next.kid).is_none());
+
+        let retired = set.retired.as_ref().unwrap();
+        let revoked = set.without(&retired.kid).unwrap().unwrap();
+        assert!(revoked.retired.is_none());
+        assert_eq!(revoked.active.kid, set.active.kid);
+
+        assert!(set.without("unknown").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_find_alg() {
+        let set = KeySet::generate().unwrap();
+        let retired = Arc::new(Jwk::generate(Algorithm::EdDsa).unwrap());
+        let set = KeySet {
+            retired: Some(retired.clone()),
+            ..set
+        };
+        assert_eq!(set.find_alg(set.active.alg).unwrap().kid, set.active.kid);
+        assert_eq!(set.find_alg(Algorithm::EdDsa).unwrap().kid, retired.kid);
+        assert!(set.find_alg(Algorithm::Hs256).is_none());
+    }
+
+    #[test]
+    fn test_key_state_round_trip() {
+        for state in [KeyState::Next, KeyState::Active, KeyState::Retired] {
+            assert_eq!(state.to_string().parse::<KeyState>(), Ok(state));
+        }
+    }
+}
@@ -1,54 +1,181 @@
+use rocket::http::uri::fmt::{Query, UriDisplay};
 use rocket::http::CookieJar;
 use rocket::http::Status;
-use rocket::response::status::{BadRequest, NoContent};
+use rocket::response::status::{BadRequest, Custom, NoContent};
 use rocket::response::Redirect;
 use rocket::serde::json::Json;
 use rocket::serde::json::{json, Value};
 use rocket_dyn_templates::{context, Template};
+use std::time::Duration;
 use uuid::Uuid;
 
+pub mod assertion;
+pub mod basic_auth;
+pub mod claims;
 pub mod client;
 pub mod client_jwt;
+pub mod discovery;
+pub mod dpop;
 pub mod error;
 pub mod forms;
 pub mod grant_types;
+pub mod introspection;
+pub mod jwe;
 pub mod jwk;
+pub mod jws;
+pub mod key_set;
+pub mod key_store;
+pub mod mtls;
 pub mod pkce;
+pub mod reference;
+pub mod refresh;
+pub mod resources;
+pub mod revocation;
 pub mod scopes;
 pub mod server;
 pub mod token;
+pub mod userinfo;
 
-use crate::config::KEY;
-use client::{Client, Clients};
+use crate::account::acc::Accounts;
+use crate::config::{KEYS, SWEEP_INTERVAL};
+use basic_auth::BasicAuth;
+use client::{Client, ClientMetadata, Clients, TokenExchanger};
+use client_jwt::{BearerError, ClientJwt};
+use discovery::ServerMetadata;
+use dpop::{DpopJwt, DpopProof, WithDpopNonce};
 use error::Error;
-use forms::{RegisterRequest, TokenRequestForm};
+use forms::{IntrospectionRequestForm, RegisterRequest, RevocationRequestForm, TokenRequestForm};
+use mtls::ClientCertificate;
 
 #[post("/token", data = "<token_request>")]
+#[allow(clippy::too_many_arguments)]
 async fn token_endpoint(
     token_request: TokenRequestForm<'_>,
+    basic: Result<BasicAuth, Error>,
+    certificate: Option<ClientCertificate>,
+    dpop: Result<DpopProof, Error>,
+    dpop_proofs: dpop::DpopProofs<'_>,
     clients: Clients<'_>,
     pkce_codes: pkce::PkceCodes<'_>,
-) -> Result<Value, Status> {
-    let token = server::token(token_request, clients, pkce_codes)
+    refresh_tokens: refresh::RefreshTokens<'_>,
+    revocations: revocation::Revocations<'_>,
+    reference_tokens: reference::ReferenceTokens<'_>,
+    resources: resources::Resources<'_>,
+) -> WithDpopNonce<Result<Value, Custom<Value>>> {
+    let token = async {
+        server::token(
+            token_request,
+            basic?,
+            certificate,
+            dpop?,
+            dpop_proofs,
+            clients,
+            pkce_codes,
+            refresh_tokens,
+            revocations,
+            reference_tokens,
+            resources,
+        )
         .await
-        .map_err(|e| -> Status { e.into() })?;
-    Ok(json!(token))
+    };
+    WithDpopNonce(
+        token
+            .await
+            .map(|token| json!(token))
+            .map_err(|e| -> Custom<Value> { e.into() }),
+    )
+}
+
+#[derive(Responder)]
+enum AuthorizeError {
+    Redirect(Redirect),
+    // never redirect when the client or redirect_uri can't be trusted
+    #[response(status = 400)]
+    Page(Template),
+}
+
+impl From<Error> for AuthorizeError {
+    fn from(e: Error) -> Self {
+        let message = match e {
+            Error::InvalidClient => "Unknown client.",
+            Error::InvalidRedirectUri => "The redirect_uri is not registered for this client.",
+            Error::InvalidTarget => "The requested resource is unknown.",
+            _ => "The authorization request is invalid.",
+        };
+        AuthorizeError::Page(Template::render(
+            "error",
+            context! {
+                error: e.code(),
+                message: message,
+            },
+        ))
+    }
+}
+
+#[post("/introspect", data = "<introspection_request>")]
+async fn introspect(
+    introspection_request: IntrospectionRequestForm<'_>,
+    basic: Result<BasicAuth, Error>,
+    certificate: Option<ClientCertificate>,
+    clients: Clients<'_>,
+    refresh_tokens: refresh::RefreshTokens<'_>,
+    revocations: revocation::Revocations<'_>,
+    reference_tokens: reference::ReferenceTokens<'_>,
+) -> Result<Value, Custom<Value>> {
+    let basic = basic.map_err(|e| -> Custom<Value> { e.into() })?;
+    let introspection = server::introspect(
+        introspection_request,
+        basic,
+        certificate,
+        clients,
+        refresh_tokens,
+        revocations,
+        reference_tokens,
+    )
+    .await
+    .map_err(|e| -> Custom<Value> { e.into() })?;
+    Ok(json!(introspection))
+}
+
+#[post("/revoke", data = "<revocation_request>")]
+async fn revoke(
+    revocation_request: RevocationRequestForm<'_>,
+    basic: Result<BasicAuth, Error>,
+    certificate: Option<ClientCertificate>,
+    clients: Clients<'_>,
+    refresh_tokens: refresh::RefreshTokens<'_>,
+    revocations: revocation::Revocations<'_>,
+    reference_tokens: reference::ReferenceTokens<'_>,
+) -> Result<(), Custom<Value>> {
+    let basic = basic.map_err(|e| -> Custom<Value> { e.into() })?;
+    server::revoke(
+        revocation_request,
+        basic,
+        certificate,
+        clients,
+        refresh_tokens,
+        revocations,
+        reference_tokens,
+    )
+    .await
+    .map_err(|e| -> Custom<Value> { e.into() })
 }
 
 #[get("/authorize?<auth_request..>")]
 async fn authorize(
     auth_request: forms::AuthorizationRequest<'_>,
     clients: Clients<'_>,
+    resources: resources::Resources<'_>,
     jar: &CookieJar<'_>,
-) -> Result<Template, Redirect> {
-    let user_cookie = jar.get("user_id");
+) -> Result<Template, AuthorizeError> {
+    let user_cookie = jar.get_private("user_id");
     if user_cookie.is_none() {
-        return Err(Redirect::to("/login"));
+        return Err(AuthorizeError::Redirect(Redirect::to("/login")));
     }
     let response_type = auth_request.response_type.to_string();
-    let auth_context = server::authorize(auth_request, clients)
+    let auth_context = server::authorize(auth_request, clients, resources)
         .await
-        .map_err(|_| Redirect::to("/account/settings"))?;
+        .map_err(|e| -> AuthorizeError { e.into() })?;
 
     Ok(Template::render(
         "authorize",
@@ -60,7 +187,9 @@ async fn authorize(
             redirect_uri: auth_context.redirect_uri,
             response_type: response_type,
             code_challenge: auth_context.code_challenge,
-            code_challenge_method: auth_context.code_challenge_method.to_string()
+            code_challenge_method: auth_context.code_challenge_method.to_string(),
+            nonce: auth_context.nonce,
+            resource: auth_context.resource,
         },
     ))
 }
@@ -71,18 +200,31 @@ async fn submit_authorize_form(
     auth_request: forms::AuthorizationRequestForm<'_>,
     clients: Clients<'_>,
     pkce_codes: pkce::PkceCodes<'_>,
-) -> Redirect {
-    let validated_auth_context =
-        server::submit_authorization(context.user_id, auth_request, clients, pkce_codes)
-            .await
-            .unwrap();
+    resources: resources::Resources<'_>,
+) -> Result<Redirect, AuthorizeError> {
+    let validated_auth_context = server::submit_authorization(
+        context.user_id,
+        context.auth_time,
+        auth_request,
+        clients,
+        pkce_codes,
+        resources,
+    )
+    .await
+    .map_err(|e| -> AuthorizeError { e.into() })?;
+    let separator = match validated_auth_context.redirect_uri.contains('?') {
+        true => '&',
+        false => '?',
+    };
+    // the client's state comes back exactly as it was sent, whatever is in it
     let redirect_uri = format!(
-        "{}?state={}&code={}",
+        "{}{}state={}&code={}",
         validated_auth_context.redirect_uri,
-        validated_auth_context.state,
-        validated_auth_context.code
+        separator,
+        &validated_auth_context.state as &dyn UriDisplay<Query>,
+        &validated_auth_context.code as &dyn UriDisplay<Query>
     );
-    Redirect::to(redirect_uri)
+    Ok(Redirect::to(redirect_uri))
 }
 
 #[post("/clients", data = "<client_request>")]
@@ -91,27 +233,88 @@ async fn register(
     clients: Clients<'_>,
 ) -> Result<Value, BadRequest<Value>> {
     let (client, secret) = clients
-        .register(
-            client_request.name.to_string(),
-            client_request.description.to_string(),
-        )
+        .register(ClientMetadata {
+            name: client_request.name.to_string(),
+            description: client_request.description.to_string(),
+            redirect_uris: client_request
+                .redirect_uris
+                .iter()
+                .map(|uri| uri.to_string())
+                .collect(),
+            userinfo_signed_response_alg: client_request
+                .userinfo_signed_response_alg
+                .as_ref()
+                .map(|alg| alg.to_string()),
+            jwks: client_request.jwks.clone(),
+            id_token_encrypted_response_alg: client_request
+                .id_token_encrypted_response_alg
+                .as_ref()
+                .map(|alg| alg.to_string()),
+            id_token_encrypted_response_enc: client_request
+                .id_token_encrypted_response_enc
+                .as_ref()
+                .map(|enc| enc.to_string()),
+            access_token_format: client_request
+                .access_token_format
+                .as_ref()
+                .map(|format| format.to_string()),
+            access_token_ttl: client_request.access_token_ttl,
+            refresh_token_ttl: client_request.refresh_token_ttl,
+            id_token_ttl: client_request.id_token_ttl,
+            token_exchangers: client_request.token_exchangers.clone(),
+            trusted_issuers: client_request.trusted_issuers.clone(),
+            token_endpoint_auth_method: client_request
+                .token_endpoint_auth_method
+                .as_ref()
+                .map(|method| method.to_string()),
+            tls_client_auth_subject_dn: client_request
+                .tls_client_auth_subject_dn
+                .as_ref()
+                .map(|dn| dn.to_string()),
+        })
         .await
         .map_err(|e| match e {
-            Error::InvalidClientName => BadRequest(Some(json!("name already taken?"))),
-            _ => BadRequest(Some(json!("unknown error"))),
+            Error::InvalidClientName => BadRequest(json!("name already taken?")),
+            Error::InvalidRedirectUri => BadRequest(json!("invalid redirect_uri")),
+            Error::InvalidClientMetadata => {
+                BadRequest(json!("unsupported or inconsistent client metadata"))
+            }
+            _ => BadRequest(json!("unknown error")),
         })?;
 
     let mut client_value = serde_json::to_value(client)
-        .map_err(|_| BadRequest(Some(json!("failed to serialize client"))))?;
+        .map_err(|_| BadRequest(json!("failed to serialize client")))?;
     client_value["secret"] = Value::String(secret);
     Ok(client_value)
 }
 
+#[get("/userinfo")]
+async fn userinfo_endpoint(
+    auth: Result<DpopJwt, Error>,
+    clients: Clients<'_>,
+    accounts: Accounts<'_>,
+) -> Result<userinfo::UserInfoResponse, BearerError> {
+    let auth = auth.map_err(BearerError)?;
+    server::userinfo(&auth, clients, accounts)
+        .await
+        .map_err(BearerError)
+}
+
+// same as the GET, section 5.3.1 lets clients use either
+#[post("/userinfo")]
+async fn userinfo_endpoint_post(
+    auth: Result<DpopJwt, Error>,
+    clients: Clients<'_>,
+    accounts: Accounts<'_>,
+) -> Result<userinfo::UserInfoResponse, BearerError> {
+    userinfo_endpoint(auth, clients, accounts).await
+}
+
 #[get("/clients/<id>")]
 async fn get_client(
     id: Uuid,
     clients: Clients<'_>,
-    auth: client_jwt::ClientJwt,
+    auth: ClientJwt,
 ) -> Result<Json<Client>, Status> {
     auth.authorize_for(&id)
         .map_err(|e| -> Status { e.into() })?;
@@ -125,46 +328,189 @@ async fn get_client(
 async fn delete_client(
     id: Uuid,
     clients: Clients<'_>,
-    auth: client_jwt::ClientJwt,
+    refresh_tokens: refresh::RefreshTokens<'_>,
+    revocations: revocation::Revocations<'_>,
+    auth: ClientJwt,
 ) -> Result<NoContent, Status> {
     auth.authorize_for(&id)
         .map_err(|e| -> Status { e.into() })?;
     clients.delete(id).await;
+    refresh_tokens.remove_client(id).await;
+    revocations.revoke_client(id).await;
     Ok(NoContent)
 }
 
+// RFC 8693: who may exchange a client's access tokens is up to that client, or the admin
+#[put("/clients/<id>/token_exchangers", data = "<exchangers>")]
+async fn set_token_exchangers(
+    id: Uuid,
+    exchangers: Json<Vec<TokenExchanger>>,
+    clients: Clients<'_>,
+    auth: ClientJwt,
+) -> Result<Json<Client>, Status> {
+    auth.authorize_for(&id)
+        .or_else(|_| auth.authorize_admin())
+        .map_err(|e| -> Status { e.into() })?;
+    match clients
+        .set_token_exchangers(&id, exchangers.into_inner())
+        .await
+    {
+        Ok(client) => Ok(Json(client)),
+        Err(Error::InvalidClient) => Err(Status::NotFound),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// next and retired keys are published too, so verifiers are never caught out by a rotation
 #[get("/keys")]
 async fn get_keys() -> Value {
+    let keys = KEYS.current();
     json!({
-        "keys": [*KEY]
+        "keys": keys.keys().into_iter().map(|(_, jwk)| jwk.as_ref()).collect::<Vec<_>>()
     })
 }
 
+#[post("/keys/rotate")]
+async fn rotate_keys(auth: ClientJwt) -> Result<NoContent, Status> {
+    auth.authorize_admin().map_err(|e| -> Status { e.into() })?;
+    key_store::rotate()
+        .await
+        .map_err(|e| -> Status { e.into() })?;
+    Ok(NoContent)
+}
+
+// for a compromised key: everything it signed stops verifying right away
+#[delete("/keys/<kid>")]
+async fn revoke_key(kid: &str, auth: ClientJwt) -> Result<NoContent, Status> {
+    auth.authorize_admin().map_err(|e| -> Status { e.into() })?;
+    match key_store::revoke(kid).await {
+        Ok(true) => Ok(NoContent),
+        Ok(false) => Err(Status::NotFound),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// OpenID Connect Discovery 1.0 section 4
+#[get("/openid-configuration")]
+async fn openid_configuration(metadata: ServerMetadata) -> Json<ServerMetadata> {
+    Json(metadata)
+}
+
+// RFC 8414 section 3, same document under the OAuth name
+#[get("/oauth-authorization-server")]
+async fn authorization_server_metadata(metadata: ServerMetadata) -> Json<ServerMetadata> {
+    Json(metadata)
+}
+
+// everything that expires on its own, dropped periodically until shutdown
+async fn sweep(
+    pkce_storage: pkce::PkceStorage,
+    refresh_token_storage: refresh::RefreshTokenStorage,
+    revocation_storage: revocation::RevocationStorage,
+    reference_token_storage: reference::ReferenceTokenStorage,
+    dpop_storage: dpop::DpopStorage,
+    mut shutdown: rocket::Shutdown,
+) {
+    let period = Duration::from_secs((*SWEEP_INTERVAL).max(1));
+    let mut interval = rocket::tokio::time::interval(period);
+    loop {
+        rocket::tokio::select! {
+            _ = interval.tick() => {
+                refresh_token_storage.remove_expired().await;
+                pkce_storage
+                    .remove_expired(&refresh_token_storage.families().await)
+                    .await;
+                revocation_storage.remove_expired().await;
+                reference_token_storage.remove_expired().await;
+                dpop_storage.remove_expired().await;
+                if let Err(e) = key_store::refresh().await {
+                    eprintln!("Failed to refresh signing keys: {:?}", e);
+                }
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+}
+
+const BASE: &str = "/oauth";
+
 pub async fn stage() -> rocket::fairing::AdHoc {
     let client_storage = client::init_state().await;
     let pkce_storage = pkce::PkceStorage::new();
-    rocket::fairing::AdHoc::on_ignite("oauth", |rocket| async {
-        rocket
+    let refresh_token_storage = refresh::RefreshTokenStorage::new();
+    let revocation_storage = revocation::RevocationStorage::new();
+    let reference_token_storage = reference::ReferenceTokenStorage::new();
+    let dpop_storage = dpop::DpopStorage::new();
+    let sweeper = {
+        let pkce_storage = pkce_storage.clone();
+        let refresh_token_storage = refresh_token_storage.clone();
+        let revocation_storage = revocation_storage.clone();
+        let reference_token_storage = reference_token_storage.clone();
+        let dpop_storage = dpop_storage.clone();
+        move |shutdown| {
+            sweep(
+                pkce_storage,
+                refresh_token_storage,
+                revocation_storage,
+                reference_token_storage,
+                dpop_storage,
+                shutdown,
+            )
+        }
+    };
+    rocket::fairing::AdHoc::try_on_ignite("oauth", |rocket| async {
+        if let Err(e) = key_store::init().await {
+            eprintln!("Failed to load the signing key: {:?}", e);
+            return Err(rocket);
+        }
+        Ok(rocket
             .mount(
-                "/oauth",
+                BASE,
                 routes![
                     token_endpoint,
+                    introspect,
+                    revoke,
                     register,
                     get_client,
                     delete_client,
+                    set_token_exchangers,
                     authorize,
                     submit_authorize_form,
-                    get_keys
+                    userinfo_endpoint,
+                    userinfo_endpoint_post,
+                    get_keys,
+                    rotate_keys,
+                    revoke_key
                 ],
             )
+            .mount(
+                "/.well-known",
+                routes![openid_configuration, authorization_server_metadata],
+            )
             .manage(client_storage)
             .manage(pkce_storage)
+            .manage(refresh_token_storage)
+            .manage(revocation_storage)
+            .manage(reference_token_storage)
+            .manage(dpop_storage)
+            .manage(resources::ResourceRegistry::configured())
+            .attach(rocket::fairing::AdHoc::on_liftoff(
+                "oauth sweeper",
+                |rocket| {
+                    let shutdown = rocket.shutdown();
+                    Box::pin(async move {
+                        rocket::tokio::spawn(sweeper(shutdown));
+                    })
+                },
+            )))
     })
 }
 
 #[cfg(test)]
 mod test {
-    use rocket::http::{ContentType, Header, Status};
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::X509;
+    use rocket::http::{ContentType, Cookie, Header, Status};
     use rocket::local::asynchronous::Client;
     use rocket::serde::json::json;
     use rocket::serde::json::Value;
@@ -174,6 +520,60 @@ mod test {
         rocket::build()
             .attach(Template::fairing())
             .attach(super::stage().await)
+            .attach(crate::account::stage().await)
+    }
+
+    const CODE_VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+
+    async fn register_client(test_client: &Client) -> (super::client::Client, String) {
+        let response = test_client
+            .post("/oauth/clients")
+            .header(ContentType::JSON)
+            .body(
+                json!({
+                    "name": "test",
+                    "description": "test",
+                    "redirect_uris": ["http://localhost/callback"]
+                })
+                .to_string(),
+            )
+            .dispatch()
+            .await;
+        let body: Value = response.into_json().await.unwrap();
+        let secret = body["secret"].as_str().unwrap().to_string();
+        (serde_json::from_value(body).unwrap(), secret)
+    }
+
+    async fn authorization_code(
+        test_client: &Client,
+        client: &super::client::Client,
+        scope: &str,
+    ) -> String {
+        authorization_code_for(test_client, client, scope, uuid::Uuid::new_v4()).await
+    }
+
+    async fn authorization_code_for(
+        test_client: &Client,
+        client: &super::client::Client,
+        scope: &str,
+        user_id: uuid::Uuid,
+    ) -> String {
+        let response = test_client
+            .post("/oauth/authorize")
+            .header(ContentType::Form)
+            .private_cookie(Cookie::new("user_id", user_id.to_string()))
+            .body(format!(
+                "client_id={}&response_type=code&redirect_uri=http://localhost/callback\
+                &scope={}&state=xyz&code_challenge={}&code_challenge_method=S256",
+                client.id,
+                scope,
+                super::pkce::CodeChallengeMethod::S256.transform(CODE_VERIFIER)
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::SeeOther);
+        let location = response.headers().get_one("Location").unwrap();
+        location.split("code=").nth(1).unwrap().to_string()
     }
 
     #[rocket::async_test]
@@ -258,4 +658,2265 @@ mod test {
 
         assert_eq!(delete_response.status(), Status::NoContent);
     }
+
+    #[rocket::async_test]
+    async fn test_refresh_token_rotation() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, secret) = register_client(&test_client).await;
+        let code = authorization_code(&test_client, &client, "openid offline_access").await;
+
+        let response = test_client
+            .post("/oauth/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=authorization_code&code={}&code_verifier={}\
+                &redirect_uri=http://localhost/callback&client_id={}&client_secret={}",
+                code, CODE_VERIFIER, client.id, secret
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let token = response.into_json::<super::token::Token>().await.unwrap();
+        let first_refresh_token = token.refresh_token.unwrap();
+
+        let refresh = |refresh_token: String| {
+            test_client
+                .post("/oauth/token")
+                .header(ContentType::Form)
+                .body(format!(
+                    "grant_type=refresh_token&refresh_token={}&client_id={}&client_secret={}",
+                    refresh_token, client.id, secret
+                ))
+                .dispatch()
+        };
+
+        let response = refresh(first_refresh_token.clone()).await;
+        assert_eq!(response.status(), Status::Ok);
+        let token = response.into_json::<super::token::Token>().await.unwrap();
+        assert_eq!(token.scope, "openid offline_access");
+        let second_refresh_token = token.refresh_token.unwrap();
+        assert_ne!(first_refresh_token, second_refresh_token);
+
+        // replaying the rotated-out token burns the whole family
+        let response = refresh(first_refresh_token).await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let response = refresh(second_refresh_token).await;
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[rocket::async_test]
+    async fn test_refresh_token_survives_rejected_scope() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, secret) = register_client(&test_client).await;
+        let code = authorization_code(&test_client, &client, "openid offline_access").await;
+        let token = exchange_code(&test_client, &client, &secret, &code).await;
+        let refresh = |scope: &str| {
+            test_client
+                .post("/oauth/token")
+                .header(ContentType::Form)
+                .body(format!(
+                    "grant_type=refresh_token&refresh_token={}&client_id={}&client_secret={}{}",
+                    token.refresh_token.as_ref().unwrap(),
+                    client.id,
+                    secret,
+                    scope
+                ))
+                .dispatch()
+        };
+
+        // more than was granted fails, and leaves the token as it was
+        let response = refresh("&scope=openid email").await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let body: Value = response.into_json().await.unwrap();
+        assert_eq!(body["error"], "invalid_scope");
+
+        let response = refresh("&scope=openid").await;
+        assert_eq!(response.status(), Status::Ok);
+        let body: Value = response.into_json().await.unwrap();
+        assert_eq!(body["scope"], "openid");
+    }
+
+    #[rocket::async_test]
+    async fn test_no_refresh_token_without_offline_access() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, secret) = register_client(&test_client).await;
+        let code = authorization_code(&test_client, &client, "openid").await;
+
+        let response = test_client
+            .post("/oauth/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=authorization_code&code={}&code_verifier={}\
+                &redirect_uri=http://localhost/callback&client_id={}&client_secret={}",
+                code, CODE_VERIFIER, client.id, secret
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let token = response.into_json::<super::token::Token>().await.unwrap();
+        assert!(token.refresh_token.is_none());
+    }
+
+    #[rocket::async_test]
+    async fn test_id_token_for_openid_scope() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, secret) = register_client(&test_client).await;
+        let user_id = uuid::Uuid::new_v4();
+
+        let response = test_client
+            .post("/oauth/authorize")
+            .header(ContentType::Form)
+            .private_cookie(Cookie::new("user_id", user_id.to_string()))
+            .private_cookie(Cookie::new("auth_time", "1700000000"))
+            .body(format!(
+                "client_id={}&response_type=code&redirect_uri=http://localhost/callback\
+                &scope=openid&state=xyz&code_challenge={}&code_challenge_method=S256\
+                &nonce=n-0S6_WzA2Mj",
+                client.id,
+                super::pkce::CodeChallengeMethod::S256.transform(CODE_VERIFIER)
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::SeeOther);
+        let location = response.headers().get_one("Location").unwrap();
+        let code = location.split("code=").nth(1).unwrap().to_string();
+
+        let response = test_client
+            .post("/oauth/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=authorization_code&code={}&code_verifier={}\
+                &redirect_uri=http://localhost/callback&client_id={}&client_secret={}",
+                code, CODE_VERIFIER, client.id, secret
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let token = response.into_json::<super::token::Token>().await.unwrap();
+        let id_token = super::jws::Compact::parse(token.id_token.as_deref().unwrap()).unwrap();
+        let claims: super::claims::IdTokenClaims = id_token.claims_unverified().unwrap();
+        assert_eq!(claims.sub, user_id.to_string());
+        assert_eq!(claims.aud, client.id.to_string());
+        assert_eq!(claims.auth_time, Some(1_700_000_000));
+        assert_eq!(claims.nonce.as_deref(), Some("n-0S6_WzA2Mj"));
+    }
+
+    #[rocket::async_test]
+    async fn test_authorize_rejects_forged_session() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, _) = register_client(&test_client).await;
+
+        // plain cookies are what anybody can set for themselves
+        let response = test_client
+            .post("/oauth/authorize")
+            .header(ContentType::Form)
+            .cookie(Cookie::new("user_id", uuid::Uuid::new_v4().to_string()))
+            .body(format!(
+                "client_id={}&response_type=code&redirect_uri=http://localhost/callback\
+                &scope=openid&state=xyz&code_challenge={}&code_challenge_method=S256",
+                client.id,
+                super::pkce::CodeChallengeMethod::S256.transform(CODE_VERIFIER)
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[rocket::async_test]
+    async fn test_no_id_token_without_openid_scope() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, secret) = register_client(&test_client).await;
+        let code = authorization_code(&test_client, &client, "profile").await;
+
+        let response = test_client
+            .post("/oauth/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=authorization_code&code={}&code_verifier={}\
+                &redirect_uri=http://localhost/callback&client_id={}&client_secret={}",
+                code, CODE_VERIFIER, client.id, secret
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let token = response.into_json::<super::token::Token>().await.unwrap();
+        assert!(token.id_token.is_none());
+    }
+
+    #[rocket::async_test]
+    async fn test_authorization_code_requires_code_verifier() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, secret) = register_client(&test_client).await;
+
+        for code_verifier in ["", "&code_verifier=wrong"] {
+            let code = authorization_code(&test_client, &client, "openid").await;
+            let response = test_client
+                .post("/oauth/token")
+                .header(ContentType::Form)
+                .body(format!(
+                    "grant_type=authorization_code&code={}{}&redirect_uri=http://localhost/callback\
+                    &client_id={}&client_secret={}",
+                    code, code_verifier, client.id, secret
+                ))
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::BadRequest);
+            assert_eq!(
+                response.into_json::<Value>().await.unwrap(),
+                json!({ "error": "invalid_grant" })
+            );
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_replayed_code_revokes_issued_tokens() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, secret) = register_client(&test_client).await;
+        let code = authorization_code(&test_client, &client, "openid offline_access").await;
+
+        let exchange = || {
+            test_client
+                .post("/oauth/token")
+                .header(ContentType::Form)
+                .body(format!(
+                    "grant_type=authorization_code&code={}&code_verifier={}\
+                    &redirect_uri=http://localhost/callback&client_id={}&client_secret={}",
+                    code, CODE_VERIFIER, client.id, secret
+                ))
+                .dispatch()
+        };
+
+        let response = exchange().await;
+        assert_eq!(response.status(), Status::Ok);
+        let token = response.into_json::<super::token::Token>().await.unwrap();
+
+        let response = exchange().await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        let response = test_client
+            .get(format!("/oauth/clients/{}", client.id))
+            .header(Header::new(
+                "Authorization",
+                format!("Bearer {}", token.access_token),
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let response = test_client
+            .post("/oauth/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=refresh_token&refresh_token={}&client_id={}&client_secret={}",
+                token.refresh_token.unwrap(),
+                client.id,
+                secret
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[rocket::async_test]
+    async fn test_authorize_rejects_unregistered_redirect_uri() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, _) = register_client(&test_client).await;
+
+        let query = format!(
+            "client_id={}&response_type=code&redirect_uri=https://evil.com/callback\
+            &scope=openid&state=xyz&code_challenge=challenge&code_challenge_method=S256",
+            client.id
+        );
+        let response = test_client
+            .get(format!("/oauth/authorize?{}", query))
+            .private_cookie(Cookie::new("user_id", uuid::Uuid::new_v4().to_string()))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+        assert!(response.headers().get_one("Location").is_none());
+
+        let response = test_client
+            .post("/oauth/authorize")
+            .header(ContentType::Form)
+            .private_cookie(Cookie::new("user_id", uuid::Uuid::new_v4().to_string()))
+            .body(query)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+        assert!(response.headers().get_one("Location").is_none());
+    }
+
+    #[rocket::async_test]
+    async fn test_token_requires_matching_redirect_uri() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, secret) = register_client(&test_client).await;
+        let code = authorization_code(&test_client, &client, "openid").await;
+
+        let response = test_client
+            .post("/oauth/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=authorization_code&code={}&code_verifier={}\
+                &redirect_uri=http://localhost/other&client_id={}&client_secret={}",
+                code, CODE_VERIFIER, client.id, secret
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(
+            response.into_json::<Value>().await.unwrap(),
+            json!({ "error": "invalid_grant" })
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_introspect() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, secret) = register_client(&test_client).await;
+
+        let response = test_client
+            .post("/oauth/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=client_credentials&scope=openid&client_id={}&client_secret={}",
+                client.id, secret
+            ))
+            .dispatch()
+            .await;
+        let token = response.into_json::<super::token::Token>().await.unwrap();
+
+        let introspect = |token: String, secret: String| {
+            test_client
+                .post("/oauth/introspect")
+                .header(ContentType::Form)
+                .body(format!(
+                    "token={}&client_id={}&client_secret={}",
+                    token, client.id, secret
+                ))
+                .dispatch()
+        };
+
+        let response = introspect(token.access_token.clone(), secret.clone()).await;
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_json::<Value>().await.unwrap();
+        assert_eq!(body["active"], true);
+        assert_eq!(body["scope"], "openid");
+        assert_eq!(body["client_id"], client.id.to_string());
+        assert_eq!(body["sub"], client.id.to_string());
+        assert_eq!(body["token_type"], "Bearer");
+        assert!(body["exp"].as_i64().unwrap() > body["iat"].as_i64().unwrap());
+
+        let response = introspect("garbage".to_string(), secret.clone()).await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.into_json::<Value>().await.unwrap(),
+            json!({ "active": false })
+        );
+
+        let response = introspect(token.access_token, "wrong".to_string()).await;
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[rocket::async_test]
+    async fn test_introspect_repeatedly() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, secret) = register_client(&test_client).await;
+
+        let response = test_client
+            .post("/oauth/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=client_credentials&scope=openid&client_id={}&client_secret={}",
+                client.id, secret
+            ))
+            .dispatch()
+            .await;
+        let token = response.into_json::<super::token::Token>().await.unwrap();
+
+        let introspect = |secret: &str| {
+            test_client
+                .post("/oauth/introspect")
+                .header(ContentType::Form)
+                .body(format!(
+                    "token={}&client_id={}&client_secret={}",
+                    token.access_token, client.id, secret
+                ))
+                .dispatch()
+        };
+
+        // a resource server checks every token it sees, that alone must never lock it out
+        for _ in 0..10 {
+            assert_eq!(introspect(&secret).await.status(), Status::Ok);
+        }
+
+        // a success in between starts the count over
+        for _ in 0..4 {
+            assert_eq!(introspect("wrong").await.status(), Status::Unauthorized);
+        }
+        assert_eq!(introspect(&secret).await.status(), Status::Ok);
+        for _ in 0..5 {
+            assert_eq!(introspect("wrong").await.status(), Status::Unauthorized);
+        }
+        assert_eq!(introspect(&secret).await.status(), Status::TooManyRequests);
+    }
+
+    #[rocket::async_test]
+    async fn test_revoke_access_token() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, secret) = register_client(&test_client).await;
+
+        let response = test_client
+            .post("/oauth/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=client_credentials&scope=openid&client_id={}&client_secret={}",
+                client.id, secret
+            ))
+            .dispatch()
+            .await;
+        let token = response.into_json::<super::token::Token>().await.unwrap();
+        let get_client = || {
+            test_client
+                .get(format!("/oauth/clients/{}", client.id))
+                .header(Header::new(
+                    "Authorization",
+                    format!("Bearer {}", token.access_token),
+                ))
+                .dispatch()
+        };
+        assert_eq!(get_client().await.status(), Status::Ok);
+
+        let response = test_client
+            .post("/oauth/revoke")
+            .header(ContentType::Form)
+            .body(format!(
+                "token={}&token_type_hint=access_token&client_id={}&client_secret={}",
+                token.access_token, client.id, secret
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(get_client().await.status(), Status::Unauthorized);
+
+        let response = test_client
+            .post("/oauth/introspect")
+            .header(ContentType::Form)
+            .body(format!(
+                "token={}&client_id={}&client_secret={}",
+                token.access_token, client.id, secret
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(
+            response.into_json::<Value>().await.unwrap(),
+            json!({ "active": false })
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_revoke_refresh_token() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, secret) = register_client(&test_client).await;
+        let code = authorization_code(&test_client, &client, "openid offline_access").await;
+
+        let response = test_client
+            .post("/oauth/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=authorization_code&code={}&code_verifier={}\
+                &redirect_uri=http://localhost/callback&client_id={}&client_secret={}",
+                code, CODE_VERIFIER, client.id, secret
+            ))
+            .dispatch()
+            .await;
+        let token = response.into_json::<super::token::Token>().await.unwrap();
+        let refresh_token = token.refresh_token.unwrap();
+
+        let response = test_client
+            .post("/oauth/revoke")
+            .header(ContentType::Form)
+            .body(format!(
+                "token={}&token_type_hint=refresh_token&client_id={}&client_secret={}",
+                refresh_token, client.id, secret
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = test_client
+            .post("/oauth/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=refresh_token&refresh_token={}&client_id={}&client_secret={}",
+                refresh_token, client.id, secret
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        // the access token issued with it is gone too
+        let response = test_client
+            .get(format!("/oauth/clients/{}", client.id))
+            .header(Header::new(
+                "Authorization",
+                format!("Bearer {}", token.access_token),
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[rocket::async_test]
+    async fn test_delete_client_revokes_its_tokens() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, secret) = register_client(&test_client).await;
+
+        let mut tokens = Vec::new();
+        for _ in 0..2 {
+            let response = test_client
+                .post("/oauth/token")
+                .header(ContentType::Form)
+                .body(format!(
+                    "grant_type=client_credentials&scope=openid&client_id={}&client_secret={}",
+                    client.id, secret
+                ))
+                .dispatch()
+                .await;
+            tokens.push(response.into_json::<super::token::Token>().await.unwrap());
+        }
+
+        let response = test_client
+            .delete(format!("/oauth/clients/{}", client.id))
+            .header(Header::new(
+                "Authorization",
+                format!("Bearer {}", tokens[0].access_token),
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NoContent);
+
+        let response = test_client
+            .get(format!("/oauth/clients/{}", client.id))
+            .header(Header::new(
+                "Authorization",
+                format!("Bearer {}", tokens[1].access_token),
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    async fn exchange_code(
+        test_client: &Client,
+        client: &super::client::Client,
+        secret: &str,
+        code: &str,
+    ) -> super::token::Token {
+        let response = test_client
+            .post("/oauth/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=authorization_code&code={}&code_verifier={}\
+                &redirect_uri=http://localhost/callback&client_id={}&client_secret={}",
+                code, CODE_VERIFIER, client.id, secret
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        response.into_json().await.unwrap()
+    }
+
+    async fn account(test_client: &Client) -> crate::account::acc::Account {
+        let accounts = test_client
+            .rocket()
+            .state::<crate::account::acc::AccountStorage>()
+            .unwrap();
+        let mut account = accounts.register("grant", "password").await.unwrap();
+        account.name = Some("Grant Azure".to_string());
+        account.email = Some("grant@example.com".to_string());
+        account.phone_number = Some("+1 555 0100".to_string());
+        accounts.update(account.clone()).await;
+        account
+    }
+
+    #[rocket::async_test]
+    async fn test_userinfo() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, secret) = register_client(&test_client).await;
+        let account = account(&test_client).await;
+        let code =
+            authorization_code_for(&test_client, &client, "openid profile email", account.id).await;
+        let token = exchange_code(&test_client, &client, &secret, &code).await;
+        let expected = json!({
+            "sub": account.id.to_string(),
+            "name": "Grant Azure",
+            "preferred_username": "grant",
+            "email": "grant@example.com",
+            "email_verified": false,
+        });
+
+        let response = test_client
+            .get("/oauth/userinfo")
+            .header(Header::new(
+                "Authorization",
+                format!("Bearer {}", token.access_token),
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_json::<Value>().await.unwrap(), expected);
+
+        let response = test_client
+            .post("/oauth/userinfo")
+            .header(Header::new(
+                "Authorization",
+                format!("Bearer {}", token.access_token),
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_json::<Value>().await.unwrap(), expected);
+
+        let response = test_client.get("/oauth/userinfo").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[rocket::async_test]
+    async fn test_userinfo_requires_openid_user_token() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, secret) = register_client(&test_client).await;
+        let account = account(&test_client).await;
+        let code = authorization_code_for(&test_client, &client, "profile", account.id).await;
+        let token = exchange_code(&test_client, &client, &secret, &code).await;
+
+        let response = test_client
+            .get("/oauth/userinfo")
+            .header(Header::new(
+                "Authorization",
+                format!("Bearer {}", token.access_token),
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+
+        // a client credentials token has no user behind it
+        let response = test_client
+            .post("/oauth/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=client_credentials&scope=openid&client_id={}&client_secret={}",
+                client.id, secret
+            ))
+            .dispatch()
+            .await;
+        let token = response.into_json::<super::token::Token>().await.unwrap();
+        let response = test_client
+            .get("/oauth/userinfo")
+            .header(Header::new(
+                "Authorization",
+                format!("Bearer {}", token.access_token),
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[rocket::async_test]
+    async fn test_signed_userinfo() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let response = test_client
+            .post("/oauth/clients")
+            .header(ContentType::JSON)
+            .body(
+                json!({
+                    "name": "test",
+                    "description": "test",
+                    "redirect_uris": ["http://localhost/callback"],
+                    "userinfo_signed_response_alg": "RS256"
+                })
+                .to_string(),
+            )
+            .dispatch()
+            .await;
+        let body: Value = response.into_json().await.unwrap();
+        let secret = body["secret"].as_str().unwrap().to_string();
+        let client: super::client::Client = serde_json::from_value(body).unwrap();
+        let account = account(&test_client).await;
+        let code = authorization_code_for(&test_client, &client, "openid phone", account.id).await;
+        let token = exchange_code(&test_client, &client, &secret, &code).await;
+
+        let response = test_client
+            .get("/oauth/userinfo")
+            .header(Header::new(
+                "Authorization",
+                format!("Bearer {}", token.access_token),
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.content_type(),
+            Some(ContentType::new("application", "jwt"))
+        );
+        let jwt = response.into_string().await.unwrap();
+        let signed = super::jws::Compact::parse(&jwt).unwrap();
+        let claims: super::userinfo::SignedUserInfo = signed.claims_unverified().unwrap();
+        assert_eq!(claims.aud, client.id.to_string());
+        assert_eq!(claims.userinfo.sub, account.id.to_string());
+        assert_eq!(claims.userinfo.phone_number.as_deref(), Some("+1 555 0100"));
+        assert!(claims.userinfo.email.is_none());
+    }
+
+    #[rocket::async_test]
+    async fn test_register_rejects_unknown_userinfo_alg() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let response = test_client
+            .post("/oauth/clients")
+            .header(ContentType::JSON)
+            .body(
+                json!({
+                    "name": "test",
+                    "description": "test",
+                    "userinfo_signed_response_alg": "none"
+                })
+                .to_string(),
+            )
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[rocket::async_test]
+    async fn test_encrypted_id_token() {
+        use super::jwk::{Jwk, PublicJwk};
+        use super::jws::Algorithm;
+
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let key = Jwk::generate(Algorithm::Es256).unwrap();
+        let mut public = PublicJwk::from_key(&key.key).unwrap();
+        public.kid = Some("enc-1".to_string());
+        let response = test_client
+            .post("/oauth/clients")
+            .header(ContentType::JSON)
+            .body(
+                json!({
+                    "name": "test",
+                    "description": "test",
+                    "redirect_uris": ["http://localhost/callback"],
+                    "jwks": { "keys": [public] },
+                    "id_token_encrypted_response_alg": "ECDH-ES",
+                    "id_token_encrypted_response_enc": "A256GCM"
+                })
+                .to_string(),
+            )
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let body: Value = response.into_json().await.unwrap();
+        assert_eq!(body["id_token_encrypted_response_alg"], "ECDH-ES");
+        let secret = body["secret"].as_str().unwrap().to_string();
+        let client: super::client::Client = serde_json::from_value(body).unwrap();
+
+        let user_id = uuid::Uuid::new_v4();
+        let code = authorization_code_for(&test_client, &client, "openid", user_id).await;
+        let token = exchange_code(&test_client, &client, &secret, &code).await;
+        let id_token = token.id_token.unwrap();
+        let (header, jwt) = super::jwe::decrypt(&id_token, &key.key).unwrap();
+        assert_eq!(header.kid.as_deref(), Some("enc-1"));
+        let jwt = String::from_utf8(jwt).unwrap();
+        let claims: super::claims::IdTokenClaims = super::jws::Compact::parse(&jwt)
+            .unwrap()
+            .verify(&crate::config::KEYS.active())
+            .unwrap();
+        assert_eq!(claims.sub, user_id.to_string());
+        // access tokens stay signed only, our own resources have to read them
+        assert!(super::client_jwt::ClientJwt::parse(&token.access_token).is_ok());
+    }
+
+    #[rocket::async_test]
+    async fn test_register_requires_encryption_key() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let response = test_client
+            .post("/oauth/clients")
+            .header(ContentType::JSON)
+            .body(
+                json!({
+                    "name": "test",
+                    "description": "test",
+                    "id_token_encrypted_response_alg": "RSA-OAEP-256"
+                })
+                .to_string(),
+            )
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[rocket::async_test]
+    async fn test_reference_token() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let response = test_client
+            .post("/oauth/clients")
+            .header(ContentType::JSON)
+            .body(
+                json!({
+                    "name": "test",
+                    "description": "test",
+                    "redirect_uris": ["http://localhost/callback"],
+                    "access_token_format": "reference"
+                })
+                .to_string(),
+            )
+            .dispatch()
+            .await;
+        let body: Value = response.into_json().await.unwrap();
+        assert_eq!(body["access_token_format"], "reference");
+        let secret = body["secret"].as_str().unwrap().to_string();
+        let client: super::client::Client = serde_json::from_value(body).unwrap();
+
+        let account = account(&test_client).await;
+        let code = authorization_code_for(&test_client, &client, "openid", account.id).await;
+        let token = exchange_code(&test_client, &client, &secret, &code).await;
+        assert!(!token.access_token.contains('.'));
+
+        // good anywhere a JWT would be
+        let response = test_client
+            .get("/oauth/userinfo")
+            .header(Header::new(
+                "Authorization",
+                format!("Bearer {}", token.access_token),
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let userinfo = response.into_json::<Value>().await.unwrap();
+        assert_eq!(userinfo["sub"], account.id.to_string());
+
+        let introspect = || {
+            test_client
+                .post("/oauth/introspect")
+                .header(ContentType::Form)
+                .body(format!(
+                    "token={}&client_id={}&client_secret={}",
+                    token.access_token, client.id, secret
+                ))
+                .dispatch()
+        };
+        let introspection = introspect().await.into_json::<Value>().await.unwrap();
+        assert_eq!(introspection["active"], true);
+        assert_eq!(introspection["sub"], account.id.to_string());
+        assert_eq!(introspection["client_id"], client.id.to_string());
+
+        let response = test_client
+            .post("/oauth/revoke")
+            .header(ContentType::Form)
+            .body(format!(
+                "token={}&client_id={}&client_secret={}",
+                token.access_token, client.id, secret
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let introspection = introspect().await.into_json::<Value>().await.unwrap();
+        assert_eq!(introspection, json!({ "active": false }));
+
+        let response = test_client
+            .get("/oauth/userinfo")
+            .header(Header::new(
+                "Authorization",
+                format!("Bearer {}", token.access_token),
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[rocket::async_test]
+    async fn test_client_token_lifetimes() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let response = test_client
+            .post("/oauth/clients")
+            .header(ContentType::JSON)
+            .body(
+                json!({
+                    "name": "test",
+                    "description": "test",
+                    "redirect_uris": ["http://localhost/callback"],
+                    "access_token_ttl": 300,
+                    "refresh_token_ttl": 7200,
+                    "id_token_ttl": 600
+                })
+                .to_string(),
+            )
+            .dispatch()
+            .await;
+        let body: Value = response.into_json().await.unwrap();
+        let secret = body["secret"].as_str().unwrap().to_string();
+        let client: super::client::Client = serde_json::from_value(body).unwrap();
+
+        let code = authorization_code(&test_client, &client, "openid offline_access").await;
+        let token = exchange_code(&test_client, &client, &secret, &code).await;
+        assert_eq!(token.expires_in, 300);
+        let access_token = super::client_jwt::ClientJwt::parse(&token.access_token).unwrap();
+        let claims = access_token.claims();
+        assert_eq!(claims.exp, claims.iat + 300);
+        let id_token = super::jws::Compact::parse(token.id_token.as_deref().unwrap()).unwrap();
+        let id_claims: super::claims::IdTokenClaims = id_token.claims_unverified().unwrap();
+        assert_eq!(id_claims.exp, id_claims.iat + 600);
+
+        let response = test_client
+            .post("/oauth/introspect")
+            .header(ContentType::Form)
+            .body(format!(
+                "token={}&token_type_hint=refresh_token&client_id={}&client_secret={}",
+                token.refresh_token.unwrap(),
+                client.id,
+                secret
+            ))
+            .dispatch()
+            .await;
+        let introspection = response.into_json::<Value>().await.unwrap();
+        assert_eq!(
+            introspection["exp"].as_i64().unwrap() - introspection["iat"].as_i64().unwrap(),
+            7200
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_register_rejects_excessive_lifetime() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let response = test_client
+            .post("/oauth/clients")
+            .header(ContentType::JSON)
+            .body(
+                json!({
+                    "name": "test",
+                    "description": "test",
+                    "access_token_ttl": *crate::config::MAX_ACCESS_TOKEN_TTL + 1
+                })
+                .to_string(),
+            )
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[rocket::async_test]
+    async fn test_discovery() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+
+        let response = test_client
+            .get("/.well-known/openid-configuration")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let openid_configuration = response.into_json::<Value>().await.unwrap();
+        let base_url = crate::config::ISSUER.trim_end_matches('/').to_string();
+        assert_eq!(openid_configuration["issuer"], *crate::config::ISSUER);
+        assert_eq!(
+            openid_configuration["authorization_endpoint"],
+            base_url.clone() + "/oauth/authorize"
+        );
+        assert_eq!(
+            openid_configuration["token_endpoint"],
+            base_url.clone() + "/oauth/token"
+        );
+        assert_eq!(
+            openid_configuration["registration_endpoint"],
+            base_url.clone() + "/oauth/clients"
+        );
+        assert_eq!(
+            openid_configuration["jwks_uri"],
+            base_url.clone() + "/oauth/keys"
+        );
+        assert_eq!(
+            openid_configuration["code_challenge_methods_supported"],
+            json!(["S256"])
+        );
+
+        let response = test_client
+            .get("/.well-known/oauth-authorization-server")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.into_json::<Value>().await.unwrap(),
+            openid_configuration
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_get_keys() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+
+        let response = test_client.get("/oauth/keys").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_json::<Value>().await.unwrap();
+        let key = &body["keys"][0];
+        assert_eq!(key["kty"], "RSA");
+        assert_eq!(key["use"], "sig");
+        assert_eq!(key["alg"], "RS256");
+        let active = crate::config::KEYS.active();
+        assert_eq!(key["kid"], active.thumbprint());
+        assert_eq!(key["n"].as_str(), active.n.as_deref());
+        // the next key is out there before anything gets signed with it
+        let next = crate::config::KEYS.current().next;
+        assert!(body["keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|key| key["kid"] == next.kid));
+    }
+
+    #[rocket::async_test]
+    async fn test_key_admin_requires_admin_client() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, secret) = register_client(&test_client).await;
+        let response = test_client
+            .post("/oauth/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=client_credentials&scope=openid&client_id={}&client_secret={}",
+                client.id, secret
+            ))
+            .dispatch()
+            .await;
+        let token = response.into_json::<super::token::Token>().await.unwrap();
+        let authorization = Header::new("Authorization", format!("Bearer {}", token.access_token));
+
+        let response = test_client
+            .post("/oauth/keys/rotate")
+            .header(authorization.clone())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+
+        let kid = crate::config::KEYS.active().kid.clone();
+        let response = test_client
+            .delete(format!("/oauth/keys/{}", kid))
+            .header(authorization)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+        assert_eq!(crate::config::KEYS.active().kid, kid);
+    }
+
+    #[rocket::async_test]
+    async fn test_resource_indicator() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, secret) = register_client(&test_client).await;
+        let decks = format!("{}/decks", crate::config::ISSUER.trim_end_matches('/'));
+
+        let response = test_client
+            .post("/oauth/authorize")
+            .header(ContentType::Form)
+            .private_cookie(Cookie::new("user_id", uuid::Uuid::new_v4().to_string()))
+            .body(format!(
+                "client_id={}&response_type=code&redirect_uri=http://localhost/callback\
+                &scope=openid&state=xyz&code_challenge={}&code_challenge_method=S256\
+                &resource={}",
+                client.id,
+                super::pkce::CodeChallengeMethod::S256.transform(CODE_VERIFIER),
+                decks
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::SeeOther);
+        let location = response.headers().get_one("Location").unwrap();
+        let code = location.split("code=").nth(1).unwrap();
+        let token = exchange_code(&test_client, &client, &secret, code).await;
+        let jwt = super::client_jwt::ClientJwt::parse(&token.access_token).unwrap();
+        assert_eq!(jwt.claims().aud, decks);
+
+        // fine for the decks, not for userinfo
+        let response = test_client
+            .get("/oauth/userinfo")
+            .header(Header::new(
+                "Authorization",
+                format!("Bearer {}", token.access_token),
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[rocket::async_test]
+    async fn test_unknown_resource() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, secret) = register_client(&test_client).await;
+
+        let response = test_client
+            .post("/oauth/token")
+            .header(ContentType::Form)
+            .body(format!(
+                "grant_type=client_credentials&client_id={}&client_secret={}\
+                &resource=https://elsewhere.example",
+                client.id, secret
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let body: Value = response.into_json().await.unwrap();
+        assert_eq!(body["error"], "invalid_target");
+
+        let response = test_client
+            .get(format!(
+                "/oauth/authorize?client_id={}&response_type=code\
+                &redirect_uri=http://localhost/callback&scope=openid&state=xyz\
+                &code_challenge=challenge&code_challenge_method=S256\
+                &resource=https://elsewhere.example",
+                client.id
+            ))
+            .private_cookie(Cookie::new("user_id", uuid::Uuid::new_v4().to_string()))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    async fn token_request(test_client: &Client, body: String) -> (Status, Value) {
+        let response = test_client
+            .post("/oauth/token")
+            .header(ContentType::Form)
+            .body(body)
+            .dispatch()
+            .await;
+        (response.status(), response.into_json().await.unwrap())
+    }
+
+    // `owner` says who may exchange its access tokens, with a token of its own
+    async fn set_token_exchangers(
+        test_client: &Client,
+        id: uuid::Uuid,
+        owner: uuid::Uuid,
+        owner_secret: &str,
+        exchangers: Value,
+    ) -> Status {
+        let (_, body) = token_request(
+            test_client,
+            format!(
+                "grant_type=client_credentials&scope=openid&client_id={}&client_secret={}",
+                owner, owner_secret
+            ),
+        )
+        .await;
+        test_client
+            .put(format!("/oauth/clients/{}/token_exchangers", id))
+            .header(ContentType::JSON)
+            .header(Header::new(
+                "Authorization",
+                format!("Bearer {}", body["access_token"].as_str().unwrap()),
+            ))
+            .body(exchangers.to_string())
+            .dispatch()
+            .await
+            .status()
+    }
+
+    #[rocket::async_test]
+    async fn test_token_exchange() {
+        use super::token::ACCESS_TOKEN_TYPE_URI;
+
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (frontend, frontend_secret) = register_client(&test_client).await;
+        let (backend, backend_secret) = register_client(&test_client).await;
+        let exchange = |client_id: &str, secret: &str, subject_token: &str, extra: &str| {
+            format!(
+                "grant_type=urn:ietf:params:oauth:grant-type:token-exchange\
+                &client_id={}&client_secret={}&subject_token={}&subject_token_type={}{}",
+                client_id, secret, subject_token, ACCESS_TOKEN_TYPE_URI, extra
+            )
+        };
+
+        let (_, body) = token_request(
+            &test_client,
+            format!(
+                "grant_type=client_credentials&client_id={}&client_secret={}\
+                &scope=openid profile",
+                frontend.id, frontend_secret
+            ),
+        )
+        .await;
+        let subject_token = body["access_token"].as_str().unwrap().to_string();
+
+        // the backend can't let itself at the frontend's tokens
+        let status = set_token_exchangers(
+            &test_client,
+            frontend.id,
+            backend.id,
+            &backend_secret,
+            json!([{ "client_id": backend.id }]),
+        )
+        .await;
+        assert_eq!(status, Status::Forbidden);
+        let (status, body) = token_request(
+            &test_client,
+            exchange(&backend.id.to_string(), &backend_secret, &subject_token, ""),
+        )
+        .await;
+        assert_eq!(status, Status::BadRequest);
+        assert_eq!(body["error"], "invalid_request");
+
+        // only for tokens meant for somewhere else
+        let status = set_token_exchangers(
+            &test_client,
+            frontend.id,
+            frontend.id,
+            &frontend_secret,
+            json!([{ "client_id": backend.id, "audiences": ["https://api.example.com"] }]),
+        )
+        .await;
+        assert_eq!(status, Status::Ok);
+        let (status, body) = token_request(
+            &test_client,
+            exchange(&backend.id.to_string(), &backend_secret, &subject_token, ""),
+        )
+        .await;
+        assert_eq!(status, Status::BadRequest);
+        assert_eq!(body["error"], "invalid_request");
+
+        let status = set_token_exchangers(
+            &test_client,
+            frontend.id,
+            frontend.id,
+            &frontend_secret,
+            json!([{
+                "client_id": backend.id,
+                "scopes": ["profile", "email"],
+                "audiences": [*crate::config::AUDIENCE]
+            }]),
+        )
+        .await;
+        assert_eq!(status, Status::Ok);
+
+        // what both the subject token and the frontend allow
+        let (status, body) = token_request(
+            &test_client,
+            exchange(&backend.id.to_string(), &backend_secret, &subject_token, ""),
+        )
+        .await;
+        assert_eq!(status, Status::Ok);
+        assert_eq!(body["scope"], "profile");
+
+        // down-scoped, on behalf of the frontend's subject, with the backend as actor
+        let (status, body) = token_request(
+            &test_client,
+            exchange(
+                &backend.id.to_string(),
+                &backend_secret,
+                &subject_token,
+                "&scope=profile",
+            ),
+        )
+        .await;
+        assert_eq!(status, Status::Ok);
+        assert_eq!(body["issued_token_type"], ACCESS_TOKEN_TYPE_URI);
+        assert_eq!(body["scope"], "profile");
+        let exchanged = body["access_token"].as_str().unwrap().to_string();
+        let jwt = super::client_jwt::ClientJwt::parse(&exchanged).unwrap();
+        let claims = jwt.claims();
+        assert_eq!(claims.sub, frontend.id.to_string());
+        assert_eq!(claims.client_id, backend.id);
+        assert_eq!(claims.act.as_ref().unwrap().sub, backend.id.to_string());
+
+        for scope in ["&scope=email", "&scope=openid"] {
+            let (status, body) = token_request(
+                &test_client,
+                exchange(
+                    &backend.id.to_string(),
+                    &backend_secret,
+                    &subject_token,
+                    scope,
+                ),
+            )
+            .await;
+            assert_eq!(status, Status::BadRequest);
+            assert_eq!(body["error"], "invalid_scope");
+        }
+
+        // a further hop keeps the chain, with the actor token naming who acts now
+        let (further, further_secret) = register_client(&test_client).await;
+        let status = set_token_exchangers(
+            &test_client,
+            backend.id,
+            backend.id,
+            &backend_secret,
+            json!([{ "client_id": further.id }]),
+        )
+        .await;
+        assert_eq!(status, Status::Ok);
+        let actor = format!(
+            "&actor_token={}&actor_token_type={}",
+            subject_token, ACCESS_TOKEN_TYPE_URI
+        );
+        let (status, body) = token_request(
+            &test_client,
+            exchange(&further.id.to_string(), &further_secret, &exchanged, &actor),
+        )
+        .await;
+        assert_eq!(status, Status::Ok);
+        let jwt =
+            super::client_jwt::ClientJwt::parse(body["access_token"].as_str().unwrap()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&jwt.claims().act).unwrap(),
+            json!({ "sub": frontend.id, "act": { "sub": backend.id } })
+        );
+
+        // nobody allowed the frontend to exchange anything
+        let (status, body) = token_request(
+            &test_client,
+            exchange(
+                &frontend.id.to_string(),
+                &frontend_secret,
+                &subject_token,
+                "",
+            ),
+        )
+        .await;
+        assert_eq!(status, Status::BadRequest);
+        assert_eq!(body["error"], "invalid_request");
+
+        let (status, body) = token_request(
+            &test_client,
+            exchange(&backend.id.to_string(), &backend_secret, "not-a-token", ""),
+        )
+        .await;
+        assert_eq!(status, Status::BadRequest);
+        assert_eq!(body["error"], "invalid_request");
+
+        // and taken back again
+        let status = set_token_exchangers(
+            &test_client,
+            frontend.id,
+            frontend.id,
+            &frontend_secret,
+            json!([]),
+        )
+        .await;
+        assert_eq!(status, Status::Ok);
+        let (status, _) = token_request(
+            &test_client,
+            exchange(&backend.id.to_string(), &backend_secret, &subject_token, ""),
+        )
+        .await;
+        assert_eq!(status, Status::BadRequest);
+    }
+
+    #[rocket::async_test]
+    async fn test_jwt_bearer() {
+        use super::claims::TokenHeader;
+        use super::jwk::{Jwk, PublicJwk};
+        use super::jws::{encode, Algorithm};
+
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let key = Jwk::generate(Algorithm::EdDsa).unwrap();
+        let trusted_issuer = json!({
+            "issuer": "https://ci.example",
+            "jwk": PublicJwk::from_key(&key.key).unwrap(),
+            "subjects": ["repo:grant/decks:*"],
+            "scopes": ["profile", "email"],
+        });
+        let response = test_client
+            .post("/oauth/clients")
+            .header(ContentType::JSON)
+            .body(
+                json!({
+                    "name": "test",
+                    "description": "test",
+                    "trusted_issuers": [trusted_issuer]
+                })
+                .to_string(),
+            )
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let client: super::client::Client = response.into_json().await.unwrap();
+        let assertion = |sub: &str, jti: Option<uuid::Uuid>| {
+            let mut claims = json!({
+                "iss": "https://ci.example",
+                "sub": sub,
+                "aud": *crate::config::ISSUER,
+                "exp": chrono::offset::Utc::now().timestamp() + 300,
+            });
+            if let Some(jti) = jti {
+                claims["jti"] = json!(jti);
+            }
+            let header = TokenHeader {
+                alg: key.alg,
+                kid: None,
+                typ: Some("JWT".to_string()),
+            };
+            encode(&header, &claims, &key.key).unwrap()
+        };
+        let grant = |assertion: &str, scope: &str| {
+            format!(
+                "grant_type=urn:ietf:params:oauth:grant-type:jwt-bearer&client_id={}\
+                &assertion={}&scope={}",
+                client.id, assertion, scope
+            )
+        };
+        let subject = |body: &Value| {
+            super::client_jwt::ClientJwt::parse(body["access_token"].as_str().unwrap())
+                .unwrap()
+                .claims()
+                .sub
+                .clone()
+        };
+
+        // no client secret, the assertion vouches for the client
+        let main = assertion("repo:grant/decks:ref:main", Some(uuid::Uuid::new_v4()));
+        // a scope the issuer doesn't get leaves the assertion usable
+        let (status, body) = token_request(&test_client, grant(&main, "openid")).await;
+        assert_eq!(status, Status::BadRequest);
+        assert_eq!(body["error"], "invalid_scope");
+        let (status, body) = token_request(&test_client, grant(&main, "profile")).await;
+        assert_eq!(status, Status::Ok);
+        assert_eq!(body["scope"], "profile");
+        // for whoever the assertion is about, never the client or one of our accounts
+        let main_subject = subject(&body);
+        assert_ne!(main_subject, client.id.to_string());
+
+        // each assertion only once
+        let (status, body) = token_request(&test_client, grant(&main, "profile")).await;
+        assert_eq!(status, Status::BadRequest);
+        assert_eq!(body["error"], "invalid_grant");
+
+        // the same subject comes out the same every time, and another one doesn't
+        let again = assertion("repo:grant/decks:ref:main", Some(uuid::Uuid::new_v4()));
+        let (_, body) = token_request(&test_client, grant(&again, "profile")).await;
+        assert_eq!(subject(&body), main_subject);
+        let dev = assertion("repo:grant/decks:ref:dev", Some(uuid::Uuid::new_v4()));
+        let (_, body) = token_request(&test_client, grant(&dev, "profile")).await;
+        assert_ne!(subject(&body), main_subject);
+
+        // nothing would stop one without a `jti` from being replayed
+        let no_jti = assertion("repo:grant/decks:ref:main", None);
+        let (status, body) = token_request(&test_client, grant(&no_jti, "profile")).await;
+        assert_eq!(status, Status::BadRequest);
+        assert_eq!(body["error"], "invalid_grant");
+
+        // and no other grant without the secret
+        let (status, _) = token_request(
+            &test_client,
+            format!("grant_type=client_credentials&client_id={}", client.id),
+        )
+        .await;
+        assert_eq!(status, Status::Unauthorized);
+    }
+
+    #[rocket::async_test]
+    async fn test_register_rejects_untrusted_issuer() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let response = test_client
+            .post("/oauth/clients")
+            .header(ContentType::JSON)
+            .body(
+                json!({
+                    "name": "test",
+                    "description": "test",
+                    "trusted_issuers": [{
+                        "issuer": "https://ci.example",
+                        "jwks": { "keys": [] },
+                        "subjects": ["*"],
+                    }]
+                })
+                .to_string(),
+            )
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[rocket::async_test]
+    async fn test_private_key_jwt() {
+        use super::claims::TokenHeader;
+        use super::jwk::{Jwk, JwkSet, PublicJwk};
+        use super::jws::{encode, Algorithm};
+
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let key = Jwk::generate(Algorithm::Es256).unwrap();
+        let mut public = PublicJwk::from_key(&key.key).unwrap();
+        public.kid = Some(key.kid.clone());
+        let response = test_client
+            .post("/oauth/clients")
+            .header(ContentType::JSON)
+            .body(
+                json!({
+                    "name": "test",
+                    "description": "test",
+                    "token_endpoint_auth_method": "private_key_jwt",
+                    "jwks": JwkSet { keys: vec![public] },
+                })
+                .to_string(),
+            )
+            .dispatch()
+            .await;
+        let body: Value = response.into_json().await.unwrap();
+        assert_eq!(body["token_endpoint_auth_method"], "private_key_jwt");
+        let secret = body["secret"].as_str().unwrap().to_string();
+        let client: super::client::Client = serde_json::from_value(body).unwrap();
+        let client_assertion = encode(
+            &TokenHeader {
+                alg: key.alg,
+                kid: Some(key.kid.clone()),
+                typ: None,
+            },
+            &json!({
+                "iss": client.id,
+                "sub": client.id,
+                "aud": *crate::config::ISSUER,
+                "exp": chrono::offset::Utc::now().timestamp() + 60,
+                "jti": uuid::Uuid::new_v4(),
+            }),
+            &key.key,
+        )
+        .unwrap();
+        let grant = format!(
+            "grant_type=client_credentials&client_id={}&client_assertion_type={}\
+            &client_assertion={}",
+            client.id,
+            super::assertion::CLIENT_ASSERTION_TYPE,
+            client_assertion
+        );
+
+        let (status, body) = token_request(&test_client, grant.clone()).await;
+        assert_eq!(status, Status::Ok);
+        assert!(body["access_token"].is_string());
+
+        // no replaying the assertion, and no falling back to the secret
+        let (status, body) = token_request(&test_client, grant).await;
+        assert_eq!(status, Status::Unauthorized);
+        assert_eq!(body["error"], "invalid_client");
+        let (status, _) = token_request(
+            &test_client,
+            format!(
+                "grant_type=client_credentials&client_id={}&client_secret={}",
+                client.id, secret
+            ),
+        )
+        .await;
+        assert_eq!(status, Status::Unauthorized);
+    }
+
+    #[rocket::async_test]
+    async fn test_client_secret_jwt() {
+        use super::claims::TokenHeader;
+        use super::jws::{encode_hmac, Algorithm};
+
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let response = test_client
+            .post("/oauth/clients")
+            .header(ContentType::JSON)
+            .body(
+                json!({
+                    "name": "test",
+                    "description": "test",
+                    "token_endpoint_auth_method": "client_secret_jwt",
+                })
+                .to_string(),
+            )
+            .dispatch()
+            .await;
+        let body: Value = response.into_json().await.unwrap();
+        let secret = body["secret"].as_str().unwrap().to_string();
+        let client: super::client::Client = serde_json::from_value(body).unwrap();
+        let client_assertion = |secret: &str| {
+            encode_hmac(
+                &TokenHeader {
+                    alg: Algorithm::Hs256,
+                    kid: None,
+                    typ: None,
+                },
+                &json!({
+                    "iss": client.id,
+                    "sub": client.id,
+                    "aud": format!("{}/oauth/token", crate::config::ISSUER.trim_end_matches('/')),
+                    "exp": chrono::offset::Utc::now().timestamp() + 60,
+                    "jti": uuid::Uuid::new_v4(),
+                }),
+                secret.as_bytes(),
+            )
+            .unwrap()
+        };
+        let introspect = |client_assertion: String| {
+            test_client
+                .post("/oauth/introspect")
+                .header(ContentType::Form)
+                .body(format!(
+                    "token=unknown&client_id={}&client_assertion_type={}&client_assertion={}",
+                    client.id,
+                    super::assertion::CLIENT_ASSERTION_TYPE,
+                    client_assertion
+                ))
+                .dispatch()
+        };
+
+        let response = introspect(client_assertion(&secret)).await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = introspect(client_assertion("not the secret")).await;
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        // one way of authenticating at a time
+        let (status, body) = token_request(
+            &test_client,
+            format!(
+                "grant_type=client_credentials&client_id={}&client_secret={}\
+                &client_assertion_type={}&client_assertion={}",
+                client.id,
+                secret,
+                super::assertion::CLIENT_ASSERTION_TYPE,
+                client_assertion(&secret)
+            ),
+        )
+        .await;
+        assert_eq!(status, Status::BadRequest);
+        assert_eq!(body["error"], "invalid_request");
+    }
+
+    #[rocket::async_test]
+    async fn test_client_secret_basic() {
+        use base64::engine::general_purpose::STANDARD;
+        use base64::Engine;
+
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, secret) = register_client(&test_client).await;
+        let basic = |client_id: &str, secret: &str| {
+            Header::new(
+                "Authorization",
+                format!(
+                    "Basic {}",
+                    STANDARD.encode(format!("{}:{}", client_id, secret))
+                ),
+            )
+        };
+
+        let response = test_client
+            .post("/oauth/token")
+            .header(ContentType::Form)
+            .header(basic(&client.id.to_string(), &secret))
+            .body("grant_type=client_credentials&scope=openid")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let body: Value = response.into_json().await.unwrap();
+        let access_token = body["access_token"].as_str().unwrap().to_string();
+
+        // a client_id in the body has to agree with the header
+        let response = test_client
+            .post("/oauth/introspect")
+            .header(ContentType::Form)
+            .header(basic(&client.id.to_string(), &secret))
+            .body(format!("token={}&client_id={}", access_token, client.id))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let body: Value = response.into_json().await.unwrap();
+        assert_eq!(body["active"], true);
+        let response = test_client
+            .post("/oauth/introspect")
+            .header(ContentType::Form)
+            .header(basic(&client.id.to_string(), &secret))
+            .body(format!(
+                "token={}&client_id={}",
+                access_token,
+                uuid::Uuid::new_v4()
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        // never both the header and the body
+        let response = test_client
+            .post("/oauth/token")
+            .header(ContentType::Form)
+            .header(basic(&client.id.to_string(), &secret))
+            .body(format!(
+                "grant_type=client_credentials&client_id={}&client_secret={}",
+                client.id, secret
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let body: Value = response.into_json().await.unwrap();
+        assert_eq!(body["error"], "invalid_request");
+
+        for header in [
+            basic(&client.id.to_string(), "not the secret"),
+            basic("not-a-uuid", &secret),
+            Header::new("Authorization", "Basic not-base64!"),
+        ] {
+            let response = test_client
+                .post("/oauth/revoke")
+                .header(ContentType::Form)
+                .header(header)
+                .body(format!("token={}", access_token))
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Unauthorized);
+        }
+        let response = test_client
+            .post("/oauth/revoke")
+            .header(ContentType::Form)
+            .header(basic(&client.id.to_string(), &secret))
+            .body(format!("token={}", access_token))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    // a TLS identity, self-signed and with the extensions webpki insists on
+    fn identity(common_name: &str) -> (X509, PKey<Private>) {
+        use openssl::asn1::Asn1Time;
+        use openssl::bn::{BigNum, MsbOption};
+        use openssl::ec::{EcGroup, EcKey};
+        use openssl::hash::MessageDigest;
+        use openssl::nid::Nid;
+        use openssl::x509::extension::{BasicConstraints, SubjectKeyIdentifier};
+        use openssl::x509::{X509Builder, X509NameBuilder};
+
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, common_name)
+            .unwrap();
+        let name = name.build();
+        let mut serial = BigNum::new().unwrap();
+        serial.rand(128, MsbOption::MAYBE_ZERO, false).unwrap();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&serial.to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder
+            .append_extension(BasicConstraints::new().critical().build().unwrap())
+            .unwrap();
+        let subject_key_identifier = SubjectKeyIdentifier::new()
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(subject_key_identifier).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        (builder.build(), key)
+    }
+
+    // Rocket's local client can't present a certificate, so these go over actual TLS
+    // connections. Every certificate in `trusted` gets through the handshake.
+    async fn launch_mtls(trusted: &[&(X509, PKey<Private>)]) -> (u16, rocket::Shutdown) {
+        use rocket::config::{LogLevel, MutualTls, Shutdown, TlsConfig};
+
+        let (certificate, key) = identity("localhost");
+        let ca_certs = trusted
+            .iter()
+            .flat_map(|(certificate, _)| certificate.to_pem().unwrap())
+            .collect::<Vec<u8>>();
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let tls = TlsConfig::from_bytes(
+            &certificate.to_pem().unwrap(),
+            &key.private_key_to_pem_pkcs8().unwrap(),
+        )
+        .with_mutual(MutualTls::from_bytes(&ca_certs));
+        let config = rocket::Config {
+            port,
+            tls: Some(tls),
+            shutdown: Shutdown {
+                ctrlc: false,
+                ..Default::default()
+            },
+            log_level: LogLevel::Off,
+            ..rocket::Config::debug_default()
+        };
+        let rocket = test_rocket()
+            .await
+            .configure(config)
+            .ignite()
+            .await
+            .unwrap();
+        let shutdown = rocket.shutdown();
+        rocket::tokio::spawn(rocket.launch());
+        (port, shutdown)
+    }
+
+    // just enough HTTP/1.1 to get a status and a JSON body back
+    async fn https(
+        port: u16,
+        identity: Option<&(X509, PKey<Private>)>,
+        request: String,
+    ) -> (u16, Value) {
+        use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+        use std::io::{Read, Write};
+
+        let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        if let Some((certificate, key)) = identity {
+            connector.set_certificate(certificate).unwrap();
+            connector.set_private_key(key).unwrap();
+        }
+        let connector = connector.build();
+        rocket::tokio::task::spawn_blocking(move || {
+            // the server may still be starting up
+            let stream = (0..50)
+                .find_map(
+                    |_| match std::net::TcpStream::connect(("127.0.0.1", port)) {
+                        Ok(stream) => Some(stream),
+                        Err(_) => {
+                            std::thread::sleep(std::time::Duration::from_millis(100));
+                            None
+                        }
+                    },
+                )
+                .unwrap();
+            let mut stream = connector.connect("localhost", stream).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = Vec::new();
+            // whatever arrived before the connection closed, cleanly or not
+            let _ = stream.read_to_end(&mut response);
+            let response = String::from_utf8(response).unwrap();
+            let (head, body) = response.split_once("\r\n\r\n").unwrap();
+            let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+            (status, serde_json::from_str(body).unwrap_or(Value::Null))
+        })
+        .await
+        .unwrap()
+    }
+
+    fn http_request(method: &str, path: &str, headers: &[(&str, &str)], body: &str) -> String {
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n", method, path);
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        ));
+        request
+    }
+
+    #[rocket::async_test]
+    async fn test_mtls_client_auth() {
+        use super::client_jwt::ClientJwt;
+        use super::jwk::{JwkSet, PublicJwk};
+        use super::mtls::ClientCertificate;
+
+        let (client_identity, other_identity) = (identity("client"), identity("other"));
+        let (port, shutdown) = launch_mtls(&[&client_identity, &other_identity]).await;
+        let register = |metadata: Value| {
+            http_request(
+                "POST",
+                "/oauth/clients",
+                &[("Content-Type", "application/json")],
+                &metadata.to_string(),
+            )
+        };
+        let token = |client_id: &str| {
+            http_request(
+                "POST",
+                "/oauth/token",
+                &[("Content-Type", "application/x-www-form-urlencoded")],
+                &format!("grant_type=client_credentials&client_id={}", client_id),
+            )
+        };
+        let thumbprint = |(certificate, _): &(X509, PKey<Private>)| {
+            ClientCertificate::from_der(certificate.to_der().unwrap())
+                .unwrap()
+                .thumbprint()
+        };
+
+        // the key in the certificate is the one registered
+        let jwks = JwkSet {
+            keys: vec![PublicJwk::from_key(&client_identity.1).unwrap()],
+        };
+        let (status, client) = https(
+            port,
+            None,
+            register(json!({
+                "name": "test",
+                "description": "test",
+                "token_endpoint_auth_method": "self_signed_tls_client_auth",
+                "jwks": jwks
+            })),
+        )
+        .await;
+        assert_eq!(status, 200);
+        let client_id = client["id"].as_str().unwrap();
+        let (status, body) = https(port, Some(&client_identity), token(client_id)).await;
+        assert_eq!(status, 200);
+        let access_token = body["access_token"].as_str().unwrap();
+        let jwt = ClientJwt::parse(access_token).unwrap();
+        assert_eq!(
+            jwt.claims()
+                .cnf
+                .as_ref()
+                .and_then(|cnf| cnf.x5t_s256.clone()),
+            Some(thumbprint(&client_identity))
+        );
+        for identity in [None, Some(&other_identity)] {
+            let (status, _) = https(port, identity, token(client_id)).await;
+            assert_eq!(status, 401);
+        }
+
+        // the bound token is no good over any other connection
+        let get_client = |access_token: &str| {
+            let authorization = format!("Bearer {}", access_token);
+            http_request(
+                "GET",
+                &format!("/oauth/clients/{}", client_id),
+                &[("Authorization", &authorization)],
+                "",
+            )
+        };
+        let (status, body) = https(port, Some(&client_identity), get_client(access_token)).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["id"], client_id);
+        for identity in [None, Some(&other_identity)] {
+            let (status, _) = https(port, identity, get_client(access_token)).await;
+            assert_eq!(status, 401);
+        }
+
+        // the subject has to be the registered one
+        let (_, client) = https(
+            port,
+            None,
+            register(json!({
+                "name": "test",
+                "description": "test",
+                "token_endpoint_auth_method": "tls_client_auth",
+                "tls_client_auth_subject_dn": "CN=other"
+            })),
+        )
+        .await;
+        let client_id = client["id"].as_str().unwrap();
+        let (status, body) = https(port, Some(&other_identity), token(client_id)).await;
+        assert_eq!(status, 200);
+        let jwt = ClientJwt::parse(body["access_token"].as_str().unwrap()).unwrap();
+        assert_eq!(
+            jwt.claims()
+                .cnf
+                .as_ref()
+                .and_then(|cnf| cnf.x5t_s256.clone()),
+            Some(thumbprint(&other_identity))
+        );
+        let (status, _) = https(port, Some(&client_identity), token(client_id)).await;
+        assert_eq!(status, 401);
+
+        shutdown.notify();
+    }
+
+    // a DPoP proof for the token endpoint
+    fn token_proof(jwk: &super::jwk::Jwk, nonce: Option<&str>) -> String {
+        let mut claims = json!({
+            "jti": uuid::Uuid::new_v4().to_string(),
+            "htm": "POST",
+            "htu": format!("{}/oauth/token", crate::config::ISSUER.trim_end_matches('/')),
+            "iat": chrono::offset::Utc::now().timestamp(),
+        });
+        if let Some(nonce) = nonce {
+            claims["nonce"] = json!(nonce);
+        }
+        super::dpop::encode_proof(jwk, &claims).unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn test_dpop_token() {
+        use super::jwk::Jwk;
+        use super::jws::Algorithm;
+
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, secret) = register_client(&test_client).await;
+        let jwk = Jwk::generate(Algorithm::Es256).unwrap();
+        let proof = |nonce: Option<&str>| token_proof(&jwk, nonce);
+        let body = format!(
+            "grant_type=client_credentials&client_id={}&client_secret={}",
+            client.id, secret
+        );
+        let token_request = |proof: Option<String>| {
+            let mut request = test_client
+                .post("/oauth/token")
+                .header(ContentType::Form)
+                .body(body.clone());
+            if let Some(proof) = proof {
+                request = request.header(Header::new("DPoP", proof));
+            }
+            request.dispatch()
+        };
+
+        // the first proof can't know the nonce yet
+        let response = token_request(Some(proof(None))).await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let nonce = response
+            .headers()
+            .get_one("DPoP-Nonce")
+            .unwrap()
+            .to_string();
+        let body: Value = response.into_json().await.unwrap();
+        assert_eq!(body["error"], "use_dpop_nonce");
+
+        let proof = proof(Some(&nonce));
+        let response = token_request(Some(proof.clone())).await;
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.headers().get_one("DPoP-Nonce").is_some());
+        let body: Value = response.into_json().await.unwrap();
+        assert_eq!(body["token_type"], "DPoP");
+        let access_token = body["access_token"].as_str().unwrap().to_string();
+        let jwt = super::client_jwt::ClientJwt::parse(&access_token).unwrap();
+        let cnf = jwt.claims().cnf.clone().unwrap();
+        assert_eq!(cnf.jkt, Some(jwk.thumbprint()));
+        assert_eq!(cnf.x5t_s256, None);
+
+        // each proof is good once
+        let response = token_request(Some(proof)).await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let body: Value = response.into_json().await.unwrap();
+        assert_eq!(body["error"], "invalid_dpop_proof");
+
+        // without DPoP it's a bearer token like always
+        let response = token_request(None).await;
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.headers().get_one("DPoP-Nonce").is_none());
+        let body: Value = response.into_json().await.unwrap();
+        assert_eq!(body["token_type"], "Bearer");
+
+        let response = test_client
+            .post("/oauth/introspect")
+            .header(ContentType::Form)
+            .body(format!(
+                "token={}&client_id={}&client_secret={}",
+                access_token, client.id, secret
+            ))
+            .dispatch()
+            .await;
+        let body: Value = response.into_json().await.unwrap();
+        assert_eq!(body["active"], true);
+        assert_eq!(body["token_type"], "DPoP");
+        assert_eq!(body["cnf"]["jkt"], jwk.thumbprint());
+    }
+
+    #[rocket::async_test]
+    async fn test_authorize_redirect_encodes_state() {
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, _) = register_client(&test_client).await;
+        let response = test_client
+            .post("/oauth/authorize")
+            .header(ContentType::Form)
+            .private_cookie(Cookie::new("user_id", uuid::Uuid::new_v4().to_string()))
+            .body(format!(
+                "client_id={}&response_type=code&redirect_uri=http://localhost/callback\
+                &scope=openid&state=a%26code%3Dforged%23b+c&code_challenge={}\
+                &code_challenge_method=S256",
+                client.id,
+                super::pkce::CodeChallengeMethod::S256.transform(CODE_VERIFIER)
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::SeeOther);
+        let location = response.headers().get_one("Location").unwrap();
+        let query = location.split_once('?').unwrap().1;
+        assert!(!location.contains('#'));
+        let params = query
+            .split('&')
+            .map(|param| param.split_once('=').unwrap())
+            .collect::<Vec<(&str, &str)>>();
+        assert_eq!(params.len(), 2);
+        assert_eq!(params[0].0, "state");
+        assert_eq!(
+            rocket::http::RawStr::new(params[0].1).url_decode().unwrap(),
+            "a&code=forged#b c"
+        );
+        assert_eq!(params[1].0, "code");
+    }
+
+    #[rocket::async_test]
+    async fn test_dpop_bound_refresh_token() {
+        use super::jwk::Jwk;
+        use super::jws::Algorithm;
+
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (client, secret) = register_client(&test_client).await;
+        let code = authorization_code(&test_client, &client, "openid offline_access").await;
+        let jwk = Jwk::generate(Algorithm::Es256).unwrap();
+        let proof = |nonce: Option<&str>| token_proof(&jwk, nonce);
+        let token_request = |body: String, proof: Option<String>| {
+            let mut request = test_client
+                .post("/oauth/token")
+                .header(ContentType::Form)
+                .body(format!(
+                    "{}&client_id={}&client_secret={}",
+                    body, client.id, secret
+                ));
+            if let Some(proof) = proof {
+                request = request.header(Header::new("DPoP", proof));
+            }
+            request.dispatch()
+        };
+        let code_body = format!(
+            "grant_type=authorization_code&code={}&code_verifier={}\
+            &redirect_uri=http://localhost/callback",
+            code, CODE_VERIFIER
+        );
+
+        let response = token_request(code_body.clone(), Some(proof(None))).await;
+        let nonce = response
+            .headers()
+            .get_one("DPoP-Nonce")
+            .unwrap()
+            .to_string();
+        let response = token_request(code_body, Some(proof(Some(&nonce)))).await;
+        assert_eq!(response.status(), Status::Ok);
+        let body: Value = response.into_json().await.unwrap();
+        assert_eq!(body["token_type"], "DPoP");
+        let refresh_token = body["refresh_token"].as_str().unwrap().to_string();
+        let refresh_body = |refresh_token: &str| {
+            format!("grant_type=refresh_token&refresh_token={}", refresh_token)
+        };
+
+        // whoever got hold of the refresh token still needs the key
+        let response = token_request(refresh_body(&refresh_token), None).await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let body: Value = response.into_json().await.unwrap();
+        assert_eq!(body["error"], "invalid_dpop_proof");
+
+        let response = token_request(refresh_body(&refresh_token), Some(proof(Some(&nonce)))).await;
+        assert_eq!(response.status(), Status::Ok);
+        let body: Value = response.into_json().await.unwrap();
+        assert_eq!(body["token_type"], "DPoP");
+
+        // and so does the one it was rotated into
+        let rotated = body["refresh_token"].as_str().unwrap();
+        let response = token_request(refresh_body(rotated), None).await;
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[rocket::async_test]
+    async fn test_token_exchange_rejects_bound_tokens() {
+        use super::jwk::Jwk;
+        use super::jws::Algorithm;
+        use super::token::ACCESS_TOKEN_TYPE_URI;
+
+        let rocket = test_rocket().await;
+        let test_client = Client::tracked(rocket).await.unwrap();
+        let (backend, backend_secret) = register_client(&test_client).await;
+        // the frontend allows the backend right at registration
+        let response = test_client
+            .post("/oauth/clients")
+            .header(ContentType::JSON)
+            .body(
+                json!({
+                    "name": "test",
+                    "description": "test",
+                    "token_exchangers": [{ "client_id": backend.id }]
+                })
+                .to_string(),
+            )
+            .dispatch()
+            .await;
+        let body: Value = response.into_json().await.unwrap();
+        let frontend_secret = body["secret"].as_str().unwrap().to_string();
+        let frontend: super::client::Client = serde_json::from_value(body).unwrap();
+
+        let jwk = Jwk::generate(Algorithm::Es256).unwrap();
+        let dpop_request = |proof: String| {
+            test_client
+                .post("/oauth/token")
+                .header(ContentType::Form)
+                .header(Header::new("DPoP", proof))
+                .body(format!(
+                    "grant_type=client_credentials&client_id={}&client_secret={}&scope=profile",
+                    frontend.id, frontend_secret
+                ))
+                .dispatch()
+        };
+        let response = dpop_request(token_proof(&jwk, None)).await;
+        let nonce = response
+            .headers()
+            .get_one("DPoP-Nonce")
+            .unwrap()
+            .to_string();
+        let response = dpop_request(token_proof(&jwk, Some(&nonce))).await;
+        let body: Value = response.into_json().await.unwrap();
+        assert_eq!(body["token_type"], "DPoP");
+        let bound_token = body["access_token"].as_str().unwrap().to_string();
+        let (_, body) = token_request(
+            &test_client,
+            format!(
+                "grant_type=client_credentials&client_id={}&client_secret={}&scope=profile",
+                frontend.id, frontend_secret
+            ),
+        )
+        .await;
+        let bearer_token = body["access_token"].as_str().unwrap().to_string();
+
+        // neither as the subject nor as the actor
+        for (subject_token, actor) in [
+            (&bound_token, String::new()),
+            (
+                &bearer_token,
+                format!(
+                    "&actor_token={}&actor_token_type={}",
+                    bound_token, ACCESS_TOKEN_TYPE_URI
+                ),
+            ),
+        ] {
+            let (status, body) = token_request(
+                &test_client,
+                format!(
+                    "grant_type=urn:ietf:params:oauth:grant-type:token-exchange\
+                    &client_id={}&client_secret={}&subject_token={}&subject_token_type={}{}",
+                    backend.id, backend_secret, subject_token, ACCESS_TOKEN_TYPE_URI, actor
+                ),
+            )
+            .await;
+            assert_eq!(status, Status::BadRequest);
+            assert_eq!(body["error"], "invalid_request");
+        }
+    }
 }
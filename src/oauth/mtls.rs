@@ -0,0 +1,139 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use openssl::x509::X509;
+use rocket::http::Status;
+use rocket::mtls::Certificate;
+use rocket::request::{self, FromRequest, Outcome, Request};
+use sha2::{Digest, Sha256};
+
+use crate::oauth::claims::Confirmation;
+use crate::oauth::error::Error;
+use crate::oauth::jwk::JwkSet;
+
+// The certificate a client presented in the TLS handshake. Rocket has already checked it
+// against the mutual TLS `ca_certs`, so self-signed ones have to be in there as well.
+pub struct ClientCertificate {
+    der: Vec<u8>,
+    x509: X509,
+}
+
+impl ClientCertificate {
+    pub fn from_der(der: Vec<u8>) -> Result<Self, Error> {
+        let x509 = X509::from_der(&der).map_err(|_| Error::InvalidClient)?;
+        Ok(Self { der, x509 })
+    }
+
+    fn from_certificate(certificate: &Certificate<'_>) -> Result<Self, Error> {
+        Self::from_der(certificate.as_bytes().to_vec())
+    }
+
+    // base64url SHA-256 of the DER, the same as a JWK's x5t#S256
+    pub fn thumbprint(&self) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(&self.der))
+    }
+
+    pub fn confirms(&self, cnf: &Confirmation) -> bool {
+        cnf.x5t_s256.as_deref() == Some(self.thumbprint().as_str())
+    }
+
+    // RFC 4514 order, most specific first: "CN=client,O=Example,C=US"
+    pub fn subject_dn(&self) -> String {
+        let mut rdns = self
+            .x509
+            .subject_name()
+            .entries()
+            .map(|entry| {
+                let name = match entry.object().nid().short_name() {
+                    Ok(name) => name.to_string(),
+                    Err(_) => entry.object().to_string(),
+                };
+                let value = entry
+                    .data()
+                    .as_utf8()
+                    .map(|value| value.to_string())
+                    .unwrap_or_default();
+                format!("{}={}", name, value)
+            })
+            .collect::<Vec<String>>();
+        rdns.reverse();
+        rdns.join(",")
+    }
+
+    // RFC 8705 section 2.2: a self-signed certificate is only as good as the key it certifies
+    pub fn matches_key(&self, jwks: &JwkSet) -> bool {
+        let key = match self.x509.public_key() {
+            Ok(key) => key,
+            Err(_) => return false,
+        };
+        jwks.keys
+            .iter()
+            .filter_map(|jwk| jwk.public_key().ok())
+            .any(|jwk| jwk.public_eq(&key))
+    }
+}
+
+// forwards when the connection has no client certificate
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientCertificate {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match request.guard::<Certificate<'_>>().await {
+            Outcome::Success(certificate) => match Self::from_certificate(&certificate) {
+                Ok(certificate) => Outcome::Success(certificate),
+                Err(e) => Outcome::Error((Status::Unauthorized, e)),
+            },
+            Outcome::Error(_) => Outcome::Error((Status::Unauthorized, Error::InvalidClient)),
+            Outcome::Forward(status) => Outcome::Forward(status),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::oauth::jwk::{Jwk, PublicJwk};
+    use crate::oauth::jws::Algorithm;
+    use base64::engine::general_purpose::STANDARD;
+
+    fn certificate(jwk: &Jwk) -> ClientCertificate {
+        ClientCertificate::from_der(STANDARD.decode(&jwk.x5c[0]).unwrap()).unwrap()
+    }
+
+    fn certificate_for(alg: Algorithm) -> ClientCertificate {
+        certificate(&Jwk::generate(alg).unwrap())
+    }
+
+    #[test]
+    fn test_thumbprint() {
+        let jwk = Jwk::generate(Algorithm::Es256).unwrap();
+        let certificate = certificate(&jwk);
+        assert_eq!(Some(certificate.thumbprint()), jwk.x5t_s256);
+        let cnf = Confirmation::new(Some(certificate.thumbprint()), None).unwrap();
+        assert!(certificate.confirms(&cnf));
+
+        let other = certificate_for(Algorithm::Es256);
+        assert!(!other.confirms(&cnf));
+        assert!(ClientCertificate::from_der(b"not a certificate".to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_subject_dn() {
+        assert_eq!(
+            certificate_for(Algorithm::Rs256).subject_dn(),
+            "CN=rockettest"
+        );
+    }
+
+    #[test]
+    fn test_matches_key() {
+        let jwk = Jwk::generate(Algorithm::EdDsa).unwrap();
+        let certificate = certificate(&jwk);
+        let jwks = |jwk: &Jwk| JwkSet {
+            keys: vec![PublicJwk::from_key(&jwk.key).unwrap()],
+        };
+        assert!(certificate.matches_key(&jwks(&jwk)));
+        assert!(!certificate.matches_key(&jwks(&Jwk::generate(Algorithm::EdDsa).unwrap())));
+        assert!(!certificate.matches_key(&JwkSet { keys: vec![] }));
+    }
+}
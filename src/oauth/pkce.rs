@@ -1,11 +1,19 @@
+use crate::config::AUTHORIZATION_CODE_TTL;
+use crate::oauth::error::Error;
+use crate::oauth::revocation::TokenId;
 use crate::oauth::scopes::Scope;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 use hex::ToHex;
+use openssl::memcmp;
 use rand::Rng;
 use rocket::form::FromFormField;
 use rocket::tokio::sync::Mutex;
 use rocket::State;
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::sync::Arc;
 use uuid::Uuid;
 
 #[derive(Debug, Clone, FromFormField)]
@@ -25,6 +33,27 @@ impl fmt::Display for CodeChallengeMethod {
     }
 }
 
+impl CodeChallengeMethod {
+    // "plain" is left out on purpose, RFC 7636 section 7.2
+    pub const ALL: [CodeChallengeMethod; 1] = [CodeChallengeMethod::S256];
+
+    pub fn transform(&self, code_verifier: &str) -> String {
+        match self {
+            CodeChallengeMethod::S256 => {
+                URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+            }
+        }
+    }
+}
+
+// RFC 7636 section 4.1: 43-128 characters of [A-Z] / [a-z] / [0-9] / "-" / "." / "_" / "~"
+fn valid_code_verifier(code_verifier: &str) -> bool {
+    (43..=128).contains(&code_verifier.len())
+        && code_verifier
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-._~".contains(&b))
+}
+
 #[derive(Debug, Clone)]
 pub struct Pkce {
     pub client_id: Uuid,
@@ -35,6 +64,16 @@ pub struct Pkce {
     pub code_challenge: String,
     pub code_challenge_method: CodeChallengeMethod,
     pub authentication_code: String,
+    pub issued_at: i64,
+    // carried into the ID token for OpenID Connect requests
+    pub nonce: Option<String>,
+    pub auth_time: Option<i64>,
+    // RFC 8707 resource indicators the user granted, tokens can be narrowed to any of them
+    pub resources: Vec<String>,
+    pub redeemed: bool,
+    // what was issued from this code, revoked if the code is replayed
+    pub access_token: Option<TokenId>,
+    pub refresh_family: Option<Uuid>,
 }
 
 impl Pkce {
@@ -58,6 +97,30 @@ impl Pkce {
             code_challenge,
             code_challenge_method,
             authentication_code,
+            issued_at: chrono::offset::Utc::now().timestamp(),
+            nonce: None,
+            auth_time: None,
+            resources: Vec::new(),
+            redeemed: false,
+            access_token: None,
+            refresh_family: None,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.issued_at + *AUTHORIZATION_CODE_TTL <= chrono::offset::Utc::now().timestamp()
+    }
+
+    pub fn verify(&self, code_verifier: &str) -> Result<(), Error> {
+        if !valid_code_verifier(code_verifier) {
+            return Err(Error::InvalidGrant);
+        }
+        let challenge = self.code_challenge_method.transform(code_verifier);
+        match challenge.len() == self.code_challenge.len()
+            && memcmp::eq(challenge.as_bytes(), self.code_challenge.as_bytes())
+        {
+            true => Ok(()),
+            false => Err(Error::InvalidGrant),
         }
     }
 
@@ -68,11 +131,13 @@ impl Pkce {
 
 type PkceMap = Mutex<HashMap<String, Pkce>>;
 pub type PkceCodes<'r> = &'r State<PkceStorage>;
-pub struct PkceStorage(PkceMap);
+// shared with the sweeper task, hence the Arc
+#[derive(Clone)]
+pub struct PkceStorage(Arc<PkceMap>);
 
 impl PkceStorage {
     pub fn new() -> Self {
-        Self(PkceMap::new(HashMap::new()))
+        Self(Arc::new(PkceMap::new(HashMap::new())))
     }
 
     #[allow(unused)]
@@ -98,4 +163,203 @@ impl PkceStorage {
     pub async fn delete(&self, code: &str) {
         self.0.lock().await.remove(code);
     }
+
+    // Marks the code as redeemed, handing back the record as it was before so callers can tell
+    // a first redemption from a replay. What the first redemption is about to issue goes in
+    // with it, so a replay racing it still finds something to revoke. Codes issued to another
+    // client are left alone, or any client could burn them.
+    pub async fn redeem(
+        &self,
+        code: &str,
+        client_id: Uuid,
+        access_token: &TokenId,
+        refresh_family: Uuid,
+    ) -> Option<Pkce> {
+        let mut codes = self.0.lock().await;
+        let pkce = codes
+            .get_mut(code)
+            .filter(|pkce| pkce.client_id == client_id)?;
+        let previous = pkce.clone();
+        if !pkce.redeemed {
+            pkce.redeemed = true;
+            pkce.access_token = Some(access_token.clone());
+            pkce.refresh_family = pkce
+                .scope
+                .contains(&Scope::OfflineAccess)
+                .then_some(refresh_family);
+        }
+        Some(previous)
+    }
+
+    // Redeemed codes stay for as long as anything issued from them is still good, so a replay
+    // can still revoke it. `families` are the refresh token families that are still around.
+    pub async fn remove_expired(&self, families: &HashSet<Uuid>) {
+        let now = chrono::offset::Utc::now().timestamp();
+        self.0.lock().await.retain(|_, c| {
+            !c.is_expired()
+                || c.access_token.as_ref().is_some_and(|t| t.expires_at > now)
+                || c.refresh_family.is_some_and(|f| families.contains(&f))
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // the example from RFC 7636 appendix B
+    const VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    const CHALLENGE: &str = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+
+    fn token_id(expires_at: i64) -> TokenId {
+        TokenId {
+            jti: Uuid::new_v4().to_string(),
+            expires_at,
+        }
+    }
+
+    fn pkce(code_challenge: &str) -> Pkce {
+        Pkce::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            "http://localhost/callback".to_string(),
+            "state".to_string(),
+            vec![Scope::OpenId],
+            code_challenge.to_string(),
+            CodeChallengeMethod::S256,
+        )
+    }
+
+    #[test]
+    fn test_transform_s256() {
+        assert_eq!(CodeChallengeMethod::S256.transform(VERIFIER), CHALLENGE);
+    }
+
+    #[test]
+    fn test_valid_code_verifier() {
+        assert!(valid_code_verifier(VERIFIER));
+        assert!(valid_code_verifier(&"~".repeat(128)));
+        assert!(!valid_code_verifier(&"a".repeat(42)));
+        assert!(!valid_code_verifier(&"a".repeat(129)));
+        assert!(!valid_code_verifier(&format!("{}+", &VERIFIER[1..])));
+    }
+
+    #[test]
+    fn test_is_expired() {
+        let mut code = pkce(CHALLENGE);
+        assert!(!code.is_expired());
+        code.issued_at -= *AUTHORIZATION_CODE_TTL;
+        assert!(code.is_expired());
+    }
+
+    #[rocket::async_test]
+    async fn test_redeem() {
+        let storage = PkceStorage::new();
+        let mut code = pkce(CHALLENGE);
+        code.scope.push(Scope::OfflineAccess);
+        storage.insert(code.clone()).await;
+        let (access_token, family) = (token_id(i64::MAX), Uuid::new_v4());
+        let redeem = || {
+            storage.redeem(
+                &code.authentication_code,
+                code.client_id,
+                &access_token,
+                family,
+            )
+        };
+
+        assert!(!redeem().await.unwrap().redeemed);
+        // a replay finds what the first redemption issued, even before it has been
+        let replayed = redeem().await.unwrap();
+        assert!(replayed.redeemed);
+        assert_eq!(replayed.access_token.unwrap().jti, access_token.jti);
+        assert_eq!(replayed.refresh_family, Some(family));
+        assert!(storage
+            .redeem("nope", code.client_id, &access_token, family)
+            .await
+            .is_none());
+    }
+
+    #[rocket::async_test]
+    async fn test_redeem_other_client() {
+        let storage = PkceStorage::new();
+        let code = pkce(CHALLENGE);
+        storage.insert(code.clone()).await;
+
+        assert!(storage
+            .redeem(
+                &code.authentication_code,
+                Uuid::new_v4(),
+                &token_id(i64::MAX),
+                Uuid::new_v4()
+            )
+            .await
+            .is_none());
+        assert!(
+            !storage
+                .redeem(
+                    &code.authentication_code,
+                    code.client_id,
+                    &token_id(i64::MAX),
+                    Uuid::new_v4()
+                )
+                .await
+                .unwrap()
+                .redeemed
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_remove_expired() {
+        let storage = PkceStorage::new();
+        let fresh = pkce(CHALLENGE);
+        let mut expired = pkce(CHALLENGE);
+        expired.issued_at = 0;
+        storage.insert(fresh.clone()).await;
+        storage.insert(expired.clone()).await;
+
+        storage.remove_expired(&HashSet::new()).await;
+        assert!(storage.get(&fresh.authentication_code).await.is_some());
+        assert!(storage.get(&expired.authentication_code).await.is_none());
+    }
+
+    #[rocket::async_test]
+    async fn test_remove_expired_keeps_issued() {
+        let storage = PkceStorage::new();
+        let redeemed = |access_token, refresh_family| {
+            let mut code = pkce(CHALLENGE);
+            code.issued_at = 0;
+            code.redeemed = true;
+            code.access_token = Some(access_token);
+            code.refresh_family = refresh_family;
+            code
+        };
+        let family = Uuid::new_v4();
+        let live_access_token = redeemed(token_id(i64::MAX), None);
+        let live_family = redeemed(token_id(0), Some(family));
+        let gone = redeemed(token_id(0), Some(Uuid::new_v4()));
+        for code in [&live_access_token, &live_family, &gone] {
+            storage.insert(code.clone()).await;
+        }
+
+        storage.remove_expired(&HashSet::from([family])).await;
+        assert!(storage
+            .get(&live_access_token.authentication_code)
+            .await
+            .is_some());
+        assert!(storage
+            .get(&live_family.authentication_code)
+            .await
+            .is_some());
+        assert!(storage.get(&gone.authentication_code).await.is_none());
+    }
+
+    #[test]
+    fn test_verify() {
+        let code = pkce(CHALLENGE);
+        assert!(code.verify(VERIFIER).is_ok());
+        assert!(code.verify(&VERIFIER.replace('d', "e")).is_err());
+        assert!(code.verify("short").is_err());
+        assert!(pkce(VERIFIER).verify(VERIFIER).is_err());
+    }
 }
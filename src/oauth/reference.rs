@@ -0,0 +1,80 @@
+use crate::oauth::claims::AccessTokenClaims;
+use hex::ToHex;
+use rand::Rng;
+use rocket::tokio::sync::Mutex;
+use rocket::State;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Opaque access tokens for clients that pass their tokens on to third parties. The claims
+// stay here, so the token means nothing to anyone but us.
+type ReferenceTokenMap = Mutex<HashMap<String, AccessTokenClaims>>;
+pub type ReferenceTokens<'r> = &'r State<ReferenceTokenStorage>;
+#[derive(Clone)]
+pub struct ReferenceTokenStorage(Arc<ReferenceTokenMap>);
+
+impl ReferenceTokenStorage {
+    pub fn new() -> Self {
+        Self(Arc::new(ReferenceTokenMap::new(HashMap::new())))
+    }
+
+    // a fresh token standing in for `claims`
+    pub async fn issue(&self, claims: AccessTokenClaims) -> String {
+        let token = rand::thread_rng().gen::<[u8; 32]>().encode_hex::<String>();
+        self.0.lock().await.insert(token.clone(), claims);
+        token
+    }
+
+    pub async fn get(&self, token: &str) -> Option<AccessTokenClaims> {
+        self.0.lock().await.get(token).cloned()
+    }
+
+    pub async fn remove_expired(&self) {
+        let now = chrono::offset::Utc::now().timestamp();
+        self.0.lock().await.retain(|_, claims| claims.exp > now);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    fn claims(exp: i64) -> AccessTokenClaims {
+        let client_id = Uuid::new_v4();
+        AccessTokenClaims {
+            iss: "http://localhost:8000".to_string(),
+            sub: client_id.to_string(),
+            aud: "http://localhost:8000".to_string(),
+            exp,
+            iat: 0,
+            nbf: None,
+            jti: Uuid::new_v4().to_string(),
+            client_id,
+            scope: String::new(),
+            act: None,
+            cnf: None,
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_issue() {
+        let storage = ReferenceTokenStorage::new();
+        let claims = claims(i64::MAX);
+        let token = storage.issue(claims.clone()).await;
+        assert_eq!(token.len(), 64);
+        assert_eq!(storage.get(&token).await, Some(claims));
+        assert!(storage.get("unknown").await.is_none());
+    }
+
+    #[rocket::async_test]
+    async fn test_remove_expired() {
+        let storage = ReferenceTokenStorage::new();
+        let expired = storage.issue(claims(0)).await;
+        let live = storage.issue(claims(i64::MAX)).await;
+
+        storage.remove_expired().await;
+        assert!(storage.get(&expired).await.is_none());
+        assert!(storage.get(&live).await.is_some());
+    }
+}
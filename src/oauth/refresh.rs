@@ -0,0 +1,306 @@
+use crate::oauth::error::Error;
+use crate::oauth::revocation::{RevocationStorage, TokenId};
+use crate::oauth::scopes::Scope;
+use hex::ToHex;
+use rand::Rng;
+use rocket::tokio::sync::Mutex;
+use rocket::State;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+pub struct RefreshToken {
+    pub token: String,
+    // every token rotated out of the same authorization shares a family
+    pub family_id: Uuid,
+    pub client_id: Uuid,
+    pub account_id: Uuid,
+    pub scope: Vec<Scope>,
+    // carried over from the authorization code
+    pub resources: Vec<String>,
+    pub issued_at: i64,
+    pub expires_at: i64,
+    pub used: bool,
+    // the access token handed out alongside this one
+    pub access_token: Option<TokenId>,
+    // RFC 9449 section 5, the DPoP key every refresh has to prove possession of
+    pub jkt: Option<String>,
+}
+
+impl RefreshToken {
+    pub fn new(
+        client_id: Uuid,
+        account_id: Uuid,
+        scope: Vec<Scope>,
+        family_id: Option<Uuid>,
+        ttl: i64,
+    ) -> Self {
+        let now = chrono::offset::Utc::now().timestamp();
+        Self {
+            token: Self::generate_token(),
+            family_id: family_id.unwrap_or_else(Uuid::new_v4),
+            client_id,
+            account_id,
+            scope,
+            resources: Vec::new(),
+            issued_at: now,
+            expires_at: now + ttl,
+            used: false,
+            access_token: None,
+            jkt: None,
+        }
+    }
+
+    // the next token in this family, with the same grant
+    pub fn rotate(&self, ttl: i64) -> Self {
+        let mut next = Self::new(
+            self.client_id,
+            self.account_id,
+            self.scope.clone(),
+            Some(self.family_id),
+            ttl,
+        );
+        next.resources = self.resources.clone();
+        next.jkt = self.jkt.clone();
+        next
+    }
+
+    fn generate_token() -> String {
+        rand::thread_rng().gen::<[u8; 32]>().encode_hex::<String>()
+    }
+}
+
+type RefreshTokenMap = Mutex<HashMap<String, RefreshToken>>;
+pub type RefreshTokens<'r> = &'r State<RefreshTokenStorage>;
+#[derive(Clone)]
+pub struct RefreshTokenStorage(Arc<RefreshTokenMap>);
+
+fn take_family(tokens: &mut HashMap<String, RefreshToken>, family_id: Uuid) -> Vec<RefreshToken> {
+    let family = tokens
+        .values()
+        .filter(|t| t.family_id == family_id)
+        .map(|t| t.token.clone())
+        .collect::<Vec<String>>();
+    family
+        .iter()
+        .filter_map(|token| tokens.remove(token))
+        .collect()
+}
+
+async fn deny_access_tokens(family: Vec<RefreshToken>, revocations: &RevocationStorage) {
+    for access_token in family.iter().filter_map(|t| t.access_token.as_ref()) {
+        revocations.revoke(access_token).await;
+    }
+}
+
+impl RefreshTokenStorage {
+    pub fn new() -> Self {
+        Self(Arc::new(RefreshTokenMap::new(HashMap::new())))
+    }
+
+    pub async fn get(&self, token: &str) -> Option<RefreshToken> {
+        let tokens = self.0.lock().await;
+        Some(tokens.get(token)?.clone())
+    }
+
+    pub async fn insert(&self, token: RefreshToken) {
+        let mut tokens = self.0.lock().await;
+        tokens.insert(token.token.clone(), token);
+    }
+
+    // Marks a token as used and hands it back. Presenting a token that was already used means
+    // it leaked somewhere, so the whole family goes with it, access tokens included. Another
+    // client presenting it changes nothing, or it could burn tokens that aren't its own. The
+    // same goes for a request without a proof from the key the token is bound to.
+    pub async fn redeem(
+        &self,
+        token: &str,
+        client_id: Uuid,
+        jkt: Option<&str>,
+        revocations: &RevocationStorage,
+    ) -> Result<RefreshToken, Error> {
+        let mut tokens = self.0.lock().await;
+        let refresh_token = tokens.get_mut(token).ok_or(Error::InvalidGrant)?;
+        if refresh_token.client_id != client_id {
+            return Err(Error::InvalidGrant);
+        }
+        if refresh_token.jkt.is_some() && refresh_token.jkt.as_deref() != jkt {
+            return Err(Error::InvalidDpopProof);
+        }
+        if refresh_token.used {
+            let family_id = refresh_token.family_id;
+            let family = take_family(&mut tokens, family_id);
+            drop(tokens);
+            deny_access_tokens(family, revocations).await;
+            return Err(Error::InvalidGrant);
+        }
+        if refresh_token.expires_at <= chrono::offset::Utc::now().timestamp() {
+            tokens.remove(token);
+            return Err(Error::InvalidGrant);
+        }
+        refresh_token.used = true;
+        Ok(refresh_token.clone())
+    }
+
+    pub async fn revoke_family(&self, family_id: Uuid, revocations: &RevocationStorage) {
+        let family = take_family(&mut *self.0.lock().await, family_id);
+        deny_access_tokens(family, revocations).await;
+    }
+
+    // families with a token still around, used or not
+    pub async fn families(&self) -> HashSet<Uuid> {
+        self.0.lock().await.values().map(|t| t.family_id).collect()
+    }
+
+    pub async fn remove_client(&self, client_id: Uuid) {
+        self.0.lock().await.retain(|_, t| t.client_id != client_id);
+    }
+
+    pub async fn remove_expired(&self) {
+        let now = chrono::offset::Utc::now().timestamp();
+        self.0.lock().await.retain(|_, t| t.expires_at > now);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn refresh_token() -> RefreshToken {
+        RefreshToken::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            vec![Scope::OpenId, Scope::OfflineAccess],
+            None,
+            3600,
+        )
+    }
+
+    #[test]
+    fn test_rotate() {
+        let token = refresh_token();
+        let rotated = token.rotate(60);
+        assert_ne!(token.token, rotated.token);
+        assert_eq!(token.family_id, rotated.family_id);
+        assert_eq!(token.scope, rotated.scope);
+        assert!(!rotated.used);
+        assert_eq!(rotated.expires_at, rotated.issued_at + 60);
+    }
+
+    #[rocket::async_test]
+    async fn test_redeem() {
+        let storage = RefreshTokenStorage::new();
+        let revocations = RevocationStorage::new();
+        let token = refresh_token();
+        storage.insert(token.clone()).await;
+
+        let redeemed = storage
+            .redeem(&token.token, token.client_id, None, &revocations)
+            .await
+            .unwrap();
+        assert_eq!(redeemed.token, token.token);
+        assert!(storage.get(&token.token).await.unwrap().used);
+        assert!(storage
+            .redeem("nope", token.client_id, None, &revocations)
+            .await
+            .is_err());
+    }
+
+    #[rocket::async_test]
+    async fn test_redeem_other_client() {
+        let storage = RefreshTokenStorage::new();
+        let revocations = RevocationStorage::new();
+        let token = refresh_token();
+        storage.insert(token.clone()).await;
+
+        for _ in 0..2 {
+            assert!(matches!(
+                storage
+                    .redeem(&token.token, Uuid::new_v4(), None, &revocations)
+                    .await,
+                Err(Error::InvalidGrant)
+            ));
+        }
+        // still good for the client it was issued to
+        assert!(!storage.get(&token.token).await.unwrap().used);
+        assert!(storage
+            .redeem(&token.token, token.client_id, None, &revocations)
+            .await
+            .is_ok());
+    }
+
+    #[rocket::async_test]
+    async fn test_redeem_bound() {
+        let storage = RefreshTokenStorage::new();
+        let revocations = RevocationStorage::new();
+        let mut token = refresh_token();
+        token.jkt = Some("thumbprint".to_string());
+        storage.insert(token.clone()).await;
+
+        for jkt in [None, Some("other")] {
+            assert!(matches!(
+                storage
+                    .redeem(&token.token, token.client_id, jkt, &revocations)
+                    .await,
+                Err(Error::InvalidDpopProof)
+            ));
+        }
+        assert!(!storage.get(&token.token).await.unwrap().used);
+        let redeemed = storage
+            .redeem(
+                &token.token,
+                token.client_id,
+                Some("thumbprint"),
+                &revocations,
+            )
+            .await
+            .unwrap();
+        assert_eq!(redeemed.rotate(60).jkt, token.jkt);
+    }
+
+    #[rocket::async_test]
+    async fn test_redeem_expired() {
+        let storage = RefreshTokenStorage::new();
+        let revocations = RevocationStorage::new();
+        let mut token = refresh_token();
+        token.expires_at = 0;
+        storage.insert(token.clone()).await;
+
+        assert!(storage
+            .redeem(&token.token, token.client_id, None, &revocations)
+            .await
+            .is_err());
+        assert!(storage.get(&token.token).await.is_none());
+    }
+
+    #[rocket::async_test]
+    async fn test_replay_revokes_family() {
+        let storage = RefreshTokenStorage::new();
+        let revocations = RevocationStorage::new();
+        let token = refresh_token();
+        let mut rotated = token.rotate(3600);
+        rotated.access_token = Some(TokenId {
+            jti: "rotated".to_string(),
+            expires_at: i64::MAX,
+        });
+        let unrelated = refresh_token();
+        storage.insert(token.clone()).await;
+        storage.insert(unrelated.clone()).await;
+
+        storage
+            .redeem(&token.token, token.client_id, None, &revocations)
+            .await
+            .unwrap();
+        storage.insert(rotated.clone()).await;
+
+        assert!(storage
+            .redeem(&token.token, token.client_id, None, &revocations)
+            .await
+            .is_err());
+        assert!(storage.get(&token.token).await.is_none());
+        assert!(storage.get(&rotated.token).await.is_none());
+        assert!(storage.get(&unrelated.token).await.is_some());
+        assert!(revocations.is_revoked("rotated", &token.client_id).await);
+    }
+}
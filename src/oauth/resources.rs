@@ -0,0 +1,163 @@
+use crate::config::{AUDIENCE, ISSUER, RESOURCE_SERVERS};
+use crate::oauth::error::Error;
+use crate::oauth::scopes::Scope;
+use rocket::serde::Deserialize;
+use rocket::State;
+
+// RFC 8707: a resource server tokens can be requested for, named by the URI that ends up in
+// their `aud`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Resource {
+    pub identifier: String,
+    // the part of this server that accepts its tokens, none for resource servers elsewhere
+    #[serde(default)]
+    pub path: Option<String>,
+    pub scopes: Vec<Scope>,
+}
+
+impl Resource {
+    fn own(identifier: String, path: &str) -> Self {
+        Self {
+            identifier,
+            path: Some(path.to_string()),
+            scopes: Scope::ALL.to_vec(),
+        }
+    }
+
+    // whole path segments only, /decks doesn't cover /decksmith
+    fn serves(&self, path: &str) -> bool {
+        let prefix = match &self.path {
+            Some(prefix) => prefix.trim_end_matches('/'),
+            None => return false,
+        };
+        match path.strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+
+    // the requested scopes this resource has any use for
+    pub fn narrow(&self, scopes: Vec<Scope>) -> Result<Vec<Scope>, Error> {
+        let requested = !scopes.is_empty();
+        let scopes = scopes
+            .into_iter()
+            .filter(|scope| self.scopes.contains(scope))
+            .collect::<Vec<Scope>>();
+        match requested && scopes.is_empty() {
+            true => Err(Error::InvalidScope),
+            false => Ok(scopes),
+        }
+    }
+}
+
+pub type Resources<'r> = &'r State<ResourceRegistry>;
+pub struct ResourceRegistry(Vec<Resource>);
+
+impl ResourceRegistry {
+    pub fn new(resources: Vec<Resource>) -> Self {
+        Self(resources)
+    }
+
+    // our own resources, then whatever RESOURCE_SERVERS adds
+    pub fn configured() -> Self {
+        let base = ISSUER.trim_end_matches('/');
+        let mut resources = vec![
+            // tokens nobody asked a resource for are good everywhere here, as they always were
+            Resource::own(AUDIENCE.clone(), "/"),
+            Resource::own(format!("{}/decks", base), "/decks"),
+            Resource::own(format!("{}/oauth", base), "/oauth"),
+        ];
+        resources.extend(RESOURCE_SERVERS.iter().cloned());
+        Self::new(resources)
+    }
+
+    pub fn get(&self, identifier: &str) -> Result<&Resource, Error> {
+        self.0
+            .iter()
+            .find(|resource| resource.identifier == identifier)
+            .ok_or(Error::InvalidTarget)
+    }
+
+    // every `aud` a token may carry to be accepted at `path`
+    pub fn audiences(&self, path: &str) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter(|resource| resource.serves(path))
+            .map(|resource| resource.identifier.as_str())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn registry() -> ResourceRegistry {
+        ResourceRegistry::new(vec![
+            Resource::own("https://as.example".to_string(), "/"),
+            Resource::own("https://as.example/decks".to_string(), "/decks"),
+            Resource {
+                identifier: "https://api.example".to_string(),
+                path: None,
+                scopes: vec![Scope::Profile],
+            },
+        ])
+    }
+
+    #[test]
+    fn test_audiences() {
+        let registry = registry();
+        assert_eq!(
+            registry.audiences("/decks/"),
+            vec!["https://as.example", "https://as.example/decks"]
+        );
+        assert_eq!(registry.audiences("/decks"), registry.audiences("/decks/"));
+        assert_eq!(registry.audiences("/decksmith"), vec!["https://as.example"]);
+        assert_eq!(
+            registry.audiences("/oauth/userinfo"),
+            vec!["https://as.example"]
+        );
+    }
+
+    #[test]
+    fn test_get() {
+        let registry = registry();
+        assert_eq!(
+            registry.get("https://api.example").unwrap().scopes,
+            vec![Scope::Profile]
+        );
+        assert!(matches!(
+            registry.get("https://elsewhere.example"),
+            Err(Error::InvalidTarget)
+        ));
+    }
+
+    #[test]
+    fn test_narrow() {
+        let registry = registry();
+        let api = registry.get("https://api.example").unwrap();
+        assert_eq!(
+            api.narrow(vec![Scope::OpenId, Scope::Profile]).unwrap(),
+            vec![Scope::Profile]
+        );
+        assert!(api.narrow(vec![]).unwrap().is_empty());
+        assert!(matches!(
+            api.narrow(vec![Scope::Email]),
+            Err(Error::InvalidScope)
+        ));
+    }
+
+    #[test]
+    fn test_configured_resource_servers() {
+        let resources: Vec<Resource> = serde_json::from_str(
+            r#"[{"identifier": "https://api.example", "scopes": ["openid", "offline_access"]}]"#,
+        )
+        .unwrap();
+        assert_eq!(resources[0].path, None);
+        assert_eq!(
+            resources[0].scopes,
+            vec![Scope::OpenId, Scope::OfflineAccess]
+        );
+    }
+}
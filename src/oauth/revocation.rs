@@ -0,0 +1,126 @@
+use rocket::tokio::sync::Mutex;
+use rocket::State;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use uuid::Uuid;
+
+// enough to find an access token on the denylist and to know when it can fall off again
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenId {
+    pub jti: String,
+    pub expires_at: i64,
+}
+
+#[derive(Default)]
+struct Denylist {
+    tokens: HashMap<String, i64>,
+    // client ids are never reused, so a deleted client can stay here for good
+    clients: HashSet<Uuid>,
+}
+
+pub type Revocations<'r> = &'r State<RevocationStorage>;
+#[derive(Clone)]
+pub struct RevocationStorage(Arc<Mutex<Denylist>>);
+
+impl RevocationStorage {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(Denylist::default())))
+    }
+
+    pub async fn revoke(&self, token_id: &TokenId) {
+        let mut denylist = self.0.lock().await;
+        denylist
+            .tokens
+            .insert(token_id.jti.clone(), token_id.expires_at);
+    }
+
+    // for tokens good only once: denies it from now on, false if it already was
+    pub async fn use_once(&self, token_id: &TokenId) -> bool {
+        let mut denylist = self.0.lock().await;
+        denylist
+            .tokens
+            .insert(token_id.jti.clone(), token_id.expires_at)
+            .is_none()
+    }
+
+    pub async fn revoke_client(&self, client_id: Uuid) {
+        self.0.lock().await.clients.insert(client_id);
+    }
+
+    pub async fn is_revoked(&self, jti: &str, client_id: &Uuid) -> bool {
+        let denylist = self.0.lock().await;
+        denylist.tokens.contains_key(jti) || denylist.clients.contains(client_id)
+    }
+
+    // once a token has expired on its own it no longer needs to be denied
+    pub async fn remove_expired(&self) {
+        let now = chrono::offset::Utc::now().timestamp();
+        self.0
+            .lock()
+            .await
+            .tokens
+            .retain(|_, expires_at| *expires_at > now);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[rocket::async_test]
+    async fn test_revoke() {
+        let storage = RevocationStorage::new();
+        let client_id = Uuid::new_v4();
+        let token_id = TokenId {
+            jti: Uuid::new_v4().to_string(),
+            expires_at: i64::MAX,
+        };
+
+        assert!(!storage.is_revoked(&token_id.jti, &client_id).await);
+        storage.revoke(&token_id).await;
+        assert!(storage.is_revoked(&token_id.jti, &client_id).await);
+        assert!(!storage.is_revoked("other", &client_id).await);
+    }
+
+    #[rocket::async_test]
+    async fn test_use_once() {
+        let storage = RevocationStorage::new();
+        let token_id = TokenId {
+            jti: Uuid::new_v4().to_string(),
+            expires_at: i64::MAX,
+        };
+
+        assert!(storage.use_once(&token_id).await);
+        assert!(!storage.use_once(&token_id).await);
+    }
+
+    #[rocket::async_test]
+    async fn test_revoke_client() {
+        let storage = RevocationStorage::new();
+        let client_id = Uuid::new_v4();
+
+        storage.revoke_client(client_id).await;
+        assert!(storage.is_revoked("any", &client_id).await);
+        assert!(!storage.is_revoked("any", &Uuid::new_v4()).await);
+    }
+
+    #[rocket::async_test]
+    async fn test_remove_expired() {
+        let storage = RevocationStorage::new();
+        let expired = TokenId {
+            jti: "expired".to_string(),
+            expires_at: 0,
+        };
+        let live = TokenId {
+            jti: "live".to_string(),
+            expires_at: i64::MAX,
+        };
+        storage.revoke(&expired).await;
+        storage.revoke(&live).await;
+
+        storage.remove_expired().await;
+        let client_id = Uuid::new_v4();
+        assert!(!storage.is_revoked("expired", &client_id).await);
+        assert!(storage.is_revoked("live", &client_id).await);
+    }
+}
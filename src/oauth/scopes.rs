@@ -1,8 +1,11 @@
+use rocket::serde::{Deserialize, Serialize};
 use std::fmt::Display;
 use std::str::FromStr;
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
 pub enum Scope {
+    #[serde(rename = "openid")]
     OpenId,
     Profile,
     Email,
@@ -11,6 +14,17 @@ pub enum Scope {
     OfflineAccess,
 }
 
+impl Scope {
+    pub const ALL: [Scope; 6] = [
+        Scope::OpenId,
+        Scope::Profile,
+        Scope::Email,
+        Scope::Address,
+        Scope::Phone,
+        Scope::OfflineAccess,
+    ];
+}
+
 impl Display for Scope {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
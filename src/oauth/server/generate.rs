@@ -1,35 +1,58 @@
 use chrono;
-use jwt::{Header, PKeyWithDigest, SignWithKey, Token as JwtToken};
-use openssl::hash::MessageDigest;
-use std::collections::BTreeMap;
+use rocket::serde::Serialize;
 use uuid::Uuid;
 
-use crate::config::KEY;
-use crate::oauth::client::Client;
+use crate::config::{ISSUER, JWT_LEEWAY, KEYS};
+use crate::oauth::claims::{
+    AccessTokenClaims, Actor, Confirmation, IdTokenClaims, TokenHeader, ACCESS_TOKEN_TYPE, JWT_TYPE,
+};
+use crate::oauth::client::{AccessTokenFormat, Client};
 use crate::oauth::error::Error;
+use crate::oauth::jwk::Jwk;
+use crate::oauth::jws;
+use crate::oauth::pkce::Pkce;
+use crate::oauth::reference::ReferenceTokenStorage;
+use crate::oauth::revocation::TokenId;
 use crate::oauth::scopes::Scope;
 use crate::oauth::token::Token;
+use crate::oauth::userinfo::{SignedUserInfo, UserInfo};
 
-const TOKEN_TTL: i64 = 3600;
+// signs any claims set with the active key, `typ` says what kind of token it is
+pub fn sign<C: Serialize>(claims: C, typ: &str) -> Result<String, Error> {
+    sign_with(&KEYS.active(), claims, typ)
+}
+
+fn sign_with<C: Serialize>(jwk: &Jwk, claims: C, typ: &str) -> Result<String, Error> {
+    let header = TokenHeader {
+        alg: jwk.alg,
+        kid: Some(jwk.kid.clone()),
+        typ: Some(typ.to_string()),
+    };
+    jws::encode(&header, &claims, &jwk.key)
+}
 
+// The id and lifetime of the access token a request is about to get, picked ahead of signing
+// so an authorization code can record it as it is redeemed. Revoking it has to outlast the
+// clock skew it is still accepted with.
+pub fn access_token_id(client: &Client) -> TokenId {
+    let now = chrono::offset::Utc::now().timestamp();
+    TokenId {
+        jti: Uuid::new_v4().to_string(),
+        expires_at: now + client.access_token_lifetime() + *JWT_LEEWAY,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn generate(
     scopes: Vec<Scope>,
     client: Client,
     user_id: Option<Uuid>,
+    audience: &str,
+    act: Option<Actor>,
+    cnf: Option<Confirmation>,
+    token_id: &TokenId,
+    reference_tokens: &ReferenceTokenStorage,
 ) -> Result<Token, Error> {
-    let mut claims = BTreeMap::new();
-    let now = chrono::offset::Utc::now().timestamp();
-    let iat = now.to_string();
-    let exp = (now + TOKEN_TTL).to_string();
-
-    claims.insert("iat", iat);
-    claims.insert("exp", exp);
-    claims.insert("client_id", client.id.to_string());
-
-    if let Some(user_id) = user_id {
-        claims.insert("user_id", user_id.to_string());
-    }
-
     // gonna just assume all scopes are valid for now
     let scopes_string = scopes
         .iter()
@@ -37,39 +60,91 @@ pub async fn generate(
         .collect::<Vec<String>>()
         .join(" ");
 
-    claims.insert("scopes", scopes_string.clone());
-    let key = PKeyWithDigest {
-        digest: MessageDigest::sha256(),
-        key: KEY.key.clone(),
+    let ttl = client.access_token_lifetime();
+    let exp = token_id.expires_at - *JWT_LEEWAY;
+    let claims = AccessTokenClaims {
+        iss: ISSUER.clone(),
+        sub: user_id.unwrap_or(client.id).to_string(),
+        aud: audience.to_string(),
+        exp,
+        iat: exp - ttl,
+        nbf: Some(exp - ttl),
+        jti: token_id.jti.clone(),
+        client_id: client.id,
+        scope: scopes_string.clone(),
+        act,
+        cnf,
     };
+    let access_token = match client.access_token_format {
+        AccessTokenFormat::Jwt => sign(claims, ACCESS_TOKEN_TYPE)?,
+        AccessTokenFormat::Reference => reference_tokens.issue(claims).await,
+    };
+    Ok(Token::new(access_token, ttl, scopes_string, None))
+}
+
+// encrypted to the client as well, if it registered for that
+pub fn generate_id_token(pkce: &Pkce, client: &Client) -> Result<String, Error> {
+    let now = chrono::offset::Utc::now().timestamp();
+    let claims = IdTokenClaims {
+        iss: ISSUER.clone(),
+        sub: pkce.account_id.to_string(),
+        aud: pkce.client_id.to_string(),
+        exp: now + client.id_token_lifetime(),
+        iat: now,
+        auth_time: pkce.auth_time,
+        nonce: pkce.nonce.clone(),
+    };
+    let jwt = sign(claims, JWT_TYPE)?;
+    match client.id_token_encryption() {
+        Some(encryption) => encryption.encrypt_jwt(&jwt),
+        None => Ok(jwt),
+    }
+}
 
-    let header = Header {
-        algorithm: KEY.alg,
-        key_id: Some(KEY.kid.to_string()),
-        ..Default::default()
+// With whichever published key has the alg the client registered. When rotation has left
+// none of that kind there's nothing the client would accept, so it fails rather than guess.
+pub fn sign_userinfo(userinfo: UserInfo, client: &Client) -> Result<String, Error> {
+    let jwk = client
+        .userinfo_signed_response_alg
+        .as_deref()
+        .and_then(|alg| alg.parse().ok())
+        .and_then(|alg| KEYS.find_alg(alg))
+        .ok_or(Error::UnsupportedKey)?;
+    let claims = SignedUserInfo {
+        iss: ISSUER.clone(),
+        aud: client.id.to_string(),
+        userinfo,
     };
-    let jwt = JwtToken::new(header, claims).sign_with_key(&key).unwrap();
-    Ok(Token::new(
-        jwt.as_str().into(),
-        TOKEN_TTL,
-        scopes_string,
-        None,
-    ))
+    sign_with(&jwk, claims, JWT_TYPE)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::config::{ACCESS_TOKEN_TTL, AUDIENCE, ID_TOKEN_TTL};
 
     #[rocket::async_test]
     async fn test_generate_client_credentials() {
         let client = Client::new_no_secret("name".to_string(), "test".to_string());
         let scopes = vec![Scope::OpenId, Scope::Profile];
-        let token = generate(scopes, client, None).await.unwrap();
+        let token_id = access_token_id(&client);
+        let token = generate(
+            scopes,
+            client,
+            None,
+            &AUDIENCE,
+            None,
+            None,
+            &token_id,
+            &ReferenceTokenStorage::new(),
+        )
+        .await
+        .unwrap();
 
-        assert_eq!(token.expires_in, TOKEN_TTL);
+        assert_eq!(token.expires_in, *ACCESS_TOKEN_TTL);
         assert_eq!(token.scope, "openid profile");
         assert!(token.refresh_token.is_none());
+        assert!(token_id.expires_at > chrono::offset::Utc::now().timestamp());
     }
 
     #[rocket::async_test]
@@ -77,10 +152,203 @@ mod test {
         let client = Client::new_no_secret("name".to_string(), "test".to_string());
         let scopes = vec![Scope::OpenId, Scope::Profile];
         let user_id = Uuid::new_v4();
-        let token = generate(scopes, client, Some(user_id)).await.unwrap();
+        let token_id = access_token_id(&client);
+        let token = generate(
+            scopes,
+            client,
+            Some(user_id),
+            &AUDIENCE,
+            None,
+            None,
+            &token_id,
+            &ReferenceTokenStorage::new(),
+        )
+        .await
+        .unwrap();
 
-        assert_eq!(token.expires_in, TOKEN_TTL);
+        assert_eq!(token.expires_in, *ACCESS_TOKEN_TTL);
         assert_eq!(token.scope, "openid profile");
         assert!(token.refresh_token.is_none());
+        assert!(token_id.expires_at > chrono::offset::Utc::now().timestamp());
+    }
+
+    #[rocket::async_test]
+    async fn test_generate_claims() {
+        use crate::oauth::client_jwt::ClientJwt;
+
+        let client = Client::new_no_secret("name".to_string(), "test".to_string());
+        let client_id = client.id;
+        let user_id = Uuid::new_v4();
+        let token_id = access_token_id(&client);
+        let token = generate(
+            vec![Scope::OpenId],
+            client,
+            Some(user_id),
+            "https://api.example",
+            None,
+            None,
+            &token_id,
+            &ReferenceTokenStorage::new(),
+        )
+        .await
+        .unwrap();
+
+        let jwt = ClientJwt::parse(&token.access_token).unwrap();
+        let claims = jwt.claims();
+        assert_eq!(claims.iss, *ISSUER);
+        assert_eq!(claims.aud, "https://api.example");
+        assert_eq!(claims.sub, user_id.to_string());
+        assert_eq!(claims.client_id, client_id);
+        assert_eq!(claims.scope, "openid");
+        assert_eq!(claims.exp, claims.iat + *ACCESS_TOKEN_TTL);
+        assert_eq!(claims.jti, token_id.jti);
+    }
+
+    fn id_token_pkce(client: &Client) -> Pkce {
+        use crate::oauth::pkce::CodeChallengeMethod;
+
+        Pkce::new(
+            client.id,
+            Uuid::new_v4(),
+            "http://localhost/callback".to_string(),
+            "state".to_string(),
+            vec![Scope::OpenId],
+            "challenge".to_string(),
+            CodeChallengeMethod::S256,
+        )
+    }
+
+    #[rocket::async_test]
+    async fn test_generate_client_lifetime() {
+        let mut client = Client::new_no_secret("name".to_string(), "test".to_string());
+        client.access_token_ttl = Some(300);
+        let token_id = access_token_id(&client);
+        let token = generate(
+            vec![],
+            client,
+            None,
+            &AUDIENCE,
+            None,
+            None,
+            &token_id,
+            &ReferenceTokenStorage::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(token.expires_in, 300);
+        let claims = crate::oauth::client_jwt::ClientJwt::parse(&token.access_token).unwrap();
+        assert_eq!(claims.claims().exp, claims.claims().iat + 300);
+        assert_eq!(token_id.expires_at, claims.claims().exp + *JWT_LEEWAY);
+    }
+
+    #[rocket::async_test]
+    async fn test_generate_reference_token() {
+        use crate::oauth::client_jwt::ClientJwt;
+
+        let mut client = Client::new_no_secret("name".to_string(), "test".to_string());
+        client.access_token_format = AccessTokenFormat::Reference;
+        let client_id = client.id;
+        let reference_tokens = ReferenceTokenStorage::new();
+        let token_id = access_token_id(&client);
+        let token = generate(
+            vec![Scope::OpenId],
+            client,
+            None,
+            &AUDIENCE,
+            None,
+            None,
+            &token_id,
+            &reference_tokens,
+        )
+        .await
+        .unwrap();
+
+        // nothing to read in the token itself
+        assert!(ClientJwt::parse(&token.access_token).is_err());
+        let jwt = ClientJwt::resolve(&token.access_token, &reference_tokens)
+            .await
+            .unwrap();
+        assert_eq!(jwt.claims().client_id, client_id);
+        assert_eq!(jwt.claims().jti, token_id.jti);
+        assert!(jwt.validate(&[&AUDIENCE]).is_ok());
+    }
+
+    #[test]
+    fn test_generate_id_token() {
+        let client = Client::new_no_secret("name".to_string(), "test".to_string());
+        let mut pkce = id_token_pkce(&client);
+        pkce.nonce = Some("n-0S6_WzA2Mj".to_string());
+        pkce.auth_time = Some(1_700_000_000);
+
+        let id_token = generate_id_token(&pkce, &client).unwrap();
+        let token = jws::Compact::parse(&id_token).unwrap();
+        let claims: IdTokenClaims = token.verify(&KEYS.active()).unwrap();
+        assert_eq!(token.header.typ.as_deref(), Some(JWT_TYPE));
+        assert_eq!(claims.iss, *ISSUER);
+        assert_eq!(claims.sub, pkce.account_id.to_string());
+        assert_eq!(claims.aud, pkce.client_id.to_string());
+        assert_eq!(claims.exp, claims.iat + *ID_TOKEN_TTL);
+        assert_eq!(claims.auth_time, Some(1_700_000_000));
+        assert_eq!(claims.nonce.as_deref(), Some("n-0S6_WzA2Mj"));
+        // an ID token is not an access token
+        assert!(crate::oauth::client_jwt::ClientJwt::parse(&id_token).is_err());
+    }
+
+    #[test]
+    fn test_generate_encrypted_id_token() {
+        use crate::oauth::jwe::{self, ContentEncryption, KeyManagement};
+        use crate::oauth::jwk::{Jwk, JwkSet, PublicJwk};
+        use crate::oauth::jws::Algorithm;
+
+        let key = Jwk::generate(Algorithm::Rs256).unwrap();
+        let mut client = Client::new_no_secret("name".to_string(), "test".to_string());
+        client.jwks = Some(JwkSet {
+            keys: vec![PublicJwk::from_key(&key.key).unwrap()],
+        });
+        client.id_token_encrypted_response_alg = Some(KeyManagement::RsaOaep256);
+        client.id_token_encrypted_response_enc = Some(ContentEncryption::A256Gcm);
+        let pkce = id_token_pkce(&client);
+
+        let id_token = generate_id_token(&pkce, &client).unwrap();
+        assert!(jws::Compact::parse(&id_token).is_err());
+        let (header, jwt) = jwe::decrypt(&id_token, &key.key).unwrap();
+        assert_eq!(header.cty.as_deref(), Some("JWT"));
+        let jwt = String::from_utf8(jwt).unwrap();
+        let claims: IdTokenClaims = jws::Compact::parse(&jwt)
+            .unwrap()
+            .verify(&KEYS.active())
+            .unwrap();
+        assert_eq!(claims.aud, client.id.to_string());
+    }
+
+    #[test]
+    fn test_sign_userinfo_registered_alg() {
+        use crate::oauth::jws::Algorithm;
+        use rocket::serde::json::Value;
+
+        let mut client = Client::new_no_secret("name".to_string(), "test".to_string());
+        let userinfo = UserInfo {
+            sub: "subject".to_string(),
+            ..Default::default()
+        };
+        let active = KEYS.active();
+        client.userinfo_signed_response_alg = Some(active.alg.to_string());
+        let signed = sign_userinfo(userinfo.clone(), &client).unwrap();
+        let token = jws::Compact::parse(&signed).unwrap();
+        assert_eq!(token.header.alg, active.alg);
+        assert_eq!(token.header.kid.as_deref(), Some(active.kid.as_str()));
+        let claims: Value = token.verify(&active).unwrap();
+        assert_eq!(claims["aud"], client.id.to_string());
+
+        // nothing published to sign with what the client asked for
+        let missing = Algorithm::ALL
+            .into_iter()
+            .find(|alg| KEYS.find_alg(*alg).is_none())
+            .unwrap();
+        client.userinfo_signed_response_alg = Some(missing.to_string());
+        assert!(matches!(
+            sign_userinfo(userinfo, &client),
+            Err(Error::UnsupportedKey)
+        ));
     }
 }
@@ -1,37 +1,340 @@
+use super::basic_auth::BasicAuth;
 use super::client::Clients;
 use super::error::Error;
 use super::forms;
 use super::pkce::CodeChallengeMethod;
+use crate::account::acc::Accounts;
 use uuid::Uuid;
 
 pub mod generate;
 pub mod validate;
+use crate::oauth::claims::Confirmation;
+use crate::oauth::client_jwt::ClientJwt;
+use crate::oauth::dpop::{DpopProof, DpopProofs, DPOP_TOKEN_TYPE};
 use crate::oauth::grant_types::GrantType;
+use crate::oauth::introspection::Introspection;
+use crate::oauth::mtls::ClientCertificate;
 use crate::oauth::pkce::{Pkce, PkceCodes};
-use crate::oauth::token::Token;
+use crate::oauth::reference::ReferenceTokens;
+use crate::oauth::refresh::{RefreshToken, RefreshTokens};
+use crate::oauth::resources::Resources;
+use crate::oauth::revocation::Revocations;
+use crate::oauth::scopes::Scope;
+use crate::oauth::token::{Token, ACCESS_TOKEN_TYPE_URI};
+use crate::oauth::userinfo::{UserInfo, UserInfoResponse};
 
+#[allow(clippy::too_many_arguments)]
 pub async fn token(
     trf: forms::TokenRequestForm<'_>,
+    basic: BasicAuth,
+    certificate: Option<ClientCertificate>,
+    dpop: DpopProof,
+    dpop_proofs: DpopProofs<'_>,
     clients: Clients<'_>,
     pkce_codes: PkceCodes<'_>,
+    refresh_tokens: RefreshTokens<'_>,
+    revocations: Revocations<'_>,
+    reference_tokens: ReferenceTokens<'_>,
+    resources: Resources<'_>,
 ) -> Result<Token, Error> {
     let grant_type: GrantType = trf.grant_type.parse()?;
-    let client = validate::validate_client(clients, &trf.client_id, &trf.client_secret).await?;
+    let (client_id, auth) = validate::ClientAuth::new(
+        trf.client_id,
+        trf.client_secret.as_deref(),
+        trf.client_assertion_type,
+        trf.client_assertion,
+        &basic,
+        certificate.as_ref(),
+    )?;
+    let client = match (auth, &grant_type) {
+        (validate::ClientAuth::None, GrantType::JwtBearer) => {
+            validate::identify_client(clients, &client_id).await?
+        }
+        (auth, _) => validate::validate_client(clients, &client_id, auth, revocations).await?,
+    };
+    // RFC 9449 section 5, the key whoever holds the token will have to sign proofs with
+    let jkt = dpop.verify(None, dpop_proofs).await?;
+    let token_id = generate::access_token_id(&client);
 
-    let (scopes, user_id) = match grant_type {
+    let (scopes, granted_resources, user_id, refresh_token, id_token, act) = match &grant_type {
         GrantType::AuthorizationCode => {
-            let pkce = validate::validate_code(trf.code, client.id, pkce_codes).await?;
-            (pkce.scope, Some(pkce.account_id))
+            let refresh_family = Uuid::new_v4();
+            let pkce = validate::validate_code(
+                trf.code,
+                trf.code_verifier,
+                trf.redirect_uri,
+                client.id,
+                &token_id,
+                refresh_family,
+                pkce_codes,
+                refresh_tokens,
+                revocations,
+            )
+            .await?;
+            let refresh_token = match pkce.scope.contains(&Scope::OfflineAccess) {
+                true => {
+                    let mut refresh_token = RefreshToken::new(
+                        client.id,
+                        pkce.account_id,
+                        pkce.scope.clone(),
+                        Some(refresh_family),
+                        client.refresh_token_lifetime(),
+                    );
+                    refresh_token.resources = pkce.resources.clone();
+                    refresh_token.jkt = jkt.clone();
+                    Some(refresh_token)
+                }
+                false => None,
+            };
+            let id_token = match pkce.scope.contains(&Scope::OpenId) {
+                true => Some(generate::generate_id_token(&pkce, &client)?),
+                false => None,
+            };
+            (
+                pkce.scope,
+                pkce.resources,
+                Some(pkce.account_id),
+                refresh_token,
+                id_token,
+                None,
+            )
         }
         GrantType::ClientCredentials => {
             let scope_param = trf.scope.unwrap_or("");
-            (validate::validate_scopes(scope_param)?, None)
+            (
+                validate::validate_scopes(scope_param)?,
+                Vec::new(),
+                None,
+                None,
+                None,
+                None,
+            )
+        }
+        GrantType::RefreshToken => {
+            let refresh_token =
+                validate::peek_refresh_token(trf.refresh_token, client.id, refresh_tokens).await?;
+            let scopes = match trf.scope {
+                Some(scope_param) => {
+                    validate::validate_scope_subset(scope_param, &refresh_token.scope)?
+                }
+                None => refresh_token.scope.clone(),
+            };
+            (
+                scopes,
+                refresh_token.resources.clone(),
+                Some(refresh_token.account_id),
+                Some(refresh_token.rotate(client.refresh_token_lifetime())),
+                None,
+                None,
+            )
+        }
+        GrantType::JwtBearer => {
+            let (scopes, subject) =
+                validate::validate_assertion(trf.assertion, trf.scope, &client, revocations)
+                    .await?;
+            (scopes, Vec::new(), Some(subject), None, None, None)
+        }
+        GrantType::TokenExchange => {
+            let exchange = validate::validate_token_exchange(
+                &trf,
+                &client,
+                clients,
+                revocations,
+                reference_tokens,
+                resources,
+            )
+            .await?;
+            (
+                exchange.scopes,
+                Vec::new(),
+                Some(exchange.subject),
+                None,
+                None,
+                Some(exchange.act),
+            )
         }
     };
-    let token = generate::generate(scopes, client, user_id).await?;
+    let resource = validate::validate_resource(trf.resource, &granted_resources, resources)?;
+    // each resource only hears about the scopes it has a use for
+    let scopes = resource.narrow(scopes)?;
+    // The refresh token is only used up once the request is otherwise good, or a retry after
+    // a bad scope or resource would look like a replay and take the whole family down
+    if grant_type == GrantType::RefreshToken {
+        validate::validate_refresh_token(
+            trf.refresh_token,
+            client.id,
+            jkt.as_deref(),
+            refresh_tokens,
+            revocations,
+        )
+        .await?;
+    }
+    let mut token = generate::generate(
+        scopes,
+        client,
+        user_id,
+        &resource.identifier,
+        act.clone(),
+        // RFC 8705 section 3, whoever holds the token has to hold the certificate as well
+        Confirmation::new(
+            certificate.as_ref().map(ClientCertificate::thumbprint),
+            jkt.clone(),
+        ),
+        &token_id,
+        reference_tokens,
+    )
+    .await?;
+    if jkt.is_some() {
+        token.token_type = DPOP_TOKEN_TYPE.to_string();
+    }
+    token.id_token = id_token;
+    if act.is_some() {
+        token.issued_token_type = Some(ACCESS_TOKEN_TYPE_URI.to_string());
+    }
+    if let Some(mut refresh_token) = refresh_token {
+        token.refresh_token = Some(refresh_token.token.clone());
+        refresh_token.access_token = Some(token_id);
+        refresh_tokens.insert(refresh_token).await;
+    }
     Ok(token)
 }
 
+pub async fn introspect(
+    irf: forms::IntrospectionRequestForm<'_>,
+    basic: BasicAuth,
+    certificate: Option<ClientCertificate>,
+    clients: Clients<'_>,
+    refresh_tokens: RefreshTokens<'_>,
+    revocations: Revocations<'_>,
+    reference_tokens: ReferenceTokens<'_>,
+) -> Result<Introspection, Error> {
+    let (client_id, auth) = validate::ClientAuth::new(
+        irf.client_id,
+        irf.client_secret.as_deref(),
+        irf.client_assertion_type,
+        irf.client_assertion,
+        &basic,
+        certificate.as_ref(),
+    )?;
+    validate::validate_client(clients, &client_id, auth, revocations).await?;
+
+    let access_token = || async {
+        let jwt = ClientJwt::resolve(irf.token, reference_tokens).await.ok()?;
+        match jwt.is_revoked(revocations).await {
+            true => Some(Introspection::inactive()),
+            false => Some(Introspection::from_access_token(&jwt)),
+        }
+    };
+    let refresh_token = || async {
+        refresh_tokens
+            .get(irf.token)
+            .await
+            .map(|t| Introspection::from_refresh_token(&t))
+    };
+    // the hint only decides which kind of token gets looked up first
+    let introspection = match irf.token_type_hint {
+        Some("refresh_token") => match refresh_token().await {
+            Some(introspection) => Some(introspection),
+            None => access_token().await,
+        },
+        _ => match access_token().await {
+            Some(introspection) => Some(introspection),
+            None => refresh_token().await,
+        },
+    };
+    Ok(introspection.unwrap_or_else(Introspection::inactive))
+}
+
+// RFC 7009: unknown tokens and tokens belonging to other clients are quietly ignored
+pub async fn revoke(
+    rrf: forms::RevocationRequestForm<'_>,
+    basic: BasicAuth,
+    certificate: Option<ClientCertificate>,
+    clients: Clients<'_>,
+    refresh_tokens: RefreshTokens<'_>,
+    revocations: Revocations<'_>,
+    reference_tokens: ReferenceTokens<'_>,
+) -> Result<(), Error> {
+    let (client_id, auth) = validate::ClientAuth::new(
+        rrf.client_id,
+        rrf.client_secret.as_deref(),
+        rrf.client_assertion_type,
+        rrf.client_assertion,
+        &basic,
+        certificate.as_ref(),
+    )?;
+    let client = validate::validate_client(clients, &client_id, auth, revocations).await?;
+
+    let revoke_access_token = || async {
+        let jwt = match ClientJwt::resolve(rrf.token, reference_tokens).await {
+            Ok(jwt) => jwt,
+            Err(_) => return false,
+        };
+        if jwt.authorize_for(&client.id).is_ok() {
+            revocations.revoke(&jwt.token_id()).await;
+        }
+        true
+    };
+    let revoke_refresh_token = || async {
+        let refresh_token = match refresh_tokens.get(rrf.token).await {
+            Some(refresh_token) => refresh_token,
+            None => return false,
+        };
+        if refresh_token.client_id == client.id {
+            refresh_tokens
+                .revoke_family(refresh_token.family_id, revocations)
+                .await;
+        }
+        true
+    };
+    // the hint only decides which kind of token gets looked up first
+    match rrf.token_type_hint {
+        Some("refresh_token") => {
+            if !revoke_refresh_token().await {
+                revoke_access_token().await;
+            }
+        }
+        _ => {
+            if !revoke_access_token().await {
+                revoke_refresh_token().await;
+            }
+        }
+    }
+    Ok(())
+}
+
+// OpenID Connect Core section 5.3, only for tokens a user granted with the openid scope
+pub async fn userinfo(
+    jwt: &ClientJwt,
+    clients: Clients<'_>,
+    accounts: Accounts<'_>,
+) -> Result<UserInfoResponse, Error> {
+    let claims = jwt.claims();
+    let scopes = claims
+        .scope
+        .split(' ')
+        .filter_map(|scope| scope.parse().ok())
+        .collect::<Vec<Scope>>();
+    if !scopes.contains(&Scope::OpenId) {
+        return Err(Error::InvalidResourceAccess);
+    }
+    // client credentials tokens have the client as subject, there's no user to describe
+    let account_id = Uuid::parse_str(&claims.sub).map_err(|_| Error::InvalidToken)?;
+    let account = accounts.get(&account_id).await.ok_or(Error::InvalidToken)?;
+    let client = clients
+        .get(&claims.client_id)
+        .await
+        .ok_or(Error::InvalidToken)?;
+
+    let userinfo = UserInfo::new(&account, &scopes);
+    match client.userinfo_signed_response_alg {
+        Some(_) => Ok(UserInfoResponse::Signed(generate::sign_userinfo(
+            userinfo, &client,
+        )?)),
+        None => Ok(UserInfoResponse::Claims(userinfo)),
+    }
+}
+
 #[derive(Debug)]
 pub struct AuthContext {
     pub client_name: String,
@@ -41,16 +344,21 @@ pub struct AuthContext {
     pub scope: String,
     pub code_challenge: String,
     pub code_challenge_method: CodeChallengeMethod,
+    pub nonce: Option<String>,
+    pub resource: Vec<String>,
 }
 
 pub async fn authorize(
     auth_request: forms::AuthorizationRequest<'_>,
     clients: Clients<'_>,
+    resources: Resources<'_>,
 ) -> Result<AuthContext, Error> {
     let client = clients
         .get(&auth_request.client_id)
         .await
         .ok_or(Error::InvalidClient)?;
+    validate::validate_redirect_uri(&client, auth_request.redirect_uri)?;
+    let resource = validate::validate_resources(&auth_request.resource, resources)?;
     Ok(AuthContext {
         client_name: client.name,
         client_id: client.id,
@@ -59,6 +367,8 @@ pub async fn authorize(
         scope: auth_request.scope.to_string(),
         code_challenge: auth_request.code_challenge.to_string(),
         code_challenge_method: auth_request.code_challenge_method,
+        nonce: auth_request.nonce.map(str::to_string),
+        resource,
     })
 }
 
@@ -72,18 +382,22 @@ pub struct ValidatedAuthContext {
 
 pub async fn submit_authorization(
     user_id: Uuid,
+    auth_time: Option<i64>,
     auth_request: forms::AuthorizationRequestForm<'_>,
     clients: Clients<'_>,
     pkce_codes: PkceCodes<'_>,
+    resources: Resources<'_>,
 ) -> Result<ValidatedAuthContext, Error> {
     let client = clients
         .get(&auth_request.client_id)
         .await
         .ok_or(Error::InvalidClient)?;
+    validate::validate_redirect_uri(&client, auth_request.redirect_uri)?;
 
     let validated_scopes = validate::validate_scopes(auth_request.scope)?;
+    let validated_resources = validate::validate_resources(&auth_request.resource, resources)?;
 
-    let pkce_code = Pkce::new(
+    let mut pkce_code = Pkce::new(
         auth_request.client_id,
         user_id,
         auth_request.redirect_uri.to_string(),
@@ -92,6 +406,9 @@ pub async fn submit_authorization(
         auth_request.code_challenge.to_string(),
         auth_request.code_challenge_method.clone(),
     );
+    pkce_code.nonce = auth_request.nonce.map(str::to_string);
+    pkce_code.auth_time = auth_time;
+    pkce_code.resources = validated_resources;
     let authentication_code = pkce_code.authentication_code.clone();
     pkce_codes.insert(pkce_code).await;
 
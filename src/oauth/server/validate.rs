@@ -1,39 +1,236 @@
-use crate::oauth::client::{Client, Clients};
+use crate::config::AUDIENCE;
+use crate::config::JWT_LEEWAY;
+use crate::oauth::assertion::{self, CLIENT_ASSERTION_TYPE};
+use crate::oauth::basic_auth::BasicAuth;
+use crate::oauth::claims::Actor;
+use crate::oauth::client::{Client, Clients, TokenEndpointAuthMethod};
+use crate::oauth::client_jwt::ClientJwt;
 use crate::oauth::error::Error;
+use crate::oauth::forms::TokenRequest;
+use crate::oauth::jws::Compact;
+use crate::oauth::mtls::ClientCertificate;
 use crate::oauth::pkce::{Pkce, PkceCodes};
+use crate::oauth::reference::ReferenceTokens;
+use crate::oauth::refresh::{RefreshToken, RefreshTokens};
+use crate::oauth::resources::{Resource, ResourceRegistry, Resources};
+use crate::oauth::revocation::{Revocations, TokenId};
 use crate::oauth::scopes::Scope;
+use crate::oauth::token::ACCESS_TOKEN_TYPE_URI;
 use uuid::Uuid;
 
+// `access_token` and `refresh_family` are what the code is about to be exchanged for
+#[allow(clippy::too_many_arguments)]
 pub async fn validate_code(
     code: Option<&str>,
+    code_verifier: Option<&str>,
+    redirect_uri: Option<&str>,
     client_id: Uuid,
+    access_token: &TokenId,
+    refresh_family: Uuid,
     pkce_codes: PkceCodes<'_>,
+    refresh_tokens: RefreshTokens<'_>,
+    revocations: Revocations<'_>,
 ) -> Result<Pkce, Error> {
     let code = code.ok_or(Error::InvalidCode)?;
-    let pkce_code = pkce_codes.get(code).await.ok_or(Error::InvalidCode)?;
-    if pkce_code.client_id != client_id {
-        return Err(Error::InvalidCode);
+    // a failed verifier still burns the code, otherwise it could be brute forced
+    let pkce_code = pkce_codes
+        .redeem(code, client_id, access_token, refresh_family)
+        .await
+        .ok_or(Error::InvalidCode)?;
+    if pkce_code.redeemed {
+        // RFC 6749 section 4.1.2: revoke what was already issued from a replayed code
+        if let Some(access_token) = &pkce_code.access_token {
+            revocations.revoke(access_token).await;
+        }
+        if let Some(family_id) = pkce_code.refresh_family {
+            refresh_tokens.revoke_family(family_id, revocations).await;
+        }
+        return Err(Error::InvalidGrant);
+    }
+    if pkce_code.is_expired() {
+        return Err(Error::InvalidGrant);
+    }
+    // RFC 6749 section 4.1.3: must be identical to the one the code was issued for
+    if redirect_uri != Some(pkce_code.redirect_uri.as_str()) {
+        return Err(Error::InvalidGrant);
     }
-    // this needs a lot more stuff
-    // validate state, code challenge, etc.
-    pkce_codes.delete(code).await;
+    pkce_code.verify(code_verifier.ok_or(Error::InvalidGrant)?)?;
     Ok(pkce_code)
 }
 
+// the grant a refresh token stands for, without using it up
+pub async fn peek_refresh_token(
+    token: Option<&str>,
+    client_id: Uuid,
+    refresh_tokens: RefreshTokens<'_>,
+) -> Result<RefreshToken, Error> {
+    let token = token.ok_or(Error::InvalidGrant)?;
+    refresh_tokens
+        .get(token)
+        .await
+        .filter(|refresh_token| refresh_token.client_id == client_id)
+        .ok_or(Error::InvalidGrant)
+}
+
+pub async fn validate_refresh_token(
+    token: Option<&str>,
+    client_id: Uuid,
+    jkt: Option<&str>,
+    refresh_tokens: RefreshTokens<'_>,
+    revocations: Revocations<'_>,
+) -> Result<RefreshToken, Error> {
+    let token = token.ok_or(Error::InvalidGrant)?;
+    refresh_tokens
+        .redeem(token, client_id, jkt, revocations)
+        .await
+}
+
+pub fn validate_redirect_uri(client: &Client, redirect_uri: &str) -> Result<(), Error> {
+    match client.has_redirect_uri(redirect_uri) {
+        true => Ok(()),
+        false => Err(Error::InvalidRedirectUri),
+    }
+}
+
+// what a request authenticates the client with, RFC 6749 section 2.3 allows one way at a time
+pub enum ClientAuth<'a> {
+    None,
+    Secret(&'a str),
+    Assertion(&'a str),
+    Certificate(&'a ClientCertificate),
+}
+
+impl<'a> ClientAuth<'a> {
+    // The client id comes along, it's in the Basic header when that's the way. A TLS client
+    // certificate only counts when nothing else was sent.
+    pub fn new(
+        client_id: Option<Uuid>,
+        client_secret: Option<&'a str>,
+        client_assertion_type: Option<&str>,
+        client_assertion: Option<&'a str>,
+        basic: &'a BasicAuth,
+        certificate: Option<&'a ClientCertificate>,
+    ) -> Result<(Uuid, Self), Error> {
+        if let Some((basic_id, secret)) = &basic.0 {
+            if client_secret.is_some()
+                || client_assertion_type.is_some()
+                || client_assertion.is_some()
+                || client_id.is_some_and(|id| id != *basic_id)
+            {
+                return Err(Error::InvalidRequest);
+            }
+            return Ok((*basic_id, ClientAuth::Secret(secret)));
+        }
+        let client_id = client_id.ok_or(Error::InvalidRequest)?;
+        let auth = match (client_secret, client_assertion_type, client_assertion) {
+            (None, None, None) => match certificate {
+                Some(certificate) => ClientAuth::Certificate(certificate),
+                None => ClientAuth::None,
+            },
+            (Some(secret), None, None) => ClientAuth::Secret(secret),
+            (None, Some(CLIENT_ASSERTION_TYPE), Some(assertion)) => {
+                ClientAuth::Assertion(assertion)
+            }
+            _ => return Err(Error::InvalidRequest),
+        };
+        Ok((client_id, auth))
+    }
+}
+
+// only the way the client registered for will do
 pub async fn validate_client(
     clients: Clients<'_>,
     client_id: &Uuid,
-    client_secret: &str,
+    auth: ClientAuth<'_>,
+    revocations: Revocations<'_>,
 ) -> Result<Client, Error> {
     let mut client = clients
         .get(client_id)
         .await
         .ok_or(Error::InvalidClient)?
         .clone();
-    client.validate_secret(client_secret)?;
-    client.increment_login_count();
+    client.check_rate_limit()?;
+    let authenticated = authenticate(&client, auth, revocations).await;
+    client.record_login(authenticated.is_ok());
     clients.update(client.clone()).await;
-    Ok(client)
+    authenticated.map(|_| client)
+}
+
+async fn authenticate(
+    client: &Client,
+    auth: ClientAuth<'_>,
+    revocations: Revocations<'_>,
+) -> Result<(), Error> {
+    match (client.token_endpoint_auth_method, auth) {
+        // it's the same secret whether it came in the body or the header
+        (
+            TokenEndpointAuthMethod::ClientSecretPost | TokenEndpointAuthMethod::ClientSecretBasic,
+            ClientAuth::Secret(secret),
+        ) => client.validate_secret(secret)?,
+        (
+            TokenEndpointAuthMethod::ClientSecretJwt | TokenEndpointAuthMethod::PrivateKeyJwt,
+            ClientAuth::Assertion(assertion),
+        ) => {
+            let token_id = assertion::verify_client_assertion(assertion, client)?;
+            if !revocations.use_once(&token_id).await {
+                return Err(Error::InvalidClient);
+            }
+        }
+        (TokenEndpointAuthMethod::TlsClientAuth, ClientAuth::Certificate(certificate)) => {
+            if client.tls_client_auth_subject_dn.as_deref() != Some(&certificate.subject_dn()) {
+                return Err(Error::InvalidClient);
+            }
+        }
+        (
+            TokenEndpointAuthMethod::SelfSignedTlsClientAuth,
+            ClientAuth::Certificate(certificate),
+        ) => {
+            if !client
+                .jwks
+                .as_ref()
+                .is_some_and(|jwks| certificate.matches_key(jwks))
+            {
+                return Err(Error::InvalidClient);
+            }
+        }
+        _ => return Err(Error::InvalidClient),
+    }
+    Ok(())
+}
+
+// RFC 7523 section 3.1: the assertion proves who's asking, the client is only named
+pub async fn identify_client(clients: Clients<'_>, client_id: &Uuid) -> Result<Client, Error> {
+    clients.get(client_id).await.ok_or(Error::InvalidClient)
+}
+
+// RFC 7523 section 2.1: an assertion from one of the client's trusted issuers, used once
+pub async fn validate_assertion(
+    assertion: Option<&str>,
+    scope: Option<&str>,
+    client: &Client,
+    revocations: Revocations<'_>,
+) -> Result<(Vec<Scope>, Uuid), Error> {
+    let assertion =
+        Compact::parse(assertion.ok_or(Error::InvalidGrant)?).map_err(|_| Error::InvalidGrant)?;
+    let (issuer, claims) = client
+        .trusted_issuers
+        .iter()
+        .find_map(|issuer| Some((issuer, issuer.verify(&assertion).ok()?)))
+        .ok_or(Error::InvalidGrant)?;
+    let scopes = match scope {
+        Some(scope) => validate_scope_subset(scope, &issuer.scopes)?,
+        None => issuer.scopes.clone(),
+    };
+    // without a `jti` nothing would stop the same assertion from being used over and over
+    let jti = claims.jti.as_ref().ok_or(Error::InvalidGrant)?;
+    let token_id = TokenId {
+        jti: format!("{} {}", claims.iss, jti),
+        expires_at: claims.exp + *JWT_LEEWAY,
+    };
+    if !revocations.use_once(&token_id).await {
+        return Err(Error::InvalidGrant);
+    }
+    Ok((scopes, assertion::mapped_subject(client, &claims)))
 }
 
 pub fn validate_scopes(scopes: &str) -> Result<Vec<Scope>, Error> {
@@ -47,10 +244,151 @@ pub fn validate_scopes(scopes: &str) -> Result<Vec<Scope>, Error> {
     Ok(scopes_list)
 }
 
+// a refresh may narrow the original grant but never widen it
+pub fn validate_scope_subset(scopes: &str, granted: &[Scope]) -> Result<Vec<Scope>, Error> {
+    let requested = validate_scopes(scopes)?;
+    match requested.iter().all(|s| granted.contains(s)) {
+        true => Ok(requested),
+        false => Err(Error::InvalidScope),
+    }
+}
+
+// RFC 8707 section 2: every resource indicator has to name a registered resource
+pub fn validate_resources(
+    requested: &[&str],
+    resources: &ResourceRegistry,
+) -> Result<Vec<String>, Error> {
+    requested
+        .iter()
+        .map(|identifier| Ok(resources.get(identifier)?.identifier.clone()))
+        .collect()
+}
+
+// RFC 8707 section 2.2: the one resource an access token is for, out of those the grant covers.
+// A grant that named none may be narrowed to any, or stays with the default audience.
+pub fn validate_resource<'a>(
+    requested: Option<&str>,
+    granted: &[String],
+    resources: &'a ResourceRegistry,
+) -> Result<&'a Resource, Error> {
+    let identifier = match (requested, granted) {
+        (Some(requested), []) => requested,
+        (Some(requested), granted) if granted.iter().any(|g| g == requested) => requested,
+        (Some(_), _) => return Err(Error::InvalidTarget),
+        (None, []) => AUDIENCE.as_str(),
+        (None, [only]) => only.as_str(),
+        (None, _) => return Err(Error::InvalidTarget),
+    };
+    resources.get(identifier)
+}
+
+// RFC 8693 section 2.1: a live access token of ours, and `token_type` has to say as much
+async fn validate_exchanged_token(
+    token: Option<&str>,
+    token_type: Option<&str>,
+    revocations: Revocations<'_>,
+    reference_tokens: ReferenceTokens<'_>,
+    resources: Resources<'_>,
+) -> Result<ClientJwt, Error> {
+    let token = token.ok_or(Error::InvalidRequest)?;
+    if token_type != Some(ACCESS_TOKEN_TYPE_URI) {
+        return Err(Error::InvalidRequest);
+    }
+    let jwt = ClientJwt::resolve(token, reference_tokens)
+        .await
+        .map_err(|_| Error::InvalidRequest)?;
+    // it may have been meant for any resource we know of
+    let resource = resources
+        .get(&jwt.claims().aud)
+        .map_err(|_| Error::InvalidRequest)?;
+    jwt.validate(&[&resource.identifier])
+        .map_err(|_| Error::InvalidRequest)?;
+    // A sender-constrained token is only good with its certificate or DPoP key, and we'd be
+    // handing out one that needs neither
+    if jwt.claims().cnf.is_some() {
+        return Err(Error::InvalidRequest);
+    }
+    match jwt.is_revoked(revocations).await {
+        true => Err(Error::InvalidRequest),
+        false => Ok(jwt),
+    }
+}
+
+// what a token exchange issues a token for
+pub struct Exchange {
+    pub subject: Uuid,
+    pub scopes: Vec<Scope>,
+    pub act: Actor,
+}
+
+pub async fn validate_token_exchange(
+    trf: &TokenRequest<'_>,
+    client: &Client,
+    clients: Clients<'_>,
+    revocations: Revocations<'_>,
+    reference_tokens: ReferenceTokens<'_>,
+    resources: Resources<'_>,
+) -> Result<Exchange, Error> {
+    if matches!(trf.requested_token_type, Some(t) if t != ACCESS_TOKEN_TYPE_URI) {
+        return Err(Error::InvalidRequest);
+    }
+    let subject = validate_exchanged_token(
+        trf.subject_token,
+        trf.subject_token_type,
+        revocations,
+        reference_tokens,
+        resources,
+    )
+    .await?;
+    let claims = subject.claims();
+    // the subject token's client has to have allowed this one, and for tokens like this
+    let owner = clients
+        .get(&claims.client_id)
+        .await
+        .ok_or(Error::InvalidRequest)?;
+    let exchanger = owner.exchanger(&client.id).ok_or(Error::InvalidRequest)?;
+    if !exchanger.audiences.is_empty() && !exchanger.audiences.contains(&claims.aud) {
+        return Err(Error::InvalidRequest);
+    }
+    // the client itself acts for the subject, unless it shows a token for someone else
+    let actor = match trf.actor_token {
+        Some(_) => validate_exchanged_token(
+            trf.actor_token,
+            trf.actor_token_type,
+            revocations,
+            reference_tokens,
+            resources,
+        )
+        .await?
+        .claims()
+        .sub
+        .clone(),
+        None => client.id.to_string(),
+    };
+    // never more than the subject token had, nor than its client allowed
+    let granted: Vec<Scope> = validate_scopes(&claims.scope)?
+        .into_iter()
+        .filter(|scope| exchanger.scopes.is_empty() || exchanger.scopes.contains(scope))
+        .collect();
+    let scopes = match trf.scope {
+        Some(scope) => validate_scope_subset(scope, &granted)?,
+        None => granted,
+    };
+    Ok(Exchange {
+        subject: Uuid::parse_str(&claims.sub).map_err(|_| Error::InvalidRequest)?,
+        scopes,
+        act: Actor {
+            sub: actor,
+            act: claims.act.clone().map(Box::new),
+        },
+    })
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::oauth::client::ClientStorage;
+    use crate::oauth::revocation::RevocationStorage;
     use rocket::tokio;
     use rocket::State;
 
@@ -61,10 +399,18 @@ mod test {
         let (client, client_secret) = Client::new("name".to_string(), "test".to_string());
         client_storage.create(client.clone()).await;
 
+        let revocation_storage = RevocationStorage::new();
+        let revocations: Revocations = State::from(&revocation_storage);
+
         let client_id = client.id;
-        let result = validate_client(clients, &client_id, &client_secret)
-            .await
-            .unwrap();
+        let result = validate_client(
+            clients,
+            &client_id,
+            ClientAuth::Secret(&client_secret),
+            revocations,
+        )
+        .await
+        .unwrap();
 
         assert_eq!(result.id, client_id);
         assert!(result.validate_secret(&client_secret).is_ok());
@@ -88,4 +434,67 @@ mod test {
         let scopes_parsed = validate_scopes(scopes).unwrap();
         assert_eq!(scopes_parsed.len(), 0);
     }
+
+    #[test]
+    fn test_validate_scope_subset() {
+        let granted = vec![Scope::OpenId, Scope::Profile, Scope::OfflineAccess];
+        let scopes_parsed = validate_scope_subset("openid profile", &granted).unwrap();
+        assert_eq!(scopes_parsed, vec![Scope::OpenId, Scope::Profile]);
+
+        assert!(validate_scope_subset("openid email", &granted).is_err());
+    }
+
+    fn resources() -> ResourceRegistry {
+        let resource = |identifier: &str| Resource {
+            identifier: identifier.to_string(),
+            path: None,
+            scopes: Scope::ALL.to_vec(),
+        };
+        ResourceRegistry::new(vec![
+            resource(&AUDIENCE),
+            resource("https://a.example"),
+            resource("https://b.example"),
+        ])
+    }
+
+    #[test]
+    fn test_validate_resources() {
+        let resources = resources();
+        assert_eq!(
+            validate_resources(&["https://a.example"], &resources).unwrap(),
+            vec!["https://a.example"]
+        );
+        assert!(validate_resources(&[], &resources).unwrap().is_empty());
+        assert!(matches!(
+            validate_resources(&["https://a.example", "https://c.example"], &resources),
+            Err(Error::InvalidTarget)
+        ));
+    }
+
+    #[test]
+    fn test_validate_resource() {
+        let resources = resources();
+        let identifier = |requested, granted: &[&str]| {
+            let granted = granted.iter().map(|g| g.to_string()).collect::<Vec<_>>();
+            validate_resource(requested, &granted, &resources).map(|r| r.identifier.clone())
+        };
+        let (a, b) = ("https://a.example", "https://b.example");
+
+        assert_eq!(identifier(None, &[]).unwrap(), *AUDIENCE);
+        assert_eq!(identifier(Some(b), &[]).unwrap(), b);
+        assert_eq!(identifier(None, &[a]).unwrap(), a);
+        assert_eq!(identifier(Some(b), &[a, b]).unwrap(), b);
+        assert!(matches!(
+            identifier(Some(b), &[a]),
+            Err(Error::InvalidTarget)
+        ));
+        assert!(matches!(
+            identifier(None, &[a, b]),
+            Err(Error::InvalidTarget)
+        ));
+        assert!(matches!(
+            identifier(Some("https://c.example"), &[]),
+            Err(Error::InvalidTarget)
+        ));
+    }
 }
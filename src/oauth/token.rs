@@ -1,5 +1,8 @@
 use rocket::serde::{Deserialize, Serialize};
 
+// RFC 8693 section 3, the only kind of token we take and hand out in a token exchange
+pub const ACCESS_TOKEN_TYPE_URI: &str = "urn:ietf:params:oauth:token-type:access_token";
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(crate = "rocket::serde")]
 pub struct Token {
@@ -11,6 +14,11 @@ pub struct Token {
     pub scope: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub refresh_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+    // RFC 8693 section 2.2.1, only in token exchange responses
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub issued_token_type: Option<String>,
 }
 
 impl Token {
@@ -26,6 +34,8 @@ impl Token {
             expires_in,
             scope,
             refresh_token,
+            id_token: None,
+            issued_token_type: None,
         }
     }
 }
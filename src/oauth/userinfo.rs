@@ -0,0 +1,136 @@
+use crate::account::acc::Account;
+use crate::oauth::scopes::Scope;
+use rocket::http::ContentType;
+use rocket::request::Request;
+use rocket::response::{self, Responder};
+use rocket::serde::json::json;
+use rocket::serde::{Deserialize, Serialize};
+
+// OpenID Connect Core section 5.1, each claim only shows up when its scope was granted
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct UserInfo {
+    pub sub: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email_verified: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<Address>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phone_number: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phone_number_verified: Option<bool>,
+}
+
+// section 5.1.1, we only keep the address as one string
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct Address {
+    pub formatted: String,
+}
+
+impl UserInfo {
+    pub fn new(account: &Account, scopes: &[Scope]) -> Self {
+        let mut userinfo = Self {
+            sub: account.id.to_string(),
+            ..Default::default()
+        };
+        if scopes.contains(&Scope::Profile) {
+            userinfo.name = account.name.clone();
+            userinfo.preferred_username = Some(account.username.clone());
+        }
+        if scopes.contains(&Scope::Email) && account.email.is_some() {
+            userinfo.email = account.email.clone();
+            userinfo.email_verified = Some(account.email_verified);
+        }
+        if scopes.contains(&Scope::Address) {
+            userinfo.address = account
+                .address
+                .clone()
+                .map(|formatted| Address { formatted });
+        }
+        if scopes.contains(&Scope::Phone) && account.phone_number.is_some() {
+            userinfo.phone_number = account.phone_number.clone();
+            userinfo.phone_number_verified = Some(account.phone_number_verified);
+        }
+        userinfo
+    }
+}
+
+// a signed response also says who it's from and who it's for, section 5.3.2
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct SignedUserInfo {
+    pub iss: String,
+    pub aud: String,
+    #[serde(flatten)]
+    pub userinfo: UserInfo,
+}
+
+pub enum UserInfoResponse {
+    Claims(UserInfo),
+    Signed(String),
+}
+
+impl<'r> Responder<'r, 'static> for UserInfoResponse {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        match self {
+            UserInfoResponse::Claims(userinfo) => json!(userinfo).respond_to(request),
+            UserInfoResponse::Signed(jwt) => {
+                (ContentType::new("application", "jwt"), jwt).respond_to(request)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn account() -> Account {
+        let mut account = Account::new("grant".to_string(), "password".to_string());
+        account.name = Some("Grant Azure".to_string());
+        account.email = Some("grant@example.com".to_string());
+        account.email_verified = true;
+        account.address = Some("1 Main St".to_string());
+        account
+    }
+
+    #[test]
+    fn test_userinfo_only_sub_for_openid() {
+        let account = account();
+        let userinfo = UserInfo::new(&account, &[Scope::OpenId]);
+        assert_eq!(json!(userinfo), json!({ "sub": account.id.to_string() }));
+    }
+
+    #[test]
+    fn test_userinfo_claims_by_scope() {
+        let account = account();
+        let userinfo = UserInfo::new(
+            &account,
+            &[
+                Scope::OpenId,
+                Scope::Profile,
+                Scope::Email,
+                Scope::Address,
+                Scope::Phone,
+            ],
+        );
+        assert_eq!(
+            json!(userinfo),
+            json!({
+                "sub": account.id.to_string(),
+                "name": "Grant Azure",
+                "preferred_username": "grant",
+                "email": "grant@example.com",
+                "email_verified": true,
+                "address": { "formatted": "1 Main St" },
+            })
+        );
+    }
+}